@@ -0,0 +1,261 @@
+//! Chat latency during a login storm.
+//!
+//! Starts a server, then measures how long chat messages take to reach another
+//! client, first while nothing else happens and then while many clients log in
+//! at once. Point `--server` at a binary built from another commit to compare.
+//!
+//!     cargo bench --bench login_storm
+//!     cargo bench --bench login_storm -- --storm-clients 32 --seconds 10
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use hwork15::{receive_message, send_message, ClientMessage, MessageType, ResponseType};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+const PASSWORD: &str = "bench-password-1";
+
+#[derive(Parser)]
+struct Args {
+    /// Server binary to run.
+    #[arg(long, default_value = env!("CARGO_BIN_EXE_server"))]
+    server: PathBuf,
+    /// Extra arguments for the server, e.g. "--argon2-memory-kib 65536".
+    #[arg(long, default_value = "", allow_hyphen_values = true)]
+    server_args: String,
+    #[arg(long, default_value = "127.0.0.1:12345")]
+    address: SocketAddr,
+    /// Clients logging in over and over during the storm.
+    #[arg(long, default_value_t = 16)]
+    storm_clients: usize,
+    /// Length of each measured phase.
+    #[arg(long, default_value_t = 5)]
+    seconds: u64,
+    /// Time between two chat messages.
+    #[arg(long, default_value_t = 20)]
+    interval_ms: u64,
+    /// Passed by `cargo bench`.
+    #[arg(long, hide = true)]
+    bench: bool,
+}
+
+/// Kills the server when the benchmark ends, however it ends.
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Send times of the chat messages in flight, by sequence number.
+type SentAt = Arc<Mutex<HashMap<u64, Instant>>>;
+
+struct Phase {
+    name: &'static str,
+    sent: usize,
+    latencies: Vec<Duration>,
+    logins: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let dir = std::env::temp_dir().join(format!("hwork15-login-storm-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let database = dir.join("bench.sqlite");
+    std::fs::File::create(&database)?;
+
+    let _server = ServerProcess(
+        Command::new(&args.server)
+            .arg("--address")
+            .arg(args.address.to_string())
+            .arg("--database-url")
+            .arg(format!("sqlite:{}", database.display()))
+            .arg("--storage-root")
+            .arg(&dir)
+            .args(args.server_args.split_whitespace())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("Failed to start {}", args.server.display()))?,
+    );
+    wait_for_server(args.address).await?;
+
+    let (mut sender_r, mut sender) = login(args.address, "REGISTER", "bench-sender").await?;
+    // Acks for the sender are not needed, but must not pile up unread.
+    tokio::spawn(async move {
+        while receive_message::<ResponseType, _>(&mut sender_r)
+            .await
+            .is_ok()
+        {}
+    });
+    let (receiver, _receiver_w) = login(args.address, "REGISTER", "bench-receiver").await?;
+    for i in 0..args.storm_clients {
+        login(args.address, "REGISTER", &format!("storm-{i}")).await?;
+    }
+
+    let sent_at = SentAt::default();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let _reader = spawn_receiver(receiver, sent_at.clone(), received.clone());
+
+    let idle = measure("idle", &args, &mut sender, &sent_at, &received, None).await?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let logins = Arc::new(AtomicU64::new(0));
+    let storm: Vec<JoinHandle<()>> = (0..args.storm_clients)
+        .map(|i| {
+            let (stop, logins, addr) = (stop.clone(), logins.clone(), args.address);
+            tokio::spawn(async move {
+                while !stop.load(Ordering::Relaxed) {
+                    if login(addr, "AUTH", &format!("storm-{i}")).await.is_ok() {
+                        logins.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+        .collect();
+    let stormy = measure(
+        "login storm",
+        &args,
+        &mut sender,
+        &sent_at,
+        &received,
+        Some(&logins),
+    )
+    .await?;
+    stop.store(true, Ordering::Relaxed);
+    for task in storm {
+        task.abort();
+    }
+
+    println!(
+        "{:<12} {:>6} {:>6} {:>8} {:>8} {:>8} {:>8} {:>9}",
+        "phase", "sent", "recv", "p50 ms", "p95 ms", "p99 ms", "max ms", "logins/s"
+    );
+    for phase in [idle, stormy] {
+        print_phase(&phase, args.seconds);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+    Ok(())
+}
+
+async fn wait_for_server(addr: SocketAddr) -> Result<()> {
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    bail!("Server did not start listening on {addr}")
+}
+
+/// Connects and runs `REGISTER` or `AUTH` for `user`.
+async fn login(
+    addr: SocketAddr,
+    action: &str,
+    user: &str,
+) -> Result<(OwnedReadHalf, OwnedWriteHalf)> {
+    let (mut reader, mut writer) = TcpStream::connect(addr).await?.into_split();
+    send_message(&mut writer, &format!("{action} {user} {PASSWORD}")).await?;
+    // Broadcasts to everyone may arrive before the answer.
+    loop {
+        match receive_message(&mut reader).await? {
+            ResponseType::Text(_) => return Ok((reader, writer)),
+            ResponseType::Error(e) => bail!("{action} {user} failed: {e}"),
+            _ => continue,
+        }
+    }
+}
+
+/// Records the latency of every benchmark message the receiver gets.
+fn spawn_receiver(
+    mut reader: OwnedReadHalf,
+    sent_at: SentAt,
+    received: Arc<Mutex<Vec<Duration>>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Ok(response) = receive_message::<ResponseType, _>(&mut reader).await {
+            let ResponseType::Chat { text, .. } = response else {
+                continue;
+            };
+            let Some(seq) = text.strip_prefix("bench ").and_then(|s| s.parse().ok()) else {
+                continue;
+            };
+            if let Some(at) = sent_at.lock().unwrap().remove(&seq) {
+                received.lock().unwrap().push(at.elapsed());
+            }
+        }
+    })
+}
+
+/// Sends chat messages for one phase and collects their latencies.
+async fn measure(
+    name: &'static str,
+    args: &Args,
+    sender: &mut OwnedWriteHalf,
+    sent_at: &SentAt,
+    received: &Mutex<Vec<Duration>>,
+    logins: Option<&AtomicU64>,
+) -> Result<Phase> {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    let logins_before = logins.map_or(0, |l| l.load(Ordering::Relaxed));
+    let end = Instant::now() + Duration::from_secs(args.seconds);
+    let mut interval = tokio::time::interval(Duration::from_millis(args.interval_ms));
+    let mut sent = 0;
+    while Instant::now() < end {
+        interval.tick().await;
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        sent_at.lock().unwrap().insert(seq, Instant::now());
+        let text = MessageType::Text(format!("bench {seq}"));
+        send_message(sender, &ClientMessage::new(text)).await?;
+        sent += 1;
+    }
+    let logins = logins.map_or(0, |l| l.load(Ordering::Relaxed)) - logins_before;
+
+    // Let stragglers arrive, then count whatever is still missing as lost.
+    for _ in 0..50 {
+        if sent_at.lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    sent_at.lock().unwrap().clear();
+    let mut latencies = std::mem::take(&mut *received.lock().unwrap());
+    latencies.sort();
+    Ok(Phase {
+        name,
+        sent,
+        latencies,
+        logins,
+    })
+}
+
+fn print_phase(phase: &Phase, seconds: u64) {
+    let percentile = |p: f64| -> f64 {
+        if phase.latencies.is_empty() {
+            return f64::NAN;
+        }
+        let index = ((phase.latencies.len() - 1) as f64 * p).round() as usize;
+        phase.latencies[index].as_secs_f64() * 1000.0
+    };
+    println!(
+        "{:<12} {:>6} {:>6} {:>8.1} {:>8.1} {:>8.1} {:>8.1} {:>9.1}",
+        phase.name,
+        phase.sent,
+        phase.latencies.len(),
+        percentile(0.5),
+        percentile(0.95),
+        percentile(0.99),
+        percentile(1.0),
+        phase.logins as f64 / seconds as f64
+    );
+}
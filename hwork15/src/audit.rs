@@ -0,0 +1,161 @@
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::server_utils::ServerState;
+
+/// How often expired audit events are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Security-relevant things clients do.
+#[derive(Clone, Copy, Debug)]
+pub enum AuditAction {
+    Login,
+    Register,
+    /// A file under the storage root, shared with `.file`.
+    FileRequest,
+    /// An image under the storage root, shared with `.image`.
+    ImageRequest,
+    /// The full resolution of an announced image, fetched with `.fetch`.
+    ImageFetch,
+    Upload,
+    /// An admin looked at the audit log.
+    AuditQuery,
+    /// An admin exported the audit log.
+    AuditExport,
+    /// A user exported the chat transcript.
+    TranscriptExport,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Login => "login",
+            AuditAction::Register => "register",
+            AuditAction::FileRequest => "file_request",
+            AuditAction::ImageRequest => "image_request",
+            AuditAction::ImageFetch => "image_fetch",
+            AuditAction::Upload => "upload",
+            AuditAction::AuditQuery => "audit_query",
+            AuditAction::AuditExport => "audit_export",
+            AuditAction::TranscriptExport => "transcript_export",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+    /// Refused because the actor lacks the right to do it.
+    Denied,
+}
+
+impl AuditOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Failure => "failure",
+            AuditOutcome::Denied => "denied",
+        }
+    }
+}
+
+/// An entry for the `audit_events` table.
+#[derive(Debug)]
+pub struct AuditEvent {
+    /// The user, or the username tried when logging in.
+    pub actor: String,
+    pub peer: SocketAddr,
+    pub action: AuditAction,
+    /// What was acted on, for example the requested file path.
+    pub target: Option<String>,
+    pub outcome: AuditOutcome,
+    /// Why it failed, or other context.
+    pub detail: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(actor: &str, peer: SocketAddr, action: AuditAction, outcome: AuditOutcome) -> Self {
+        Self {
+            actor: actor.to_string(),
+            peer,
+            action,
+            target: None,
+            outcome,
+            detail: None,
+        }
+    }
+
+    /// An event for a request answered with `result`; a failure's reason becomes the detail.
+    pub fn for_result<T>(
+        actor: &str,
+        peer: SocketAddr,
+        action: AuditAction,
+        result: &Result<T, String>,
+    ) -> Self {
+        match result {
+            Ok(_) => Self::new(actor, peer, action, AuditOutcome::Success),
+            Err(reason) => Self::new(actor, peer, action, AuditOutcome::Failure).detail(reason),
+        }
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Which audit events `/audit` shows or exports.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    /// Latest events to return; all of them when unset.
+    pub limit: Option<i64>,
+}
+
+impl AuditFilter {
+    /// Parses `[N] [user=<name>] [action=<action>]`.
+    pub fn parse(args: &str) -> Result<Self> {
+        let mut filter = Self::default();
+        for arg in args.split_whitespace() {
+            if let Some(user) = arg.strip_prefix("user=") {
+                filter.actor = Some(user.to_string());
+            } else if let Some(action) = arg.strip_prefix("action=") {
+                filter.action = Some(action.to_string());
+            } else {
+                let limit: i64 = arg
+                    .parse()
+                    .ok()
+                    .with_context(|| format!("Unexpected argument {arg}"))?;
+                if limit < 1 {
+                    bail!("Show at least 1 event");
+                }
+                filter.limit = Some(limit);
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// Deletes audit events older than `audit.retention_days` every hour.
+pub async fn prune_periodically(state: Arc<ServerState>) {
+    let days = state.config.audit.retention_days;
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match state.database.prune_audit_events(days).await {
+            Ok(0) => {}
+            Ok(pruned) => info!("Pruned {} audit events older than {} days", pruned, days),
+            Err(e) => error!("Failed to prune audit events: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use hwork15::config::ServerConfig;
+use hwork15::db::Database;
+use hwork15::export::{self, Attachments, ExportFormat, TranscriptFilter};
+use hwork15::passwords::Passwords;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+/// Maintenance tasks run directly against the server's database, whether or
+/// not the server is running.
+#[derive(Parser)]
+struct Args {
+    /// Server config file, for the database URL and storage root.
+    #[arg(short, long, env = "HWORK_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(short, long, env = "HWORK_DATABASE_URL")]
+    database_url: Option<String>,
+    #[arg(long, env = "HWORK_STORAGE_ROOT")]
+    storage_root: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a user; the password must meet the server's password policy.
+    CreateUser {
+        username: String,
+        #[command(flatten)]
+        password: PasswordArg,
+    },
+    /// Set a new password for a user.
+    ResetPassword {
+        username: String,
+        #[command(flatten)]
+        password: PasswordArg,
+    },
+    /// List users with their last-seen time and message count.
+    Users,
+    /// Refuse further logins of a user. Sessions already open stay open.
+    Lock { username: String },
+    /// Allow a locked user to log in again.
+    Unlock { username: String },
+    /// Delete a user's chat messages and the direct messages they sent.
+    PurgeMessages {
+        username: String,
+        /// Delete instead of only counting what would be deleted.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Show row counts and the size of the database.
+    Stats,
+    /// Write the chat transcript as JSON Lines, CSV or HTML.
+    Export {
+        /// jsonl, csv or html.
+        #[arg(short, long, default_value = "html")]
+        format: ExportFormat,
+        /// Only messages and attachments of this user.
+        #[arg(short, long)]
+        user: Option<String>,
+        /// First day (YYYY-MM-DD) or time (YYYY-MM-DDTHH:MM:SS) to include, in UTC.
+        #[arg(long)]
+        since: Option<String>,
+        /// Last day or time to include, in UTC.
+        #[arg(long)]
+        until: Option<String>,
+        /// Embed attachments instead of referencing them by path.
+        #[arg(short, long)]
+        bundle: bool,
+        /// File to write; stdout when left out.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(clap::Args)]
+struct PasswordArg {
+    /// Read from the first line of stdin when left out.
+    #[arg(short, long, env = "HWORK_NEW_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+}
+
+impl PasswordArg {
+    fn resolve(self) -> Result<String> {
+        if let Some(password) = self.password {
+            return Ok(password);
+        }
+        eprint!("Password: ");
+        let mut line = String::new();
+        std::io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .context("Failed to read password")?;
+        let password = line.trim_end_matches(['\r', '\n']).to_string();
+        if password.is_empty() {
+            bail!("No password given");
+        }
+        Ok(password)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut config = ServerConfig::load(args.config.as_deref())?;
+    if let Some(url) = args.database_url {
+        config.database.url = url;
+    }
+    if let Some(root) = args.storage_root {
+        config.storage.root = root;
+    }
+    let passwords = Passwords::from_config(&config.auth)?;
+    let database = Database::new(&config.database.url, passwords).await?;
+
+    match args.command {
+        Command::CreateUser { username, password } => {
+            database
+                .create_user(&username, &password.resolve()?)
+                .await
+                .with_context(|| format!("Failed to create user {username}"))?;
+            println!("Created user {username}");
+        }
+        Command::ResetPassword { username, password } => {
+            if !database
+                .set_password(&username, &password.resolve()?)
+                .await?
+            {
+                bail!("No user named {username}");
+            }
+            println!("Reset the password of {username}");
+        }
+        Command::Users => {
+            let users = database.users().await?;
+            let width = users
+                .iter()
+                .map(|user| user.username.len())
+                .max()
+                .unwrap_or(0)
+                .max("USER".len());
+            println!(
+                "{:width$}  {:19}  {:>8}  STATUS",
+                "USER", "LAST SEEN", "MESSAGES"
+            );
+            for user in &users {
+                println!(
+                    "{:width$}  {:19}  {:>8}  {}",
+                    user.username,
+                    user.last_seen.as_deref().unwrap_or("never"),
+                    user.messages,
+                    if user.locked { "locked" } else { "active" }
+                );
+            }
+        }
+        Command::Lock { username } => {
+            if !database.set_locked(&username, true).await? {
+                bail!("No user named {username}");
+            }
+            println!("Locked {username}");
+        }
+        Command::Unlock { username } => {
+            if !database.set_locked(&username, false).await? {
+                bail!("No user named {username}");
+            }
+            println!("Unlocked {username}");
+        }
+        Command::PurgeMessages { username, yes } => {
+            if yes {
+                let purged = database.purge_user_messages(&username).await?;
+                println!(
+                    "Deleted {} chat and {} direct messages of {username}",
+                    purged.chat, purged.direct
+                );
+            } else {
+                let found = database.count_user_messages(&username).await?;
+                println!(
+                    "{username} has {} chat and {} sent direct messages; run again with --yes to delete them",
+                    found.chat, found.direct
+                );
+            }
+        }
+        Command::Stats => {
+            let stats = database.stats().await?;
+            println!(
+                "Users:           {} ({} locked)",
+                stats.users, stats.locked_users
+            );
+            print!("Chat messages:   {}", stats.messages);
+            if let (Some(oldest), Some(newest)) = (&stats.oldest_message, &stats.newest_message) {
+                print!(" ({oldest} to {newest})");
+            }
+            println!();
+            let direct: Vec<String> = stats
+                .direct_messages
+                .iter()
+                .map(|(state, count)| format!("{count} {state}"))
+                .collect();
+            let total: i64 = stats.direct_messages.iter().map(|(_, count)| count).sum();
+            print!("Direct messages: {total}");
+            if !direct.is_empty() {
+                print!(" ({})", direct.join(", "));
+            }
+            println!();
+            println!("Attachments:     {}", stats.attachments);
+            println!("Audit events:    {}", stats.audit_events);
+            println!("Size:            {} KiB", stats.size_bytes / 1024);
+        }
+        Command::Export {
+            format,
+            user,
+            since,
+            until,
+            bundle,
+            output,
+        } => {
+            let filter = TranscriptFilter {
+                user,
+                ..Default::default()
+            }
+            .between(since.as_deref(), until.as_deref())?;
+            let entries = database.transcript(&filter).await?;
+            let protected = config.protected_files();
+            let attachments = Attachments {
+                root: &config.storage.root,
+                protected: &protected,
+                bundle,
+                max_bundled_bytes: config.limits.max_file_bytes,
+            };
+            let content = export::render(&entries, format, &attachments)?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, content)
+                        .with_context(|| format!("Failed to write {}", path.display()))?;
+                    eprintln!(
+                        "Exported {} transcript entries to {}",
+                        entries.len(),
+                        path.display()
+                    );
+                }
+                None => std::io::stdout()
+                    .write_all(&content)
+                    .context("Failed to write transcript")?,
+            }
+        }
+    }
+    Ok(())
+}
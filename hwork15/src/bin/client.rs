@@ -1,60 +1,280 @@
 use anyhow::Result;
 use clap::Parser;
-use hwork15::{parse_input, parse_socket_addr, send_message, MessageType};
+use hwork15::{
+    parse_socket_addr, send_message, ClientMessage, MessageType, ReceiptStatus,
+    DEFAULT_MAX_FRAME_BYTES,
+};
+use image::ImageFormat;
 use std::net::SocketAddr;
-use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tracing::{error, info};
 
 #[path = "../client_utils.rs"]
 mod client_utils;
-use client_utils::{handle_authentication_or_registration, handle_server};
+use client_utils::{
+    handle_authentication_or_registration, handle_server, log_notice, send_receipt, MentionAlert,
+};
+
+#[path = "../downloads.rs"]
+mod downloads;
+use downloads::{parse_image_format, Downloads};
+
+#[path = "../e2e.rs"]
+mod e2e;
+use e2e::{E2e, E2eSession, Outgoing};
+
+#[path = "../tui.rs"]
+mod tui;
+
+#[path = "../script.rs"]
+mod script;
+use script::{Command, ScriptArgs};
+
+#[path = "../line_editor.rs"]
+mod line_editor;
+use line_editor::{LineEditor, OnlineUsers};
 
 /// Client configuration
 #[derive(Parser)]
 struct Config {
     #[arg(short, long, default_value = "127.0.0.1:11111", value_parser = parse_socket_addr)]
     address: SocketAddr,
+    /// Full-screen terminal UI; logs go to <download dir>/client.log instead of stdout.
+    #[arg(short, long)]
+    tui: bool,
+    /// Longest frame accepted from the server; a longer one ends the session.
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_BYTES)]
+    max_frame_bytes: usize,
+    /// Tell senders when their messages were delivered to and read by you.
+    #[arg(short, long)]
+    receipts: bool,
+    /// Ring the terminal bell when a chat message mentions you as @username.
+    #[arg(long)]
+    bell: bool,
+    /// Convert received images to this format (png, jpg, webp...); by default they are kept as sent.
+    #[arg(long, value_parser = parse_image_format)]
+    image_format: Option<ImageFormat>,
+    /// Directory received files and images are saved under.
+    #[arg(long, default_value = "client_db")]
+    download_dir: PathBuf,
+    /// Files larger than this many bytes wait for .accept/.decline.
+    #[arg(long, default_value_t = 10 * 1024 * 1024)]
+    confirm_above: u64,
+    /// File extensions saved without asking whatever their size, e.g. txt,pdf.
+    #[arg(long, value_delimiter = ',')]
+    auto_accept: Vec<String>,
+    /// Most bytes the download directory may hold.
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    download_quota: u64,
+    /// Encrypt direct messages end to end; the server only relays ciphertext.
+    #[arg(long)]
+    e2e: bool,
+    /// Where the identity key and pinned contact keys are kept.
+    #[arg(long, default_value_os_t = e2e::default_key_dir())]
+    key_dir: PathBuf,
+    #[command(flatten)]
+    script: ScriptArgs,
+    /// Run one job non-interactively instead of starting a chat session.
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
+async fn main() -> Result<ExitCode> {
     let config = Config::parse();
+    if let Some(command) = config.command {
+        return Ok(script::run(
+            config.address,
+            config.max_frame_bytes,
+            config.script,
+            command,
+        )
+        .await);
+    }
+
+    let online = OnlineUsers::default();
+    let mut editor = None;
+    if config.tui {
+        std::fs::create_dir_all(&config.download_dir)?;
+        let log_file = std::fs::File::create(config.download_dir.join("client.log"))?;
+        tracing_subscriber::fmt()
+            .with_writer(std::sync::Mutex::new(log_file))
+            .with_ansi(false)
+            .init();
+    } else {
+        let mut line_editor = LineEditor::new(online.clone())?;
+        match line_editor.printer() {
+            Some(printer) => tracing_subscriber::fmt()
+                .with_writer(std::sync::Mutex::new(printer))
+                .init(),
+            None => tracing_subscriber::fmt::init(),
+        }
+        editor = Some(line_editor);
+    }
+
+    let downloads = Downloads::new(
+        config.download_dir,
+        config.image_format,
+        config.confirm_above,
+        &config.auto_accept,
+        config.download_quota,
+    );
     let server_addr = &config.address;
     let stream = TcpStream::connect(server_addr).await?;
 
-    let (mut reader, mut writer) = stream.into_split();
+    let Some(editor) = editor else {
+        let key_dir = config.e2e.then_some(config.key_dir);
+        tui::run(
+            stream,
+            *server_addr,
+            config.receipts,
+            config.bell,
+            downloads,
+            key_dir,
+            config.max_frame_bytes,
+        )
+        .await?;
+        return Ok(ExitCode::SUCCESS);
+    };
 
-    let buf_read_lines = BufReader::new(stdin());
-    let mut lines = buf_read_lines.lines();
-    handle_authentication_or_registration(&mut reader, &mut writer, &mut lines).await?;
+    let (mut reader, mut writer) = stream.into_split();
+    let mut lines = editor.spawn();
+    let username = handle_authentication_or_registration(
+        &mut reader,
+        &mut writer,
+        &mut lines,
+        config.max_frame_bytes,
+    )
+    .await?;
     info!("Authentication successful. I was waiting on you.. Neo.");
 
-    let write_task = tokio::spawn(async move {
+    // Sealed messages are produced by the read task once a key arrives, and sent by the write task.
+    let (outgoing_send, mut outgoing_recv) = mpsc::unbounded_channel::<Outgoing>();
+    let session = if config.e2e {
+        let state = E2e::load(&config.key_dir, &username)?;
+        info!(
+            "End-to-end encryption on, your key: {}",
+            state.fingerprint()
+        );
+        send_message(&mut writer, &ClientMessage::new(state.publish())).await?;
+        Some(Arc::new(E2eSession {
+            state: Mutex::new(state),
+            outgoing: outgoing_send,
+        }))
+    } else {
+        None
+    };
+
+    // Chat messages count as read once the user sends something after receiving them.
+    let (delivered_send, mut delivered_recv) = mpsc::unbounded_channel::<i64>();
+    let local_downloads = downloads.clone();
+    let local_session = session.clone();
+    let mut write_task = tokio::spawn(async move {
+        let mut unread = Vec::new();
         loop {
-            match parse_input(&mut lines).await {
-                Ok(msg) => {
-                    if let Err(e) = send_message(&mut writer, &msg).await {
-                        error!("Send message error: {:?}", e);
+            tokio::select! {
+                line = lines.recv() => {
+                    let Some(line) = line else { break };
+                    match local_downloads.local_command(&line).await {
+                        Some(Ok(done)) => { info!("{}", done); continue; }
+                        Some(Err(e)) => { error!("{:#}", e); continue; }
+                        None => {}
+                    }
+                    if let Some(session) = &local_session {
+                        let handled = session.state.lock().ok().and_then(|mut state| state.local_command(&line));
+                        match handled {
+                            Some(Ok((notices, outgoing))) => {
+                                notices.into_iter().for_each(log_notice);
+                                for message in outgoing {
+                                    let _ = session.outgoing.send(message);
+                                }
+                                continue;
+                            }
+                            Some(Err(e)) => { error!("{:#}", e); continue; }
+                            None => {}
+                        }
+                    }
+                    let msg = match line.trim().parse::<MessageType>() {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            error!("Failed to parse input: {:?}", e);
+                            continue;
+                        }
+                    };
+                    // With --e2e a direct message waits for the recipient's key.
+                    let msg = match (msg, &local_session) {
+                        (MessageType::Direct(to, text), Some(session)) => match session.state.lock() {
+                            Ok(mut state) => state.queue_direct(to, text),
+                            Err(_) => continue,
+                        },
+                        (msg, _) => msg,
+                    };
+                    for id in unread.drain(..) {
+                        if let Err(e) = send_receipt(&mut writer, id, ReceiptStatus::Read).await {
+                            error!("{:?}", e);
+                        }
+                    }
+                    let quit = matches!(msg, MessageType::Quit);
+                    let msg = ClientMessage::new(msg);
+                    match send_message(&mut writer, &msg).await {
+                        Ok(()) if !quit => info!("Message {} sent", msg.id),
+                        Ok(()) => {}
+                        Err(e) => error!("Send message error: {:?}", e),
                     }
-                    if let MessageType::Quit = msg {
+                    if quit {
                         break;
                     }
                 }
-                Err(e) => error!("Failed to parse input: {:?}", e),
+                Some(outgoing) = outgoing_recv.recv() => {
+                    let msg = ClientMessage::new(outgoing.message);
+                    match send_message(&mut writer, &msg).await {
+                        Ok(()) => info!("Message {} to {} sent encrypted: {}", msg.id, outgoing.to, outgoing.text),
+                        Err(e) => error!("Send message error: {:?}", e),
+                    }
+                }
+                Some(id) = delivered_recv.recv() => {
+                    if let Err(e) = send_receipt(&mut writer, id, ReceiptStatus::Delivered).await {
+                        error!("{:?}", e);
+                    }
+                    unread.push(id);
+                }
             }
         }
     });
 
-    let read_task = tokio::spawn(async move {
-        if let Err(e) = handle_server(&mut reader).await {
+    let receipts = config.receipts;
+    let max_frame_bytes = config.max_frame_bytes;
+    let alert = MentionAlert {
+        username,
+        bell: config.bell,
+    };
+    let mut read_task = tokio::spawn(async move {
+        let delivered = receipts.then_some(&delivered_send);
+        let session = session.as_deref();
+        if let Err(e) = handle_server(
+            &mut reader,
+            max_frame_bytes,
+            &online,
+            delivered,
+            &downloads,
+            session,
+            &alert,
+        )
+        .await
+        {
             error!("Error receiving from server: {:?}", e);
         }
     });
 
-    tokio::join!(write_task, read_task);
+    // Whichever side finishes first (.quit or a closed connection) ends the session.
+    tokio::select! {
+        res = &mut write_task => res?,
+        res = &mut read_task => res?,
+    }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
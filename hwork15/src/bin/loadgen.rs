@@ -0,0 +1,447 @@
+//! Load generator: many simulated users chatting and uploading against a
+//! running server, reporting connection success, broadcast latency and throughput.
+//!
+//!     cargo run --release --bin loadgen -- --users 100 --duration 60 --text-rate 2
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use hwork15::{
+    parse_socket_addr, receive_message, send_message, ClientMessage, MessageId, MessageType,
+    ResponseType,
+};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, MissedTickBehavior};
+use tracing::warn;
+
+/// Simulates many chat users against a running server and reports how it copes.
+#[derive(Parser)]
+struct Config {
+    #[arg(short, long, default_value = "127.0.0.1:11111", value_parser = parse_socket_addr)]
+    address: SocketAddr,
+    /// Simulated users, each on its own connection.
+    #[arg(short, long, default_value_t = 50)]
+    users: usize,
+    /// Users are named <prefix>-0, <prefix>-1...; missing ones are registered.
+    #[arg(long, default_value = "load")]
+    user_prefix: String,
+    #[arg(long, default_value = "load-password-1")]
+    password: String,
+    /// How long to send traffic, in seconds.
+    #[arg(short, long, default_value_t = 30)]
+    duration: u64,
+    /// Chat messages per second, per user.
+    #[arg(long, default_value_t = 1.0)]
+    text_rate: f64,
+    /// File uploads per second, per user; uploads are stored by the server.
+    #[arg(long, default_value_t = 0.0)]
+    file_rate: f64,
+    /// Size of each uploaded file, in bytes.
+    #[arg(long, default_value_t = 64 * 1024)]
+    file_size: usize,
+    /// Print the report as JSON instead of a table.
+    #[arg(long)]
+    json: bool,
+}
+
+/// What a run measured, shared by all simulated users.
+#[derive(Default)]
+struct Stats {
+    connect_times: Mutex<Vec<Duration>>,
+    connect_failures: AtomicU64,
+    text: TrafficStats,
+    file: TrafficStats,
+    /// Ids of the uploads not yet acknowledged, to tell their acks from chat ones.
+    pending_files: Mutex<HashSet<MessageId>>,
+}
+
+#[derive(Default)]
+struct TrafficStats {
+    sent: AtomicU64,
+    acked: AtomicU64,
+    rejected: AtomicU64,
+    delivered: AtomicU64,
+    bytes_delivered: AtomicU64,
+    /// Time from sending to another user receiving the broadcast.
+    latencies: Mutex<Vec<Duration>>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    users: usize,
+    duration_secs: f64,
+    connections: ConnectionReport,
+    text: TrafficReport,
+    file: TrafficReport,
+}
+
+#[derive(Serialize)]
+struct ConnectionReport {
+    succeeded: usize,
+    failed: u64,
+    p50_ms: f64,
+    p99_ms: f64,
+}
+
+#[derive(Serialize)]
+struct TrafficReport {
+    sent: u64,
+    acked: u64,
+    rejected: u64,
+    /// Broadcasts received, counting every receiving user.
+    delivered: u64,
+    /// Broadcasts that should have been received by every other user.
+    expected: u64,
+    sent_per_sec: f64,
+    delivered_per_sec: f64,
+    delivered_bytes_per_sec: f64,
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+    max_ms: f64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = Arc::new(Config::parse());
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_writer(std::io::stderr)
+        .init();
+    if config.users == 0 {
+        bail!("--users must be at least 1");
+    }
+
+    let stats = Arc::new(Stats::default());
+    // Timestamps in messages are offsets from this instant, so every user reads them alike.
+    let start = Instant::now();
+
+    let connections = futures_util::future::join_all(
+        (0..config.users)
+            .map(|i| connect(&config, &stats, format!("{}-{}", config.user_prefix, i))),
+    )
+    .await;
+    let connections: Vec<_> = connections.into_iter().flatten().collect();
+    let connected = connections.len();
+    if connected == 0 {
+        bail!("No user could connect to {}", config.address);
+    }
+
+    let traffic_start = Instant::now();
+    let deadline = traffic_start + Duration::from_secs(config.duration);
+    let mut readers = Vec::new();
+    let mut writers = Vec::new();
+    for (index, (reader, writer)) in connections.into_iter().enumerate() {
+        readers.push(tokio::spawn(read_traffic(reader, stats.clone(), start)));
+        writers.push(tokio::spawn(send_traffic(
+            writer,
+            index,
+            config.clone(),
+            stats.clone(),
+            start,
+            deadline,
+        )));
+    }
+
+    let mut writers_back = Vec::new();
+    for writer in writers {
+        if let Ok(Ok(writer)) = writer.await {
+            writers_back.push(writer);
+        }
+    }
+    let elapsed = traffic_start.elapsed();
+    // Give broadcasts still in flight a moment to arrive.
+    sleep(Duration::from_secs(2)).await;
+    for mut writer in writers_back {
+        let _ = send_message(&mut writer, &ClientMessage::new(MessageType::Quit)).await;
+    }
+    for reader in readers {
+        reader.abort();
+    }
+
+    let report = build_report(&stats, config.users, connected, elapsed);
+    if config.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_table(&report);
+    }
+    Ok(())
+}
+
+/// Connects one user, logging in or registering it. Failures are counted, not fatal.
+async fn connect(
+    config: &Config,
+    stats: &Stats,
+    user: String,
+) -> Option<(OwnedReadHalf, OwnedWriteHalf)> {
+    let started = Instant::now();
+    match login(config.address, &user, &config.password).await {
+        Ok(halves) => {
+            stats.connect_times.lock().unwrap().push(started.elapsed());
+            Some(halves)
+        }
+        Err(e) => {
+            warn!("{}: {:#}", user, e);
+            stats.connect_failures.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+async fn login(
+    addr: SocketAddr,
+    user: &str,
+    password: &str,
+) -> Result<(OwnedReadHalf, OwnedWriteHalf)> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .context("Failed to connect")?;
+    let (mut reader, mut writer) = stream.into_split();
+    // A failed AUTH leaves the connection waiting for another attempt, so a new
+    // user can register right away.
+    for action in ["AUTH", "REGISTER"] {
+        send_message(&mut writer, &format!("{action} {user} {password}")).await?;
+        if wait_for_login(&mut reader).await? {
+            return Ok((reader, writer));
+        }
+    }
+    bail!("Could not log in or register")
+}
+
+/// Waits for the answer to a login attempt, skipping broadcasts that arrive first.
+async fn wait_for_login(reader: &mut OwnedReadHalf) -> Result<bool> {
+    loop {
+        match receive_message(reader).await? {
+            ResponseType::Text(msg) if msg == "AUTH OK" || msg == "Registration successful" => {
+                return Ok(true)
+            }
+            ResponseType::Error(_) => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// Sends chat messages and uploads at the configured rates until the deadline.
+async fn send_traffic(
+    mut writer: OwnedWriteHalf,
+    index: usize,
+    config: Arc<Config>,
+    stats: Arc<Stats>,
+    start: Instant,
+    deadline: Instant,
+) -> Result<OwnedWriteHalf> {
+    let mut texts = ticker(config.text_rate);
+    let mut files = ticker(config.file_rate);
+    let content = vec![0x5a; config.file_size];
+    let end = sleep(deadline.saturating_duration_since(Instant::now()));
+    tokio::pin!(end);
+    let mut seq = 0u64;
+    loop {
+        let is_file = tokio::select! {
+            _ = &mut end => return Ok(writer),
+            Some(_) = tick(&mut texts) => false,
+            Some(_) = tick(&mut files) => true,
+        };
+        seq += 1;
+        let sent_at = start.elapsed().as_micros();
+        let message = if is_file {
+            let name = format!("load-{index}-{seq}-{sent_at}.bin");
+            ClientMessage::new(MessageType::Upload(name, content.clone()))
+        } else {
+            ClientMessage::new(MessageType::Text(format!("load {index} {seq} {sent_at}")))
+        };
+        let traffic = if is_file {
+            stats.pending_files.lock().unwrap().insert(message.id);
+            &stats.file
+        } else {
+            &stats.text
+        };
+        send_message(&mut writer, &message)
+            .await
+            .context("Failed to send")?;
+        traffic.sent.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// An interval firing `rate` times a second, starting at a random point of the
+/// first period so users do not send in lockstep; `None` when the rate is 0.
+fn ticker(rate: f64) -> Option<tokio::time::Interval> {
+    if rate <= 0.0 {
+        return None;
+    }
+    let period = Duration::from_secs_f64(1.0 / rate);
+    let offset = period.mul_f64(rand::thread_rng().gen::<f64>());
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + offset, period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    Some(ticker)
+}
+
+async fn tick(ticker: &mut Option<tokio::time::Interval>) -> Option<()> {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+            Some(())
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Counts acks and measures the latency of the broadcasts other users sent.
+async fn read_traffic(mut reader: OwnedReadHalf, stats: Arc<Stats>, start: Instant) {
+    while let Ok(response) = receive_message::<ResponseType, _>(&mut reader).await {
+        let now = start.elapsed();
+        match response {
+            ResponseType::Chat { text, .. } => {
+                if let Some(sent_at) = sent_at(&text, ' ') {
+                    record(&stats.text, now, sent_at, text.len());
+                }
+            }
+            ResponseType::File(name, content) => {
+                if let Some(sent_at) = sent_at(name.trim_end_matches(".bin"), '-') {
+                    record(&stats.file, now, sent_at, content.len());
+                }
+            }
+            ResponseType::Ack(id) => {
+                acked_traffic(&stats, id)
+                    .acked
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            ResponseType::Nack(id, reason) => {
+                warn!("Rejected: {}", reason);
+                acked_traffic(&stats, id)
+                    .rejected
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The traffic an acknowledged message belonged to.
+fn acked_traffic(stats: &Stats, id: MessageId) -> &TrafficStats {
+    if stats.pending_files.lock().unwrap().remove(&id) {
+        &stats.file
+    } else {
+        &stats.text
+    }
+}
+
+/// Reads the send time, in microseconds since the start, from the last field of a payload.
+fn sent_at(payload: &str, separator: char) -> Option<Duration> {
+    let rest = payload.strip_prefix("load")?;
+    let micros = rest.rsplit(separator).next()?.parse().ok()?;
+    Some(Duration::from_micros(micros))
+}
+
+fn record(traffic: &TrafficStats, now: Duration, sent_at: Duration, bytes: usize) {
+    traffic.delivered.fetch_add(1, Ordering::Relaxed);
+    traffic
+        .bytes_delivered
+        .fetch_add(bytes as u64, Ordering::Relaxed);
+    traffic
+        .latencies
+        .lock()
+        .unwrap()
+        .push(now.saturating_sub(sent_at));
+}
+
+/// The latency below which a fraction `p` of the sorted samples fall, in milliseconds.
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index].as_secs_f64() * 1000.0
+}
+
+fn build_report(stats: &Stats, users: usize, connected: usize, elapsed: Duration) -> Report {
+    let seconds = elapsed.as_secs_f64();
+    let mut connect_times = stats.connect_times.lock().unwrap().clone();
+    connect_times.sort();
+    let traffic = |traffic: &TrafficStats| {
+        let mut latencies = traffic.latencies.lock().unwrap().clone();
+        latencies.sort();
+        let sent = traffic.sent.load(Ordering::Relaxed);
+        let delivered = traffic.delivered.load(Ordering::Relaxed);
+        TrafficReport {
+            sent,
+            acked: traffic.acked.load(Ordering::Relaxed),
+            rejected: traffic.rejected.load(Ordering::Relaxed),
+            delivered,
+            expected: sent * (connected as u64 - 1),
+            sent_per_sec: sent as f64 / seconds,
+            delivered_per_sec: delivered as f64 / seconds,
+            delivered_bytes_per_sec: traffic.bytes_delivered.load(Ordering::Relaxed) as f64
+                / seconds,
+            p50_ms: percentile(&latencies, 0.5),
+            p90_ms: percentile(&latencies, 0.9),
+            p99_ms: percentile(&latencies, 0.99),
+            max_ms: percentile(&latencies, 1.0),
+        }
+    };
+    Report {
+        users,
+        duration_secs: seconds,
+        connections: ConnectionReport {
+            succeeded: connected,
+            failed: stats.connect_failures.load(Ordering::Relaxed),
+            p50_ms: percentile(&connect_times, 0.5),
+            p99_ms: percentile(&connect_times, 0.99),
+        },
+        text: traffic(&stats.text),
+        file: traffic(&stats.file),
+    }
+}
+
+fn print_table(report: &Report) {
+    let connections = &report.connections;
+    println!(
+        "{} users over {:.1} s: {} connected, {} failed (login p50 {:.1} ms, p99 {:.1} ms)",
+        report.users,
+        report.duration_secs,
+        connections.succeeded,
+        connections.failed,
+        connections.p50_ms,
+        connections.p99_ms
+    );
+    println!();
+    println!(
+        "{:<6} {:>8} {:>8} {:>8} {:>10} {:>10} {:>9} {:>10} {:>8} {:>8} {:>8} {:>8}",
+        "kind",
+        "sent",
+        "acked",
+        "nacked",
+        "delivered",
+        "expected",
+        "sent/s",
+        "deliv/s",
+        "p50 ms",
+        "p90 ms",
+        "p99 ms",
+        "max ms"
+    );
+    for (kind, traffic) in [("text", &report.text), ("file", &report.file)] {
+        println!(
+            "{:<6} {:>8} {:>8} {:>8} {:>10} {:>10} {:>9.1} {:>10.1} {:>8.1} {:>8.1} {:>8.1} {:>8.1}",
+            kind,
+            traffic.sent,
+            traffic.acked,
+            traffic.rejected,
+            traffic.delivered,
+            traffic.expected,
+            traffic.sent_per_sec,
+            traffic.delivered_per_sec,
+            traffic.p50_ms,
+            traffic.p90_ms,
+            traffic.p99_ms,
+            traffic.max_ms
+        );
+    }
+}
@@ -1,94 +1,170 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
 use clap::Parser;
-use hwork15::{parse_socket_addr, send_message};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::broadcast;
-use tokio::{net::TcpListener, sync::Mutex};
-use tracing::{error, info};
+use hwork15::config::ServerConfig;
+use hwork15::{parse_socket_addr, Server};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
-#[path = "../server_utils.rs"]
-mod server_utils;
-use crate::server_utils::handle_client;
-
-#[path = "../db.rs"]
-mod db; // Ensure this line is added to import db.rs
-use db::Database;
-
-/// Server configuration
+/// Server configuration. Flags take precedence over `HWORK_*` environment
+/// variables, which take precedence over the config file.
 #[derive(Parser)]
 struct Config {
-    #[arg(short, long, default_value = "127.0.0.1:11111", value_parser = parse_socket_addr)]
-    address: SocketAddr,
-    #[arg(short, long, default_value = "sqlite:./db.sqlite")]
-    database_url: String,
+    /// TOML config file, see `ServerConfig` for the schema.
+    #[arg(short, long, env = "HWORK_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(short, long, env = "HWORK_ADDRESS", value_parser = parse_socket_addr)]
+    address: Option<SocketAddr>,
+    #[arg(short, long, env = "HWORK_DATABASE_URL")]
+    database_url: Option<String>,
+    /// Optional WebSocket gateway for browser clients.
+    #[arg(short, long, env = "HWORK_WS_ADDRESS", value_parser = parse_socket_addr)]
+    ws_address: Option<SocketAddr>,
+    /// Optional HTTP listener exporting Prometheus metrics on /metrics.
+    #[arg(short, long, env = "HWORK_METRICS_ADDRESS", value_parser = parse_socket_addr)]
+    metrics_address: Option<SocketAddr>,
+    #[arg(long, env = "HWORK_BROADCAST_CAPACITY")]
+    broadcast_capacity: Option<usize>,
+    #[arg(long, env = "HWORK_MAX_FILE_BYTES")]
+    max_file_bytes: Option<u64>,
+    #[arg(long, env = "HWORK_OFFLINE_QUEUE_LIMIT")]
+    offline_queue_limit: Option<i64>,
+    #[arg(long, env = "HWORK_OFFLINE_QUEUE_TTL_SECS")]
+    offline_queue_ttl_secs: Option<u64>,
+    #[arg(long, env = "HWORK_MAX_IMAGE_DIMENSION")]
+    max_image_dimension: Option<u32>,
+    #[arg(long, env = "HWORK_THUMBNAIL_SIZE")]
+    thumbnail_size: Option<u32>,
+    #[arg(long, env = "HWORK_STORAGE_ROOT")]
+    storage_root: Option<PathBuf>,
+    #[arg(long, env = "HWORK_MIN_PASSWORD_LENGTH")]
+    min_password_length: Option<usize>,
+    #[arg(long, env = "HWORK_MAX_PASSWORD_LENGTH")]
+    max_password_length: Option<usize>,
+    #[arg(long, env = "HWORK_BANNED_PASSWORDS_FILE")]
+    banned_passwords_file: Option<PathBuf>,
+    #[arg(long, env = "HWORK_ARGON2_MEMORY_KIB")]
+    argon2_memory_kib: Option<u32>,
+    #[arg(long, env = "HWORK_ARGON2_ITERATIONS")]
+    argon2_iterations: Option<u32>,
+    #[arg(long, env = "HWORK_ARGON2_PARALLELISM")]
+    argon2_parallelism: Option<u32>,
+    #[arg(long, env = "HWORK_MAX_CONCURRENT_HASHES")]
+    max_concurrent_hashes: Option<usize>,
+    /// Users allowed to run admin commands, comma-separated.
+    #[arg(long, env = "HWORK_ADMINS", value_delimiter = ',')]
+    admins: Option<Vec<String>>,
+    #[arg(long, env = "HWORK_AUDIT_RETENTION_DAYS")]
+    audit_retention_days: Option<u64>,
+    #[arg(long, env = "HWORK_RETENTION_MAX_AGE_DAYS")]
+    retention_max_age_days: Option<u64>,
+    #[arg(long, env = "HWORK_RETENTION_MAX_MESSAGES")]
+    retention_max_messages: Option<u64>,
+    #[arg(long, env = "HWORK_RETENTION_INTERVAL_SECS")]
+    retention_interval_secs: Option<u64>,
+    #[arg(long, env = "HWORK_RETENTION_BATCH_SIZE")]
+    retention_batch_size: Option<u64>,
+    #[arg(long, env = "HWORK_VACUUM_INTERVAL_HOURS")]
+    vacuum_interval_hours: Option<u64>,
+    #[arg(short, long, env = "HWORK_LOG_LEVEL")]
+    log_level: Option<String>,
+}
+
+impl Config {
+    /// Loads the config file and layers flags and environment variables on top.
+    fn resolve(self) -> Result<ServerConfig> {
+        let mut config = ServerConfig::load(self.config.as_deref())?;
+        if let Some(address) = self.address {
+            config.listeners.address = address;
+        }
+        if let Some(ws_address) = self.ws_address {
+            config.listeners.ws_address = Some(ws_address);
+        }
+        if let Some(metrics_address) = self.metrics_address {
+            config.listeners.metrics_address = Some(metrics_address);
+        }
+        if let Some(url) = self.database_url {
+            config.database.url = url;
+        }
+        if let Some(capacity) = self.broadcast_capacity {
+            config.limits.broadcast_capacity = capacity;
+        }
+        if let Some(max_file_bytes) = self.max_file_bytes {
+            config.limits.max_file_bytes = max_file_bytes;
+        }
+        if let Some(limit) = self.offline_queue_limit {
+            config.limits.offline_queue_limit = limit;
+        }
+        if let Some(ttl) = self.offline_queue_ttl_secs {
+            config.limits.offline_queue_ttl_secs = ttl;
+        }
+        if let Some(dimension) = self.max_image_dimension {
+            config.limits.max_image_dimension = dimension;
+        }
+        if let Some(size) = self.thumbnail_size {
+            config.limits.thumbnail_size = size;
+        }
+        if let Some(root) = self.storage_root {
+            config.storage.root = root;
+        }
+        if let Some(length) = self.min_password_length {
+            config.auth.min_password_length = length;
+        }
+        if let Some(length) = self.max_password_length {
+            config.auth.max_password_length = length;
+        }
+        if let Some(path) = self.banned_passwords_file {
+            config.auth.banned_passwords_file = Some(path);
+        }
+        if let Some(memory) = self.argon2_memory_kib {
+            config.auth.argon2_memory_kib = memory;
+        }
+        if let Some(iterations) = self.argon2_iterations {
+            config.auth.argon2_iterations = iterations;
+        }
+        if let Some(parallelism) = self.argon2_parallelism {
+            config.auth.argon2_parallelism = parallelism;
+        }
+        if let Some(hashes) = self.max_concurrent_hashes {
+            config.auth.max_concurrent_hashes = hashes;
+        }
+        if let Some(admins) = self.admins {
+            config.auth.admins = admins;
+        }
+        if let Some(days) = self.audit_retention_days {
+            config.audit.retention_days = days;
+        }
+        if let Some(days) = self.retention_max_age_days {
+            config.retention.max_age_days = days;
+        }
+        if let Some(messages) = self.retention_max_messages {
+            config.retention.max_messages = messages;
+        }
+        if let Some(secs) = self.retention_interval_secs {
+            config.retention.interval_secs = secs;
+        }
+        if let Some(batch_size) = self.retention_batch_size {
+            config.retention.batch_size = batch_size;
+        }
+        if let Some(hours) = self.vacuum_interval_hours {
+            config.retention.vacuum_interval_hours = hours;
+        }
+        if let Some(level) = self.log_level {
+            config.logging.level = level;
+        }
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let config = Config::parse().resolve()?;
+
     tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
+        .with_max_level(config.log_level())
         .with_target(false)
         .init();
 
-    let config = Config::parse();
-    let addr = &config.address;
-    let database_url = &config.database_url;
-
-    let database = Database::new(database_url).await?;
-    let database = Arc::new(database);
-
-    let listener = TcpListener::bind(addr)
-        .await
-        .context("Failed to bind to socket")?;
-
-    info!("Server running on {}", addr);
-
-    let (br_send, _br_recv) = broadcast::channel(1024);
-
-    loop {
-        let Ok((stream, addr)) = listener.accept().await else {
-            error!("Failed to accept connection");
-            continue;
-        };
-
-        info!("New connection from {}", addr);
-
-        let sender = br_send.clone();
-        let mut receiver = br_send.subscribe();
-
-        let (stream_reader, stream_writer) = stream.into_split();
-
-        let stream_writer_sync = Arc::new(Mutex::new(stream_writer));
-        let stream_writer_clone = stream_writer_sync.clone();
-
-        let db_clone = Arc::clone(&database);
-
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(
-                stream_reader,
-                &stream_writer_sync.clone(),
-                addr,
-                sender,
-                db_clone,
-            )
-            .await
-            {
-                error!("Error handling client: {:?}", e);
-            }
-        });
-
-        tokio::spawn(async move {
-            while let Ok((msg, other_addr)) = receiver.recv().await {
-                if other_addr == addr {
-                    continue;
-                }
-                let mut stream = stream_writer_clone.lock().await;
-                if let Err(e) = send_message(&mut *stream, &msg).await {
-                    error!("{e}");
-                    break;
-                }
-                drop(stream);
-            }
-        });
-    }
+    let server = Server::builder().config(config).build().await?;
+    server.run().await
 }
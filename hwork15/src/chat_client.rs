@@ -0,0 +1,395 @@
+use futures_util::Stream;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use crate::{
+    receive_message_within, send_message, ClientMessage, MessageId, MessageType, Quote,
+    ReceiptStatus, ResponseType, SealedMessage, SharedLibError, DEFAULT_MAX_FRAME_BYTES,
+};
+
+/// Something the server sent, as seen by a logged-in client.
+#[derive(Debug, Clone)]
+pub enum ChatEvent {
+    /// A chat message, stored under `id` on the server; `mentions` lists the
+    /// users it addresses as `@name` and `reply_to` quotes the message it answers.
+    Chat {
+        id: i64,
+        from: String,
+        text: String,
+        mentions: Vec<String>,
+        reply_to: Option<Quote>,
+    },
+    /// A direct message; `queued_at` is set when it waited in the offline queue.
+    Direct {
+        id: i64,
+        from: String,
+        text: String,
+        queued_at: Option<String>,
+    },
+    /// An end-to-end encrypted direct message, left for the application to open.
+    SealedDirect {
+        id: i64,
+        from: String,
+        sealed: SealedMessage,
+        queued_at: Option<String>,
+    },
+    /// Identity public key of `user`, as asked for with `fetch_key`.
+    PublicKey { user: String, key: Option<[u8; 32]> },
+    /// A file shared by someone else.
+    File { name: String, content: Vec<u8> },
+    /// A full-resolution image, as asked for with `fetch_image`.
+    Image {
+        from: String,
+        name: String,
+        content: Vec<u8>,
+    },
+    /// An image someone shared; `fetch_image(id)` gets the full resolution.
+    Thumbnail {
+        id: u64,
+        from: String,
+        name: String,
+        width: u32,
+        height: u32,
+        thumbnail: Vec<u8>,
+    },
+    /// Everyone online, sorted.
+    Users(Vec<String>),
+    /// Informational text from the server, such as command replies.
+    Notice(String),
+    /// An error reported by the server.
+    Error(String),
+    /// The message sent under this id was stored and broadcast.
+    Acked(MessageId),
+    /// The message sent under this id was rejected.
+    Rejected { id: MessageId, reason: String },
+    /// `user` received or read the message sent under this id.
+    Receipt {
+        id: MessageId,
+        user: String,
+        status: ReceiptStatus,
+    },
+}
+
+impl ChatEvent {
+    /// The event for a server response; `None` for a server-side quit, which ends the stream.
+    fn from_response(response: ResponseType) -> Option<Self> {
+        let event = match response {
+            ResponseType::Chat {
+                id,
+                from,
+                text,
+                mentions,
+                reply_to,
+            } => ChatEvent::Chat {
+                id,
+                from,
+                text,
+                mentions,
+                reply_to,
+            },
+            ResponseType::Direct {
+                id,
+                from,
+                text,
+                queued_at,
+            } => ChatEvent::Direct {
+                id,
+                from,
+                text,
+                queued_at,
+            },
+            ResponseType::SealedDirect {
+                id,
+                from,
+                sealed,
+                queued_at,
+            } => ChatEvent::SealedDirect {
+                id,
+                from,
+                sealed,
+                queued_at,
+            },
+            ResponseType::PublicKey { user, key } => ChatEvent::PublicKey { user, key },
+            ResponseType::File(name, content) => ChatEvent::File { name, content },
+            ResponseType::Image {
+                from,
+                name,
+                content,
+            } => ChatEvent::Image {
+                from,
+                name,
+                content,
+            },
+            ResponseType::Thumbnail {
+                id,
+                from,
+                name,
+                width,
+                height,
+                thumbnail,
+            } => ChatEvent::Thumbnail {
+                id,
+                from,
+                name,
+                width,
+                height,
+                thumbnail,
+            },
+            ResponseType::Users(users) => ChatEvent::Users(users),
+            ResponseType::Text(text) => ChatEvent::Notice(text),
+            ResponseType::Error(text) => ChatEvent::Error(text),
+            ResponseType::Ack(id) => ChatEvent::Acked(id),
+            ResponseType::Nack(id, reason) => ChatEvent::Rejected { id, reason },
+            ResponseType::Receipt { id, user, status } => ChatEvent::Receipt { id, user, status },
+            ResponseType::Quit(_) => return None,
+        };
+        Some(event)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ChatClientError {
+    #[error("Failed to connect to {0}: {1}")]
+    Connect(SocketAddr, std::io::Error),
+    #[error("Login failed: {0}")]
+    Login(String),
+    #[error("Connection closed")]
+    Closed,
+    #[error(transparent)]
+    Protocol(#[from] SharedLibError),
+}
+
+/// Answer to a pending AUTH or REGISTER: `Err` holds the server's reason.
+type LoginReply = oneshot::Sender<Result<(), String>>;
+
+/// Sending half of a connection to a chat server. Clones share the connection,
+/// so several tasks can send at once. Every send returns the id the server
+/// acknowledges it under, as `ChatEvent::Acked` or `ChatEvent::Rejected`.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), hwork15::ChatClientError> {
+/// use futures_util::StreamExt;
+/// use hwork15::{ChatClient, ChatEvent};
+///
+/// let (client, mut events) = ChatClient::connect("127.0.0.1:11111".parse().unwrap()).await?;
+/// client.login("bot", "bot-password-1").await?;
+/// while let Some(event) = events.next().await {
+///     if let ChatEvent::Chat { from, text, .. } = event {
+///         client.send_text(format!("{from} said {text}")).await?;
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ChatClient {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    pending_login: Arc<std::sync::Mutex<Option<LoginReply>>>,
+}
+
+/// Everything the server sends, except login answers. Ends when the connection closes.
+pub struct ChatEvents {
+    receiver: mpsc::UnboundedReceiver<ChatEvent>,
+}
+
+impl Stream for ChatEvents {
+    type Item = ChatEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ChatEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl ChatClient {
+    /// Connects to the server; log in with `login` or `register` before sending.
+    pub async fn connect(addr: SocketAddr) -> Result<(Self, ChatEvents), ChatClientError> {
+        Self::connect_within(addr, DEFAULT_MAX_FRAME_BYTES).await
+    }
+
+    /// Like `connect`, but the connection is closed when the server sends a
+    /// frame longer than `max_frame_bytes`.
+    pub async fn connect_within(
+        addr: SocketAddr,
+        max_frame_bytes: usize,
+    ) -> Result<(Self, ChatEvents), ChatClientError> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| ChatClientError::Connect(addr, e))?;
+        let (reader, writer) = stream.into_split();
+        let client = Self {
+            writer: Arc::new(Mutex::new(writer)),
+            pending_login: Arc::default(),
+        };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(read_events(
+            reader,
+            max_frame_bytes,
+            sender,
+            client.pending_login.clone(),
+        ));
+        Ok((client, ChatEvents { receiver }))
+    }
+
+    /// Logs in as an existing user.
+    pub async fn login(&self, username: &str, password: &str) -> Result<(), ChatClientError> {
+        self.authenticate("AUTH", username, password).await
+    }
+
+    /// Creates a user and logs in as them.
+    pub async fn register(&self, username: &str, password: &str) -> Result<(), ChatClientError> {
+        self.authenticate("REGISTER", username, password).await
+    }
+
+    async fn authenticate(
+        &self,
+        action: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<(), ChatClientError> {
+        let (reply, answer) = oneshot::channel();
+        *self.pending_login.lock().unwrap() = Some(reply);
+        send_message(
+            &mut *self.writer.lock().await,
+            &format!("{action} {username} {password}"),
+        )
+        .await?;
+        match answer.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(reason)) => Err(ChatClientError::Login(reason)),
+            Err(_) => Err(ChatClientError::Closed),
+        }
+    }
+
+    /// Sends any message and returns its id.
+    pub async fn send(&self, message: MessageType) -> Result<MessageId, ChatClientError> {
+        let message = ClientMessage::new(message);
+        send_message(&mut *self.writer.lock().await, &message).await?;
+        Ok(message.id)
+    }
+
+    pub async fn send_text(&self, text: impl Into<String>) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::Text(text.into())).await
+    }
+
+    /// Sends a chat message answering the chat message stored under `message_id`.
+    pub async fn send_reply(
+        &self,
+        message_id: i64,
+        text: impl Into<String>,
+    ) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::Reply(message_id, text.into())).await
+    }
+
+    /// Sends a direct message, queued by the server while `to` is offline.
+    pub async fn send_direct(
+        &self,
+        to: &str,
+        text: impl Into<String>,
+    ) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::Direct(to.to_string(), text.into()))
+            .await
+    }
+
+    /// Sends a direct message already sealed for `to`.
+    pub async fn send_sealed(
+        &self,
+        to: &str,
+        sealed: SealedMessage,
+    ) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::SealedDirect(to.to_string(), sealed))
+            .await
+    }
+
+    /// Asks the server to share the file at `path`, relative to its storage
+    /// root, with everyone else online.
+    pub async fn request_file(&self, path: &str) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::File(path.to_string())).await
+    }
+
+    /// Like `request_file`, for an image: others receive a thumbnail first.
+    pub async fn request_image(&self, path: &str) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::Image(path.to_string())).await
+    }
+
+    /// Asks for the full resolution of the image announced under `id`.
+    pub async fn fetch_image(&self, id: u64) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::FetchImage(id)).await
+    }
+
+    /// Sends a local file's content; the server stores it and shares it with everyone online.
+    pub async fn upload(&self, name: &str, content: Vec<u8>) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::Upload(name.to_string(), content))
+            .await
+    }
+
+    /// Runs the server command `/name args`.
+    pub async fn command(&self, name: &str, args: &str) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::Command(name.to_string(), args.to_string()))
+            .await
+    }
+
+    /// Tells the sender of the chat message `message_id` it was received or read.
+    pub async fn send_receipt(
+        &self,
+        message_id: i64,
+        status: ReceiptStatus,
+    ) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::Receipt(message_id, status)).await
+    }
+
+    pub async fn publish_key(&self, key: [u8; 32]) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::PublishKey(key)).await
+    }
+
+    /// Asks for the identity key of `user`, answered with `ChatEvent::PublicKey`.
+    pub async fn fetch_key(&self, user: &str) -> Result<MessageId, ChatClientError> {
+        self.send(MessageType::FetchKey(user.to_string())).await
+    }
+
+    /// Ends the session; the server then closes the connection.
+    pub async fn quit(&self) -> Result<(), ChatClientError> {
+        self.send(MessageType::Quit).await.map(|_| ())
+    }
+}
+
+/// Turns responses into events until the connection closes, handing login
+/// answers to the pending `login` or `register` call instead.
+async fn read_events(
+    mut reader: OwnedReadHalf,
+    max_frame_bytes: usize,
+    events: mpsc::UnboundedSender<ChatEvent>,
+    pending_login: Arc<std::sync::Mutex<Option<LoginReply>>>,
+) {
+    while let Ok(response) =
+        receive_message_within::<ResponseType, _>(&mut reader, max_frame_bytes).await
+    {
+        // Broadcasts reach connections that are still logging in, so only the
+        // login answers themselves are taken out of the stream.
+        let answer = match &response {
+            ResponseType::Text(msg) if msg == "AUTH OK" || msg == "Registration successful" => {
+                Some(Ok(()))
+            }
+            ResponseType::Error(msg) => {
+                Some(Err(msg.lines().next().unwrap_or_default().to_string()))
+            }
+            _ => None,
+        };
+        if let Some(answer) = answer {
+            if let Some(reply) = pending_login.lock().unwrap().take() {
+                let _ = reply.send(answer);
+                continue;
+            }
+        }
+        let Some(event) = ChatEvent::from_response(response) else {
+            break;
+        };
+        // Nobody listening is fine; a client may only send.
+        let _ = events.send(event);
+    }
+}
@@ -1,30 +1,74 @@
 use anyhow::{Context, Result};
-use chrono::Local;
-use hwork15::{receive_message, send_message, ResponseType};
-use image::{load_from_memory, ImageFormat};
-use std::path::Path;
-use tokio::io::AsyncWriteExt;
+use hwork15::{
+    receive_message_within, send_message, ClientMessage, MessageType, ReceiptStatus, ResponseType,
+};
+use std::io::Write;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::task;
-use tokio::{fs, io::BufReader};
-use tracing::{error, info};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::{error, info, warn};
 
-pub async fn handle_server(stream_r: &mut OwnedReadHalf) -> Result<()> {
+use crate::downloads::{Downloads, FileOutcome};
+use crate::e2e::{E2eSession, Notice};
+use crate::line_editor::OnlineUsers;
+
+/// Picks out chat messages that mention the logged-in user.
+pub struct MentionAlert {
+    pub username: String,
+    /// Ring the terminal bell for each mention.
+    pub bell: bool,
+}
+
+/// Handles server responses. Ids of incoming chat messages are passed to `delivered`
+/// when receipts are enabled. With `e2e`, fetched keys release held direct messages
+/// and sealed ones are decrypted. Frames longer than `max_frame_bytes` end the session.
+pub async fn handle_server(
+    stream_r: &mut OwnedReadHalf,
+    max_frame_bytes: usize,
+    online: &OnlineUsers,
+    delivered: Option<&UnboundedSender<i64>>,
+    downloads: &Downloads,
+    e2e: Option<&E2eSession>,
+    alert: &MentionAlert,
+) -> Result<()> {
     loop {
-        let response = receive_message::<ResponseType, OwnedReadHalf>(stream_r)
+        let response = receive_message_within::<ResponseType, _>(stream_r, max_frame_bytes)
             .await
             .context("Failed to receive message")?;
         // drop(stream);
         match response {
             ResponseType::File(name, content) => {
-                info!("Received file with name: {name}");
-                save_file(&name, &content)
-                    .await
-                    .context("Failed to save file")?;
+                let size = content.len();
+                match downloads.receive_file(&name, content).await {
+                    Ok(FileOutcome::Saved(path)) => {
+                        info!("Saved file {} as {}", name, path.display())
+                    }
+                    Ok(FileOutcome::Pending(id)) => info!(
+                        "File {} ({} bytes) is waiting: .accept {} or .decline {}",
+                        name, size, id, id
+                    ),
+                    Err(e) => error!("Failed to save file {}: {:#}", name, e),
+                }
             }
-            ResponseType::Image(name, img) => {
-                info!("Received image with name: {name}");
-                save_image(&img).await.context("Failed to save image")?;
+            ResponseType::Image {
+                from,
+                name,
+                content,
+            } => match downloads.save_image(&from, &name, content).await {
+                Ok(path) => info!("Saved image {} from {} as {}", name, from, path.display()),
+                Err(e) => error!("Failed to save image {}: {:#}", name, e),
+            },
+            ResponseType::Thumbnail {
+                id,
+                from,
+                name,
+                width,
+                height,
+                ..
+            } => {
+                info!(
+                    "Image #{}: {} shared {} ({}x{}), .fetch {} to save it",
+                    id, from, name, width, height, id
+                );
             }
             ResponseType::Text(msg) => {
                 info!("Chat: {}", msg);
@@ -36,68 +80,138 @@ pub async fn handle_server(stream_r: &mut OwnedReadHalf) -> Result<()> {
             ResponseType::Error(msg) => {
                 error!("Server: {}", msg);
             }
+            ResponseType::Users(users) => {
+                info!("Online: {}", users.join(", "));
+                if let Ok(mut online) = online.lock() {
+                    *online = users;
+                }
+            }
+            ResponseType::Chat {
+                id,
+                from,
+                text,
+                mentions,
+                reply_to,
+            } => {
+                if let Some(quote) = reply_to {
+                    info!("  ↪ #{} {}: {}", quote.id, quote.from, quote.text);
+                }
+                if mentions.contains(&alert.username) {
+                    warn!("Chat #{} mentions you: {}: {}", id, from, text);
+                    if alert.bell {
+                        ring_bell();
+                    }
+                } else {
+                    info!("Chat #{}: {}: {}", id, from, text);
+                }
+                if let Some(delivered) = delivered {
+                    let _ = delivered.send(id);
+                }
+            }
+            ResponseType::Direct {
+                id,
+                from,
+                text,
+                queued_at,
+            } => match queued_at {
+                Some(queued_at) => info!(
+                    "Direct #{} from {} (sent {}): {}",
+                    id, from, queued_at, text
+                ),
+                None => info!("Direct #{} from {}: {}", id, from, text),
+            },
+            ResponseType::SealedDirect {
+                id,
+                from,
+                sealed,
+                queued_at,
+            } => {
+                let Some(e2e) = e2e else {
+                    info!(
+                        "Direct #{} from {} is encrypted; restart with --e2e to read it",
+                        id, from
+                    );
+                    continue;
+                };
+                let opened = e2e.state.lock().map(|mut state| state.open(&from, &sealed));
+                match opened {
+                    Ok(Ok((text, notice))) => {
+                        notice.into_iter().for_each(log_notice);
+                        match queued_at {
+                            Some(queued_at) => info!(
+                                "Direct #{} from {} (encrypted, sent {}): {}",
+                                id, from, queued_at, text
+                            ),
+                            None => info!("Direct #{} from {} (encrypted): {}", id, from, text),
+                        }
+                    }
+                    Ok(Err(e)) => error!("Direct #{}: {:#}", id, e),
+                    Err(_) => error!("Encryption state is unavailable"),
+                }
+            }
+            ResponseType::PublicKey { user, key } => {
+                let Some(e2e) = e2e else { continue };
+                let Ok(mut state) = e2e.state.lock() else {
+                    continue;
+                };
+                let (notices, outgoing) = state.key_received(&user, key);
+                notices.into_iter().for_each(log_notice);
+                for message in outgoing {
+                    let _ = e2e.outgoing.send(message);
+                }
+            }
+            ResponseType::Ack(id) => {
+                info!("Message {} stored", id);
+            }
+            ResponseType::Nack(id, reason) => {
+                error!("Message {} rejected: {}", id, reason);
+            }
+            ResponseType::Receipt { id, user, status } => {
+                info!("Message {} {} by {}", id, status, user);
+            }
         }
     }
 
     Ok(())
 }
 
-pub async fn save_file(name: &str, content: &[u8]) -> Result<()> {
-    let dir = Path::new("client_db/files");
-    fs::create_dir_all(dir)
-        .await
-        .context("Failed to create directory.")?;
-
-    let path = dir.join(name);
-
-    let mut file = fs::File::create(path)
-        .await
-        .context("Failed to create file.")?;
-    file.write_all(content)
-        .await
-        .context("Failed to write to file.")?;
+/// Logs a notice about end-to-end encryption.
+pub fn log_notice(notice: Notice) {
+    match notice {
+        Notice::Info(text) => info!("{}", text),
+        Notice::Warning(text) => warn!("{}", text),
+    }
+}
 
-    Ok(())
+/// Rings the terminal bell; stderr keeps it clear of the prompt and the TUI.
+pub fn ring_bell() {
+    let mut stderr = std::io::stderr();
+    let _ = stderr.write_all(b"\x07");
+    let _ = stderr.flush();
 }
 
-pub async fn save_image(content: &[u8]) -> Result<()> {
-    let dir = Path::new("client_db/images");
-    fs::create_dir_all(dir)
+/// Tells the server this client received or read the chat message `message_id`.
+pub async fn send_receipt(
+    stream_w: &mut OwnedWriteHalf,
+    message_id: i64,
+    status: ReceiptStatus,
+) -> Result<()> {
+    let receipt = ClientMessage::new(MessageType::Receipt(message_id, status));
+    send_message(stream_w, &receipt)
         .await
-        .context("Failed to create directory.")?;
-
-    let timestamp = Local::now().format("%Y%m%d%H%M%S").to_string();
-    let path = dir.join(format!("{}.png", timestamp));
-
-    let content_cp = content.to_vec();
-    // Spawn blocking to handle the image processing
-    let img = task::spawn_blocking(move || {
-        load_from_memory(&content_cp).context("Failed to load content from memory")
-    })
-    .await??;
-
-    // Save image on a blocking thread
-    task::spawn_blocking(move || {
-        img.save_with_format(path, ImageFormat::Png)
-            .context("Failed to save image with PNG format")
-    })
-    .await??;
-
-    Ok(())
+        .context("Failed to send receipt")
 }
 
+/// Logs in or registers from the user's input and returns the username.
 pub async fn handle_authentication_or_registration(
     stream_r: &mut OwnedReadHalf,
     stream_w: &mut OwnedWriteHalf,
-    lines: &mut tokio::io::Lines<BufReader<tokio::io::Stdin>>,
-) -> Result<()> {
+    lines: &mut UnboundedReceiver<String>,
+    max_frame_bytes: usize,
+) -> Result<String> {
     loop {
         println!("Enter command (REGISTER or AUTH) followed by username and password:");
-        let line = lines
-            .next_line()
-            .await
-            .context("Failed to read input line")?
-            .context("No input received")?;
+        let line = lines.recv().await.context("No input received")?;
 
         let parts: Vec<&str> = line.split_whitespace().collect();
         if parts.len() == 3 && (parts[0] == "REGISTER" || parts[0] == "AUTH") {
@@ -108,14 +222,14 @@ pub async fn handle_authentication_or_registration(
             let auth_message = format!("{} {} {}", command, username, password);
             println!("{auth_message}");
             send_message(stream_w, &auth_message).await?;
-            let response: ResponseType = receive_message(stream_r).await?;
+            let response: ResponseType = receive_message_within(stream_r, max_frame_bytes).await?;
             match response {
                 ResponseType::Text(msg)
                     if msg.contains("AUTH OK") || msg.contains("Registration successful") =>
                 {
                     info!("Server response: {}", msg);
                     info!("You are now authenticated.");
-                    return Ok(());
+                    return Ok(username.to_string());
                 }
                 ResponseType::Error(err) => {
                     error!("Authentication or Registration failed: {}", err);
@@ -0,0 +1,565 @@
+use crate::ResponseType;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::Local;
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use crate::audit::{AuditAction, AuditEvent, AuditFilter, AuditOutcome};
+use crate::config::ServerConfig;
+use crate::db::{Database, StoredMessage};
+use crate::export::{self, Attachments, ExportRequest};
+use crate::server_utils::{Audience, ServerState};
+
+/// A server command invoked by clients as `/name args`.
+#[async_trait]
+pub trait CommandHandler: Send + Sync {
+    /// Name the command is invoked with, without the slash.
+    fn name(&self) -> &str;
+
+    /// One-line usage shown by `/help`.
+    fn help(&self) -> &str;
+
+    /// Runs the command; an error is reported back to the caller only.
+    async fn run(&self, ctx: &CommandContext<'_>, args: &str) -> Result<()>;
+}
+
+/// What a command can see and do on behalf of the calling user.
+pub struct CommandContext<'a> {
+    pub user: &'a str,
+    pub addr: SocketAddr,
+    state: &'a ServerState,
+}
+
+impl<'a> CommandContext<'a> {
+    pub fn new(user: &'a str, addr: SocketAddr, state: &'a ServerState) -> Self {
+        Self { user, addr, state }
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.state.database
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.state.config
+    }
+
+    /// Whether the calling user is listed in `auth.admins`.
+    pub fn is_admin(&self) -> bool {
+        self.state.is_admin(self.user)
+    }
+
+    /// Records an audit event for the calling user.
+    pub async fn audit(&self, action: AuditAction, target: Option<&str>, outcome: AuditOutcome) {
+        let mut event = AuditEvent::new(self.user, self.addr, action, outcome);
+        event.target = target.map(str::to_string);
+        self.state.audit(event).await;
+    }
+
+    /// Commands registered on this server, by name.
+    pub fn commands(&self) -> &CommandRegistry {
+        &self.state.commands
+    }
+
+    /// Sends `text` to the calling connection only.
+    pub fn reply(&self, text: impl Into<String>) {
+        self.send(ResponseType::Text(text.into()), Audience::Only(self.addr));
+    }
+
+    /// Sends `text` to every connected client, including the caller.
+    pub fn broadcast(&self, text: impl Into<String>) {
+        self.send(ResponseType::Text(text.into()), Audience::All);
+    }
+
+    /// Sends a file to the calling connection only; the client saves it like any other.
+    pub fn send_file(&self, name: impl Into<String>, content: Vec<u8>) {
+        self.send(
+            ResponseType::File(name.into(), content),
+            Audience::Only(self.addr),
+        );
+    }
+
+    fn send(&self, response: ResponseType, audience: Audience) {
+        let _ = self.state.sender.send((response, audience));
+    }
+}
+
+/// Command handlers by name, set up before the server starts.
+#[derive(Default)]
+pub struct CommandRegistry {
+    handlers: BTreeMap<String, Box<dyn CommandHandler>>,
+}
+
+impl CommandRegistry {
+    /// Registry with the commands every server ships with.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::default();
+        registry
+            .register(Help)
+            .register(Echo)
+            .register(Time)
+            .register(Roll)
+            .register(History)
+            .register(Mentions)
+            .register(Thread)
+            .register(Audit)
+            .register(Export);
+        registry
+    }
+
+    /// Adds a handler, replacing any earlier one with the same name.
+    pub fn register(&mut self, handler: impl CommandHandler + 'static) -> &mut Self {
+        self.handlers
+            .insert(handler.name().to_string(), Box::new(handler));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn CommandHandler> {
+        self.handlers.get(name).map(Box::as_ref)
+    }
+
+    /// Handlers sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = &dyn CommandHandler> {
+        self.handlers.values().map(Box::as_ref)
+    }
+}
+
+struct Help;
+
+#[async_trait]
+impl CommandHandler for Help {
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn help(&self) -> &str {
+        "/help - list server commands"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, _args: &str) -> Result<()> {
+        let lines: Vec<&str> = ctx.commands().iter().map(|cmd| cmd.help()).collect();
+        ctx.reply(format!("Commands:\n{}", lines.join("\n")));
+        Ok(())
+    }
+}
+
+struct Echo;
+
+#[async_trait]
+impl CommandHandler for Echo {
+    fn name(&self) -> &str {
+        "echo"
+    }
+
+    fn help(&self) -> &str {
+        "/echo <text> - repeat text back to you"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: &str) -> Result<()> {
+        ctx.reply(args);
+        Ok(())
+    }
+}
+
+struct Time;
+
+#[async_trait]
+impl CommandHandler for Time {
+    fn name(&self) -> &str {
+        "time"
+    }
+
+    fn help(&self) -> &str {
+        "/time - show the server's local time"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, _args: &str) -> Result<()> {
+        ctx.reply(format!(
+            "Server time: {}",
+            Local::now().format("%Y-%m-%d %H:%M:%S %Z")
+        ));
+        Ok(())
+    }
+}
+
+/// Messages `/history` shows by default and at most.
+const DEFAULT_HISTORY: i64 = 10;
+const MAX_HISTORY: i64 = 100;
+
+struct History;
+
+#[async_trait]
+impl CommandHandler for History {
+    fn name(&self) -> &str {
+        "history"
+    }
+
+    fn help(&self) -> &str {
+        "/history [N] - show the last N chat messages"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: &str) -> Result<()> {
+        let limit = if args.is_empty() {
+            DEFAULT_HISTORY
+        } else {
+            args.parse().context("Expected a number of messages")?
+        };
+        if !(1..=MAX_HISTORY).contains(&limit) {
+            bail!("Show 1-{MAX_HISTORY} messages");
+        }
+
+        let messages = ctx.database().recent_messages(limit).await?;
+        if messages.is_empty() {
+            ctx.reply("No messages yet");
+            return Ok(());
+        }
+        let lines: Vec<String> = messages.iter().map(message_line).collect();
+        ctx.reply(lines.join("\n"));
+        Ok(())
+    }
+}
+
+struct Mentions;
+
+#[async_trait]
+impl CommandHandler for Mentions {
+    fn name(&self) -> &str {
+        "mentions"
+    }
+
+    fn help(&self) -> &str {
+        "/mentions - list chat messages that mention you and you have not read"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, _args: &str) -> Result<()> {
+        let messages = ctx.database().unread_mentions(ctx.user).await?;
+        let Some(last) = messages.last() else {
+            ctx.reply("No unread mentions");
+            return Ok(());
+        };
+        ctx.database().mark_mentions_read(ctx.user, last.id).await?;
+        let lines: Vec<String> = messages.iter().map(message_line).collect();
+        ctx.reply(format!(
+            "{} unread mentions:\n{}",
+            messages.len(),
+            lines.join("\n")
+        ));
+        Ok(())
+    }
+}
+
+/// One chat message as `/history`, `/mentions` and `/thread` list it.
+fn message_line(m: &StoredMessage) -> String {
+    let reply = m
+        .reply_to
+        .map(|parent| format!(" ↪ #{parent}"))
+        .unwrap_or_default();
+    format!(
+        "#{} [{}] {}{}: {}",
+        m.id, m.timestamp, m.username, reply, m.content
+    )
+}
+
+/// Messages of a thread `/thread` shows at most.
+const MAX_THREAD: usize = 200;
+
+/// A non-empty thread, oldest first, with each reply indented below its parent.
+fn thread_reply(messages: &[StoredMessage]) -> String {
+    // Replies always come after their parent, so depths fill in as we go.
+    let mut depths = BTreeMap::new();
+    let mut lines = Vec::new();
+    for m in messages.iter().take(MAX_THREAD) {
+        let depth = m
+            .reply_to
+            .and_then(|parent| depths.get(&parent))
+            .map_or(0, |depth| depth + 1);
+        depths.insert(m.id, depth);
+        lines.push(format!("{}{}", "  ".repeat(depth), message_line(m)));
+    }
+    if messages.len() > MAX_THREAD {
+        lines.push(format!("… {} more", messages.len() - MAX_THREAD));
+    }
+    format!(
+        "Thread of #{} ({} messages):\n{}",
+        messages[0].id,
+        messages.len(),
+        lines.join("\n")
+    )
+}
+
+struct Thread;
+
+#[async_trait]
+impl CommandHandler for Thread {
+    fn name(&self) -> &str {
+        "thread"
+    }
+
+    fn help(&self) -> &str {
+        "/thread <id> - show the whole thread a chat message belongs to"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: &str) -> Result<()> {
+        let id: i64 = args
+            .trim_start_matches('#')
+            .parse()
+            .context("Expected a message id")?;
+        let messages = ctx.database().thread(id).await?;
+        if messages.is_empty() {
+            bail!("Unknown message #{id}");
+        }
+        ctx.reply(thread_reply(&messages));
+        Ok(())
+    }
+}
+
+/// Dice per roll and sides per die accepted by `/roll`.
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+
+struct Roll;
+
+#[async_trait]
+impl CommandHandler for Roll {
+    fn name(&self) -> &str {
+        "roll"
+    }
+
+    fn help(&self) -> &str {
+        "/roll [N]dM - roll N dice with M sides for everyone to see"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: &str) -> Result<()> {
+        let spec = if args.is_empty() { "1d6" } else { args };
+        let (count, sides) = spec.split_once('d').context("Expected dice like 2d6")?;
+        let count: u32 = if count.is_empty() {
+            1
+        } else {
+            count.parse().context("Invalid number of dice")?
+        };
+        let sides: u32 = sides.parse().context("Invalid number of sides")?;
+        if !(1..=MAX_DICE).contains(&count) || !(2..=MAX_SIDES).contains(&sides) {
+            bail!("Roll 1-{MAX_DICE} dice with 2-{MAX_SIDES} sides");
+        }
+
+        let rolls: Vec<u32> = {
+            let mut rng = rand::thread_rng();
+            (0..count).map(|_| rng.gen_range(1..=sides)).collect()
+        };
+        let total: u32 = rolls.iter().sum();
+        let shown: Vec<String> = rolls.iter().map(u32::to_string).collect();
+        ctx.broadcast(format!(
+            "{} rolled {count}d{sides}: {} = {total}",
+            ctx.user,
+            shown.join(" + ")
+        ));
+        Ok(())
+    }
+}
+
+/// Audit events `/audit` shows when no number is given.
+const DEFAULT_AUDIT: i64 = 20;
+
+struct Audit;
+
+#[async_trait]
+impl CommandHandler for Audit {
+    fn name(&self) -> &str {
+        "audit"
+    }
+
+    fn help(&self) -> &str {
+        "/audit [export] [N] [user=<name>] [action=<action>] - show or export the audit log (admins)"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: &str) -> Result<()> {
+        let (export, args) = match args.strip_prefix("export") {
+            Some(rest) if rest.is_empty() || rest.starts_with(' ') => (true, rest),
+            _ => (false, args),
+        };
+        let action = if export {
+            AuditAction::AuditExport
+        } else {
+            AuditAction::AuditQuery
+        };
+        let target = Some(args.trim()).filter(|args| !args.is_empty());
+        if !ctx.is_admin() {
+            ctx.audit(action, target, AuditOutcome::Denied).await;
+            bail!("Only admins can read the audit log");
+        }
+
+        let mut filter = AuditFilter::parse(args)?;
+        if !export {
+            filter.limit = filter.limit.or(Some(DEFAULT_AUDIT));
+        }
+        let events = ctx.database().audit_events(&filter).await?;
+        ctx.audit(action, target, AuditOutcome::Success).await;
+
+        if export {
+            let mut lines = String::new();
+            for event in &events {
+                lines.push_str(&serde_json::to_string(event)?);
+                lines.push('\n');
+            }
+            let name = format!("audit-{}.jsonl", Local::now().format("%Y%m%d-%H%M%S"));
+            ctx.reply(format!(
+                "Exported {} audit events as {}",
+                events.len(),
+                name
+            ));
+            ctx.send_file(name, lines.into_bytes());
+            return Ok(());
+        }
+        if events.is_empty() {
+            ctx.reply("No audit events");
+            return Ok(());
+        }
+        let lines: Vec<String> = events
+            .iter()
+            .map(|e| {
+                let mut line = format!(
+                    "#{} [{}] {} from {}: {} {}",
+                    e.id, e.timestamp, e.actor, e.peer, e.action, e.outcome
+                );
+                if let Some(target) = &e.target {
+                    line.push_str(&format!(" {target}"));
+                }
+                if let Some(detail) = &e.detail {
+                    line.push_str(&format!(" ({detail})"));
+                }
+                line
+            })
+            .collect();
+        ctx.reply(lines.join("\n"));
+        Ok(())
+    }
+}
+
+struct Export;
+
+#[async_trait]
+impl CommandHandler for Export {
+    fn name(&self) -> &str {
+        "export"
+    }
+
+    fn help(&self) -> &str {
+        "/export [jsonl|csv|html] [user=<name>] [since=<date>] [until=<date>] [bundle] - download the chat transcript (bundle: admins)"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: &str) -> Result<()> {
+        let target = Some(args.trim()).filter(|args| !args.is_empty());
+        let request = ExportRequest::parse(args)?;
+        // Chat messages reach everyone, but bundling reads stored files off the server's disk.
+        if request.bundle && !ctx.is_admin() {
+            ctx.audit(AuditAction::TranscriptExport, target, AuditOutcome::Denied)
+                .await;
+            bail!("Only admins can bundle stored files into a transcript");
+        }
+
+        let entries = ctx.database().transcript(&request.filter).await?;
+        let count = entries.len();
+        let root = ctx.config().storage.root.clone();
+        let protected = ctx.config().protected_files();
+        let max_bytes = ctx.config().limits.max_file_bytes;
+        let (format, bundle) = (request.format, request.bundle);
+        let content = tokio::task::spawn_blocking(move || {
+            let attachments = Attachments {
+                root: &root,
+                protected: &protected,
+                bundle,
+                max_bundled_bytes: max_bytes,
+            };
+            export::render(&entries, format, &attachments)
+        })
+        .await??;
+
+        if content.len() as u64 > max_bytes {
+            ctx.audit(AuditAction::TranscriptExport, target, AuditOutcome::Failure)
+                .await;
+            bail!(
+                "Transcript is {} bytes, the limit is {}; narrow it down or leave out bundle",
+                content.len(),
+                max_bytes
+            );
+        }
+        ctx.audit(AuditAction::TranscriptExport, target, AuditOutcome::Success)
+            .await;
+
+        let name = format!(
+            "transcript-{}.{}",
+            Local::now().format("%Y%m%d-%H%M%S"),
+            format.extension()
+        );
+        ctx.reply(format!("Exported {count} transcript entries as {name}"));
+        ctx.send_file(name, content);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i64, reply_to: Option<i64>) -> StoredMessage {
+        StoredMessage {
+            id,
+            username: "bob".to_string(),
+            content: format!("m{id}"),
+            timestamp: "2024-05-01 12:00:00".to_string(),
+            reply_to,
+        }
+    }
+
+    #[test]
+    fn thread_indents_replies_below_their_parent() {
+        let thread = [
+            message(1, None),
+            message(2, Some(1)),
+            message(4, Some(2)),
+            message(5, Some(1)),
+            message(7, Some(4)),
+        ];
+        let reply = thread_reply(&thread);
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines[0], "Thread of #1 (5 messages):");
+        let indents: Vec<(usize, &str)> = lines[1..]
+            .iter()
+            .map(|line| {
+                let trimmed = line.trim_start();
+                (
+                    line.len() - trimmed.len(),
+                    trimmed.split(' ').next().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            indents,
+            [(0, "#1"), (2, "#2"), (4, "#4"), (2, "#5"), (6, "#7")]
+        );
+        assert_eq!(lines[2], "  #2 [2024-05-01 12:00:00] bob ↪ #1: m2");
+    }
+
+    #[test]
+    fn thread_without_its_root_starts_at_the_oldest_left() {
+        // Retention removed #1, so #2 leads though it still names its parent.
+        let reply = thread_reply(&[message(2, Some(1)), message(3, Some(2))]);
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines[0], "Thread of #2 (2 messages):");
+        assert!(lines[1].starts_with("#2 "));
+        assert!(lines[2].starts_with("  #3 "));
+    }
+
+    #[test]
+    fn long_threads_are_cut_off() {
+        let thread: Vec<_> = (1..=MAX_THREAD as i64 + 5)
+            .map(|id| message(id, (id > 1).then_some(id - 1)))
+            .collect();
+        let reply = thread_reply(&thread);
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines.len(), MAX_THREAD + 2);
+        assert_eq!(lines[MAX_THREAD + 1], "… 5 more");
+        assert!(lines[0].contains(&format!("({} messages)", MAX_THREAD + 5)));
+    }
+}
@@ -0,0 +1,472 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+
+/// Server settings loaded from a TOML file. Every section and key is optional;
+/// `server.example.toml` documents the schema and the defaults.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listeners: ListenerConfig,
+    pub database: DatabaseConfig,
+    pub limits: LimitsConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub audit: AuditConfig,
+    pub retention: RetentionConfig,
+    pub logging: LoggingConfig,
+    /// File the settings were loaded from, which is never shared as a stored file.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    pub ws_address: Option<SocketAddr>,
+    pub metrics_address: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub broadcast_capacity: usize,
+    pub max_file_bytes: u64,
+    pub offline_queue_limit: i64,
+    pub offline_queue_ttl_secs: u64,
+    pub max_image_dimension: u32,
+    pub thumbnail_size: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub root: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub min_password_length: usize,
+    pub max_password_length: usize,
+    /// Extra banned passwords, one per line, on top of the built-in common ones.
+    pub banned_passwords_file: Option<PathBuf>,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    /// Password hashes computed at the same time; further logins queue.
+    pub max_concurrent_hashes: usize,
+    /// Users allowed to run admin commands such as `/audit`.
+    pub admins: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Days audit events are kept; 0 keeps them forever.
+    pub retention_days: u64,
+}
+
+/// How long chat history is kept. With neither limit set nothing is deleted.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Days chat messages and attachments are kept; 0 keeps them forever.
+    pub max_age_days: u64,
+    /// Most chat messages kept, newest first; 0 keeps any number.
+    pub max_messages: u64,
+    /// Seconds between pruning runs.
+    pub interval_secs: u64,
+    /// Rows deleted per statement, so writers never wait long for the database.
+    pub batch_size: u64,
+    /// Hours between VACUUMs, run after a prune; 0 never vacuums.
+    pub vacuum_interval_hours: u64,
+}
+
+impl RetentionConfig {
+    pub fn enabled(&self) -> bool {
+        self.max_age_days > 0 || self.max_messages > 0
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([127, 0, 0, 1], 11111)),
+            ws_address: None,
+            metrics_address: None,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: "sqlite:./db.sqlite".to_string(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            broadcast_capacity: 1024,
+            max_file_bytes: 64 * 1024 * 1024,
+            offline_queue_limit: 100,
+            offline_queue_ttl_secs: 7 * 24 * 60 * 60,
+            max_image_dimension: 8192,
+            thumbnail_size: 160,
+        }
+    }
+}
+
+/// Storage root used unless one is configured; the server creates it at startup.
+pub const DEFAULT_STORAGE_ROOT: &str = "server_db/files";
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from(DEFAULT_STORAGE_ROOT),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            min_password_length: 8,
+            max_password_length: 128,
+            banned_passwords_file: None,
+            // OWASP's recommended Argon2id settings.
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            max_concurrent_hashes: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            admins: Vec::new(),
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self { retention_days: 90 }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_age_days: 0,
+            max_messages: 0,
+            interval_secs: 60 * 60,
+            batch_size: 1000,
+            vacuum_interval_hours: 24,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the config file, or the defaults when no file is given.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config: Self = toml::from_str(&contents)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        config.source = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// Checks the settings that can only be verified as a whole, reporting every problem at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        let listeners = [
+            Some(self.listeners.address),
+            self.listeners.ws_address,
+            self.listeners.metrics_address,
+        ];
+        let listeners: Vec<SocketAddr> = listeners.into_iter().flatten().collect();
+        for (i, addr) in listeners.iter().enumerate() {
+            if listeners[..i].contains(addr) {
+                problems.push(format!("listeners: {} is configured more than once", addr));
+            }
+        }
+        if !crate::storage::URL_SCHEMES
+            .iter()
+            .any(|scheme| self.database.url.starts_with(scheme))
+        {
+            problems.push(format!(
+                "database.url must start with one of {} (got \"{}\")",
+                crate::storage::URL_SCHEMES.join(", "),
+                self.database.url
+            ));
+        }
+        if self.limits.broadcast_capacity == 0 {
+            problems.push("limits.broadcast_capacity must be greater than 0".to_string());
+        }
+        if self.limits.max_file_bytes == 0 {
+            problems.push("limits.max_file_bytes must be greater than 0".to_string());
+        }
+        if self.limits.offline_queue_limit < 1 {
+            problems.push("limits.offline_queue_limit must be at least 1".to_string());
+        }
+        if self.limits.offline_queue_ttl_secs == 0 {
+            problems.push("limits.offline_queue_ttl_secs must be greater than 0".to_string());
+        }
+        if self.limits.max_image_dimension == 0 {
+            problems.push("limits.max_image_dimension must be greater than 0".to_string());
+        }
+        if !(1..=self.limits.max_image_dimension).contains(&self.limits.thumbnail_size) {
+            problems.push(format!(
+                "limits.thumbnail_size must be between 1 and limits.max_image_dimension (got {})",
+                self.limits.thumbnail_size
+            ));
+        }
+        // The default root is created at startup; a configured one must exist.
+        let missing_default =
+            self.storage.root == Path::new(DEFAULT_STORAGE_ROOT) && !self.storage.root.exists();
+        if !missing_default && !self.storage.root.is_dir() {
+            problems.push(format!(
+                "storage.root {} is not a directory",
+                self.storage.root.display()
+            ));
+        }
+        if self.auth.min_password_length == 0 {
+            problems.push("auth.min_password_length must be at least 1".to_string());
+        }
+        if self.auth.max_password_length < self.auth.min_password_length {
+            problems.push(format!(
+                "auth.max_password_length must be at least auth.min_password_length (got {})",
+                self.auth.max_password_length
+            ));
+        }
+        if let Some(path) = &self.auth.banned_passwords_file {
+            if !path.is_file() {
+                problems.push(format!(
+                    "auth.banned_passwords_file {} is not a file",
+                    path.display()
+                ));
+            }
+        }
+        if let Err(e) = argon2::Params::new(
+            self.auth.argon2_memory_kib,
+            self.auth.argon2_iterations,
+            self.auth.argon2_parallelism,
+            None,
+        ) {
+            problems.push(format!(
+                "auth.argon2_memory_kib, argon2_iterations and argon2_parallelism are invalid: {}",
+                e
+            ));
+        }
+        if self.auth.max_concurrent_hashes == 0 {
+            problems.push("auth.max_concurrent_hashes must be at least 1".to_string());
+        }
+        if self.auth.admins.iter().any(|admin| admin.trim().is_empty()) {
+            problems.push("auth.admins must not contain empty usernames".to_string());
+        }
+        if self.retention.interval_secs == 0 {
+            problems.push("retention.interval_secs must be greater than 0".to_string());
+        }
+        if self.retention.batch_size == 0 {
+            problems.push("retention.batch_size must be greater than 0".to_string());
+        }
+        if self.logging.level.parse::<tracing::Level>().is_err() {
+            problems.push(format!(
+                "logging.level must be one of trace, debug, info, warn, error (got \"{}\")",
+                self.logging.level
+            ));
+        }
+
+        if !problems.is_empty() {
+            bail!(
+                "Invalid server configuration:\n  - {}",
+                problems.join("\n  - ")
+            );
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> tracing::Level {
+        self.logging.level.parse().unwrap_or(tracing::Level::INFO)
+    }
+
+    /// Resolves a requested file path against the storage root, refusing paths
+    /// that would leave it and the server's own files.
+    pub fn storage_path(&self, path: &str) -> Result<PathBuf> {
+        confined_path(&self.storage.root, path, &self.protected_files())
+    }
+
+    /// Files clients must never get, even if they sit under the storage root:
+    /// the config file and the SQLite database with its journal files.
+    pub fn protected_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self.source.iter().cloned().collect();
+        let database = self
+            .database
+            .url
+            .strip_prefix("sqlite:")
+            .map(|rest| rest.trim_start_matches("//"))
+            .and_then(|rest| rest.split('?').next())
+            .filter(|file| !file.is_empty() && *file != ":memory:");
+        if let Some(database) = database {
+            for suffix in ["", "-wal", "-shm", "-journal"] {
+                files.push(PathBuf::from(format!("{database}{suffix}")));
+            }
+        }
+        files
+    }
+}
+
+/// Resolves a relative `path` under `root`. Absolute paths and `..` are refused,
+/// and so is anything that resolves, through symlinks, outside `root` or to one
+/// of the `protected` files. The file must exist.
+pub fn confined_path(root: &Path, path: &str, protected: &[PathBuf]) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    if path.is_empty()
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("Path must stay inside the storage root");
+    }
+    let root = root
+        .canonicalize()
+        .context("Failed to resolve the storage root")?;
+    let resolved = root
+        .join(relative)
+        .canonicalize()
+        .context("Failed to open file")?;
+    if !resolved.starts_with(&root) {
+        bail!("Path must stay inside the storage root");
+    }
+    let is_protected = protected
+        .iter()
+        .any(|file| file.canonicalize().is_ok_and(|file| file == resolved));
+    if is_protected {
+        bail!("{path} is one of the server's own files and is not shared");
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh storage root holding `uploads/a.txt`, next to a file outside it.
+    fn storage_root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hwork15-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("root/uploads")).unwrap();
+        std::fs::write(dir.join("root/uploads/a.txt"), "shared").unwrap();
+        std::fs::write(dir.join("secret.txt"), "not shared").unwrap();
+        dir
+    }
+
+    #[test]
+    fn confined_path_resolves_relative_paths() {
+        let dir = storage_root("confined-ok");
+        let root = dir.join("root");
+        let expected = root.join("uploads/a.txt").canonicalize().unwrap();
+        assert_eq!(
+            confined_path(&root, "uploads/a.txt", &[]).unwrap(),
+            expected
+        );
+        assert_eq!(
+            confined_path(&root, "./uploads/./a.txt", &[]).unwrap(),
+            expected
+        );
+        assert!(confined_path(&root, "uploads/missing.txt", &[]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn confined_path_refuses_escapes() {
+        let dir = storage_root("confined-escape");
+        let root = dir.join("root");
+        for path in [
+            "",
+            "/etc/passwd",
+            "../secret.txt",
+            "uploads/../../secret.txt",
+            "a/..",
+        ] {
+            assert!(
+                confined_path(&root, path, &[]).is_err(),
+                "{path} was accepted"
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confined_path_refuses_symlinks_out_of_the_root() {
+        let dir = storage_root("confined-symlink");
+        let root = dir.join("root");
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(&dir, root.join("up")).unwrap();
+        assert!(confined_path(&root, "link.txt", &[]).is_err());
+        assert!(confined_path(&root, "up/secret.txt", &[]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn server_files_under_the_root_are_not_shared() {
+        let dir = storage_root("confined-protected");
+        let root = dir.join("root");
+        std::fs::write(root.join("db.sqlite"), "users").unwrap();
+        std::fs::write(root.join("db.sqlite-wal"), "users").unwrap();
+        std::fs::write(root.join("server.toml"), "").unwrap();
+
+        let mut config = ServerConfig::default();
+        config.storage.root = root.clone();
+        config.database.url = format!("sqlite:{}?mode=rwc", root.join("db.sqlite").display());
+        config.source = Some(root.join("./server.toml"));
+        for path in ["db.sqlite", "./db.sqlite-wal", "server.toml"] {
+            assert!(config.storage_path(path).is_err(), "{path} was accepted");
+        }
+        assert!(config.storage_path("uploads/a.txt").is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn protected_files_follow_the_database_url() {
+        let mut config = ServerConfig::default();
+        assert_eq!(
+            config.protected_files()[..2],
+            [
+                PathBuf::from("./db.sqlite"),
+                PathBuf::from("./db.sqlite-wal")
+            ]
+        );
+        config.database.url = "sqlite::memory:".to_string();
+        assert!(config.protected_files().is_empty());
+        config.database.url = "postgres://chat@localhost/chat".to_string();
+        assert!(config.protected_files().is_empty());
+    }
+}
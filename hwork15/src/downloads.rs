@@ -0,0 +1,404 @@
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use image::{load_from_memory, DynamicImage, ImageFormat};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::task;
+
+/// Longest file name kept from a server-provided name, in chars.
+const MAX_NAME_CHARS: usize = 100;
+
+/// Most files, and bytes, held in memory waiting for `.accept`/`.decline`.
+const MAX_PENDING_FILES: usize = 16;
+const MAX_PENDING_BYTES: usize = 256 * 1024 * 1024;
+
+/// Files waiting for `.accept`/`.decline`, by pending id.
+type Pending = Arc<Mutex<BTreeMap<u64, (String, Vec<u8>)>>>;
+
+/// Where and how received files and images are stored. Clones share the
+/// files waiting for confirmation.
+#[derive(Clone)]
+pub struct Downloads {
+    /// Root of everything saved; files go to `files/`, images to `images/`.
+    dir: PathBuf,
+    /// Convert received images to this format instead of keeping the original bytes.
+    image_format: Option<ImageFormat>,
+    /// Files larger than this wait for `.accept` unless their extension is allowlisted.
+    confirm_above: u64,
+    /// Lowercase extensions that are saved without asking.
+    auto_accept: Vec<String>,
+    /// Most bytes the download directory may hold.
+    quota: u64,
+    pending: Pending,
+    next_pending: Arc<AtomicU64>,
+}
+
+/// What happened to a received file.
+pub enum FileOutcome {
+    Saved(PathBuf),
+    /// Held until the user runs `.accept <id>` or `.decline <id>`.
+    Pending(u64),
+}
+
+/// Written next to each saved image as `<image name>.json`.
+#[derive(Serialize)]
+struct ImageSidecar<'a> {
+    sender: &'a str,
+    received_at: String,
+    original_name: &'a str,
+    original_format: &'a str,
+    saved_format: &'a str,
+}
+
+impl Downloads {
+    pub fn new(
+        dir: PathBuf,
+        image_format: Option<ImageFormat>,
+        confirm_above: u64,
+        auto_accept: &[String],
+        quota: u64,
+    ) -> Self {
+        Self {
+            dir,
+            image_format,
+            confirm_above,
+            auto_accept: auto_accept
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                .collect(),
+            quota,
+            pending: Pending::default(),
+            next_pending: Arc::default(),
+        }
+    }
+
+    /// Saves a broadcast file, or holds it for confirmation if it is large and
+    /// its extension is not allowlisted. Files that would take the held ones past
+    /// `MAX_PENDING_FILES` or `MAX_PENDING_BYTES` are dropped with an error.
+    pub async fn receive_file(&self, name: &str, content: Vec<u8>) -> Result<FileOutcome> {
+        if content.len() as u64 > self.confirm_above && !self.auto_accepted(name) {
+            let mut pending = self.lock_pending();
+            let held: usize = pending.values().map(|(_, content)| content.len()).sum();
+            if pending.len() >= MAX_PENDING_FILES || held + content.len() > MAX_PENDING_BYTES {
+                bail!(
+                    "Dropped it: {} files ({} bytes) already wait for .accept/.decline",
+                    pending.len(),
+                    held
+                );
+            }
+            let id = self.next_pending.fetch_add(1, Ordering::Relaxed) + 1;
+            pending.insert(id, (name.to_string(), content));
+            return Ok(FileOutcome::Pending(id));
+        }
+        self.save_file(name, &content).await.map(FileOutcome::Saved)
+    }
+
+    /// Handles `.accept <id>` and `.decline <id>`, returning what to tell the user.
+    /// Other lines give `None`.
+    pub async fn local_command(&self, line: &str) -> Option<Result<String>> {
+        let (command, id) = line.trim().split_once(' ')?;
+        let accept = match command {
+            ".accept" => true,
+            ".decline" => false,
+            _ => return None,
+        };
+        let Ok(id) = id.trim().trim_start_matches('#').parse::<u64>() else {
+            return Some(Err(anyhow::anyhow!("Expected {command} <id>")));
+        };
+        let Some((name, content)) = self.lock_pending().remove(&id) else {
+            return Some(Err(anyhow::anyhow!("No file is waiting under #{id}")));
+        };
+        if !accept {
+            return Some(Ok(format!("Declined {name}")));
+        }
+        Some(
+            self.save_file(&name, &content)
+                .await
+                .map(|path| format!("Saved file {} as {}", name, path.display())),
+        )
+    }
+
+    async fn save_file(&self, name: &str, content: &[u8]) -> Result<PathBuf> {
+        let file_name = PathBuf::from(sanitize_file_name(name, "file"));
+        self.check_quota(content.len() as u64).await?;
+        self.write_new("files", &file_name, content).await
+    }
+
+    /// Saves an image under its sanitised original name, numbered if taken,
+    /// with a JSON sidecar describing it. Returns the path of the image.
+    pub async fn save_image(&self, from: &str, name: &str, content: Vec<u8>) -> Result<PathBuf> {
+        let original = image::guess_format(&content).context("Not a supported image")?;
+        let (format, content) = match self.image_format {
+            Some(format) if format != original => {
+                let converted =
+                    task::spawn_blocking(move || convert_image(&content, format)).await??;
+                (format, converted)
+            }
+            _ => (original, content),
+        };
+
+        let mut file_name = PathBuf::from(sanitize_file_name(name, "image"));
+        if ImageFormat::from_path(&file_name).ok() != Some(format) {
+            file_name.set_extension(format.extensions_str()[0]);
+        }
+        let sidecar = ImageSidecar {
+            sender: from,
+            received_at: Local::now().to_rfc3339(),
+            original_name: name,
+            original_format: format_name(original),
+            saved_format: format_name(format),
+        };
+        let sidecar = serde_json::to_vec_pretty(&sidecar)?;
+        self.check_quota((content.len() + sidecar.len()) as u64)
+            .await?;
+
+        let path = self.write_new("images", &file_name, &content).await?;
+        let mut sidecar_path = path.clone().into_os_string();
+        sidecar_path.push(".json");
+        fs::write(&sidecar_path, sidecar)
+            .await
+            .context("Failed to write image sidecar.")?;
+        Ok(path)
+    }
+
+    /// Writes `content` to a new file in `subdir` of the download directory,
+    /// never replacing an existing one.
+    async fn write_new(&self, subdir: &str, file_name: &Path, content: &[u8]) -> Result<PathBuf> {
+        let dir = self.dir.join(subdir);
+        fs::create_dir_all(&dir)
+            .await
+            .context("Failed to create directory.")?;
+        let (path, mut file) = create_unique(&dir, file_name).await?;
+        file.write_all(content)
+            .await
+            .context("Failed to write to file.")?;
+        Ok(path)
+    }
+
+    async fn check_quota(&self, incoming: u64) -> Result<()> {
+        let dir = self.dir.clone();
+        let used = task::spawn_blocking(move || dir_size(&dir)).await?;
+        if used + incoming > self.quota {
+            bail!(
+                "Download quota exceeded: {} bytes used, {} more would pass the {} byte limit",
+                used,
+                incoming,
+                self.quota
+            );
+        }
+        Ok(())
+    }
+
+    fn auto_accepted(&self, name: &str) -> bool {
+        Path::new(name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| self.auto_accept.contains(&ext.to_lowercase()))
+    }
+
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, (String, Vec<u8>)>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Parses an `--image-format` value such as `png` or `jpg`.
+pub fn parse_image_format(value: &str) -> Result<ImageFormat, String> {
+    ImageFormat::from_extension(value)
+        .filter(ImageFormat::writing_enabled)
+        .ok_or_else(|| format!("unsupported image format: {value}"))
+}
+
+fn format_name(format: ImageFormat) -> &'static str {
+    format.extensions_str()[0]
+}
+
+fn convert_image(content: &[u8], format: ImageFormat) -> Result<Vec<u8>> {
+    let mut img = load_from_memory(content).context("Failed to load content from memory")?;
+    if format == ImageFormat::Jpeg {
+        // JPEG has no alpha channel.
+        img = DynamicImage::ImageRgb8(img.to_rgb8());
+    }
+    let mut converted = Cursor::new(Vec::new());
+    img.write_to(&mut converted, format)
+        .with_context(|| format!("Failed to convert image to {}", format_name(format)))?;
+    Ok(converted.into_inner())
+}
+
+/// Names Windows treats as devices whatever their extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Reduces a server-provided name to a single harmless file name, so nothing
+/// can be written outside the download directory. Hidden and device names
+/// lose their meaning: `.bashrc` becomes `bashrc`, `con.txt` becomes `_con.txt`.
+pub fn sanitize_file_name(name: &str, fallback: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let clean: String = base
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '.' | '-' | '_' | ' ') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_NAME_CHARS)
+        .collect();
+    let clean = clean.trim().trim_start_matches('.');
+    let stem = clean.split('.').next().unwrap_or_default().trim_end();
+    if clean.is_empty() {
+        fallback.to_string()
+    } else if RESERVED_NAMES.contains(&stem.to_uppercase().as_str()) {
+        format!("_{clean}")
+    } else {
+        clean.to_string()
+    }
+}
+
+/// Creates `dir/name`, or `dir/name (1)`, `dir/name (2)`... if it is taken.
+/// `create_new` also refuses to follow a symlink planted at the path.
+async fn create_unique(dir: &Path, name: &Path) -> Result<(PathBuf, fs::File)> {
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    for n in 0.. {
+        let path = match n {
+            0 => dir.join(name),
+            n => dir.join(format!("{stem} ({n}){extension}")),
+        };
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).context("Failed to create file."),
+        }
+    }
+    unreachable!("ran out of numbered file names")
+}
+
+/// Total size of the regular files under `dir`; a missing directory is empty.
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => dir_size(&entry.path()),
+            Ok(kind) if kind.is_file() => entry.metadata().map_or(0, |meta| meta.len()),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn downloads(name: &str) -> Downloads {
+        let dir = std::env::temp_dir().join(format!("hwork15-{name}-{}", std::process::id()));
+        Downloads::new(dir, None, 0, &[], u64::MAX)
+    }
+
+    #[tokio::test]
+    async fn pending_files_are_capped_by_count() {
+        let downloads = downloads("pending-count");
+        for _ in 0..MAX_PENDING_FILES {
+            let outcome = downloads.receive_file("a.bin", vec![0; 1]).await.unwrap();
+            assert!(matches!(outcome, FileOutcome::Pending(_)));
+        }
+        assert!(downloads.receive_file("a.bin", vec![0; 1]).await.is_err());
+
+        assert!(downloads.local_command(".decline 1").await.unwrap().is_ok());
+        let outcome = downloads.receive_file("a.bin", vec![0; 1]).await.unwrap();
+        assert!(matches!(outcome, FileOutcome::Pending(_)));
+    }
+
+    #[tokio::test]
+    async fn pending_files_are_capped_by_bytes() {
+        let downloads = downloads("pending-bytes");
+        let half = MAX_PENDING_BYTES / 2;
+        downloads
+            .receive_file("a.bin", vec![0; half])
+            .await
+            .unwrap();
+        downloads
+            .receive_file("b.bin", vec![0; half])
+            .await
+            .unwrap();
+        assert!(downloads.receive_file("c.bin", vec![0; 1]).await.is_err());
+        assert_eq!(downloads.lock_pending().len(), 2);
+    }
+
+    #[test]
+    fn sanitized_names_cannot_leave_the_directory() {
+        assert_eq!(sanitize_file_name("../../etc/passwd", "file"), "passwd");
+        assert_eq!(sanitize_file_name("..\\..\\boot.ini", "file"), "boot.ini");
+        assert_eq!(sanitize_file_name("/abs/path.txt", "file"), "path.txt");
+        assert_eq!(sanitize_file_name("..", "file"), "file");
+        assert_eq!(sanitize_file_name("dir/", "file"), "file");
+        assert_eq!(sanitize_file_name("", "image"), "image");
+        assert_eq!(sanitize_file_name("a\0b:c*d?.txt", "file"), "a_b_c_d_.txt");
+    }
+
+    #[test]
+    fn sanitized_names_are_not_hidden_or_devices() {
+        assert_eq!(sanitize_file_name(".bashrc", "file"), "bashrc");
+        assert_eq!(sanitize_file_name("...", "file"), "file");
+        assert_eq!(sanitize_file_name("CON", "file"), "_CON");
+        assert_eq!(sanitize_file_name("nul.txt", "file"), "_nul.txt");
+        assert_eq!(sanitize_file_name("com1.tar.gz", "file"), "_com1.tar.gz");
+        assert_eq!(sanitize_file_name("console.txt", "file"), "console.txt");
+    }
+
+    #[test]
+    fn sanitized_names_are_capped() {
+        let name = sanitize_file_name(&"x".repeat(500), "file");
+        assert_eq!(name.chars().count(), MAX_NAME_CHARS);
+    }
+
+    #[tokio::test]
+    async fn create_unique_numbers_taken_names() {
+        let dir = std::env::temp_dir().join(format!("hwork15-unique-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = Path::new("report.txt");
+
+        let (first, _) = create_unique(&dir, name).await.unwrap();
+        let (second, _) = create_unique(&dir, name).await.unwrap();
+        let (third, _) = create_unique(&dir, name).await.unwrap();
+        assert_eq!(first, dir.join("report.txt"));
+        assert_eq!(second, dir.join("report (1).txt"));
+        assert_eq!(third, dir.join("report (2).txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn create_unique_does_not_follow_symlinks() {
+        let dir = std::env::temp_dir().join(format!("hwork15-symlink-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("target");
+        std::os::unix::fs::symlink(&target, dir.join("planted.txt")).unwrap();
+
+        let (path, _) = create_unique(&dir, Path::new("planted.txt")).await.unwrap();
+        assert_eq!(path, dir.join("planted (1).txt"));
+        assert!(!target.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,469 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit};
+use chacha20poly1305::XChaCha20Poly1305;
+use hkdf::Hkdf;
+use hwork15::{MessageType, SealedMessage};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Domain separation for the message keys derived with HKDF.
+const KEY_INFO: &[u8] = b"hwork15 e2e direct message v1";
+
+/// Something the user should be told about.
+pub enum Notice {
+    Info(String),
+    Warning(String),
+}
+
+/// A sealed direct message ready to send, with what it says for the sender's own log.
+pub struct Outgoing {
+    pub to: String,
+    pub text: String,
+    pub message: MessageType,
+}
+
+/// End-to-end encryption state shared by the line client's tasks, with the
+/// channel the reading side uses to hand sealed messages to the writing side.
+pub struct E2eSession {
+    pub state: Mutex<E2e>,
+    pub outgoing: UnboundedSender<Outgoing>,
+}
+
+/// Identity key of the logged-in user, the keys pinned for contacts and
+/// direct messages waiting for their recipient's key.
+pub struct E2e {
+    secret: StaticSecret,
+    public: [u8; 32],
+    known_path: PathBuf,
+    /// Contact keys trusted so far, by username.
+    known: BTreeMap<String, [u8; 32]>,
+    /// Keys that differ from the pinned one, until the user runs `.trust`.
+    changed: HashMap<String, [u8; 32]>,
+    /// Outgoing texts waiting for the recipient's key.
+    pending: HashMap<String, Vec<String>>,
+}
+
+/// Default directory for identity and pinned keys.
+pub fn default_key_dir() -> PathBuf {
+    dirs::config_dir()
+        .map(|dir| dir.join("hwork15").join("keys"))
+        .unwrap_or_else(|| PathBuf::from("client_db/keys"))
+}
+
+/// Short, comparable form of a public key.
+pub fn fingerprint(key: &[u8; 32]) -> String {
+    let digest = Sha256::digest(key);
+    digest[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl E2e {
+    /// Loads the identity of `username` from `dir`, creating one on first use.
+    pub fn load(dir: &Path, username: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create key directory {}", dir.display()))?;
+        let name = key_file_stem(username);
+        let key_path = dir.join(format!("{name}.key"));
+        let known_path = dir.join(format!("{name}.known.json"));
+        migrate_key_files(dir, username, &name)?;
+
+        let secret = match std::fs::read(&key_path) {
+            Ok(bytes) => {
+                let bytes: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| anyhow!("Corrupt identity key {}", key_path.display()))?;
+                StaticSecret::from(bytes)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let secret = StaticSecret::random_from_rng(OsRng);
+                write_private(&key_path, &secret.to_bytes())?;
+                secret
+            }
+            Err(e) => return Err(e).context("Failed to read identity key"),
+        };
+        let known = match std::fs::read(&known_path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .with_context(|| format!("Corrupt key file {}", known_path.display()))?,
+            Err(_) => BTreeMap::new(),
+        };
+
+        Ok(Self {
+            public: PublicKey::from(&secret).to_bytes(),
+            secret,
+            known_path,
+            known,
+            changed: HashMap::new(),
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Message publishing this identity's public key.
+    pub fn publish(&self) -> MessageType {
+        MessageType::PublishKey(self.public)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public)
+    }
+
+    /// Holds `text` until `to`'s key arrives and returns the request for it.
+    pub fn queue_direct(&mut self, to: String, text: String) -> MessageType {
+        self.pending.entry(to.clone()).or_default().push(text);
+        MessageType::FetchKey(to)
+    }
+
+    /// Handles a fetched key: pins it on first sight, holds messages if it
+    /// changed, and otherwise seals what was waiting for it.
+    pub fn key_received(
+        &mut self,
+        user: &str,
+        key: Option<[u8; 32]>,
+    ) -> (Vec<Notice>, Vec<Outgoing>) {
+        let mut notices = Vec::new();
+        let Some(key) = key else {
+            if let Some(texts) = self.pending.remove(user) {
+                notices.push(Notice::Warning(format!(
+                    "{user} has no encryption key; {} message(s) not sent",
+                    texts.len()
+                )));
+            }
+            return (notices, Vec::new());
+        };
+
+        match self.known.get(user) {
+            Some(known) if *known == key => {}
+            Some(known) => {
+                notices.push(self.key_changed(user, *known, key));
+                return (notices, Vec::new());
+            }
+            None => notices.push(self.pin(user, key)),
+        }
+        let outgoing = self.seal_pending(user, &key, &mut notices);
+        (notices, outgoing)
+    }
+
+    /// Handles `.trust <user>` and `.fingerprint [user]`. Other lines give `None`.
+    pub fn local_command(&mut self, line: &str) -> Option<Result<(Vec<Notice>, Vec<Outgoing>)>> {
+        let mut words = line.split_whitespace();
+        match (words.next()?, words.next()) {
+            (".fingerprint", None) => Some(Ok((
+                vec![Notice::Info(format!("Your key: {}", self.fingerprint()))],
+                Vec::new(),
+            ))),
+            (".fingerprint", Some(user)) => Some(match self.known.get(user) {
+                Some(key) => Ok((
+                    vec![Notice::Info(format!("{user}: {}", fingerprint(key)))],
+                    Vec::new(),
+                )),
+                None => Err(anyhow!("No key pinned for {user} yet")),
+            }),
+            (".trust", Some(user)) => Some(self.trust(user)),
+            (".trust", None) => Some(Err(anyhow!("Expected .trust <user>"))),
+            _ => None,
+        }
+    }
+
+    /// Pins the changed key of `user` and sends what was held for them.
+    fn trust(&mut self, user: &str) -> Result<(Vec<Notice>, Vec<Outgoing>)> {
+        let Some(key) = self.changed.remove(user) else {
+            bail!("{user}'s key has not changed");
+        };
+        let mut notices = vec![self.pin(user, key)];
+        let outgoing = self.seal_pending(user, &key, &mut notices);
+        Ok((notices, outgoing))
+    }
+
+    /// Decrypts a direct message. The notice warns when the sender's key is new
+    /// or differs from the one pinned for them.
+    pub fn open(&mut self, from: &str, sealed: &SealedMessage) -> Result<(String, Option<Notice>)> {
+        let notice = match self.known.get(from) {
+            Some(known) if *known == sealed.sender_key => None,
+            Some(known) => Some(self.key_changed(from, *known, sealed.sender_key)),
+            None => Some(self.pin(from, sealed.sender_key)),
+        };
+        let cipher = self.cipher(&sealed.sender_key, &sealed.sender_key, &self.public)?;
+        let text = cipher
+            .decrypt(&sealed.nonce.into(), sealed.ciphertext.as_slice())
+            .map_err(|_| anyhow!("Failed to decrypt message from {from}"))?;
+        let text = String::from_utf8(text).context("Decrypted message is not UTF-8")?;
+        Ok((text, notice))
+    }
+
+    fn seal(&self, to_key: &[u8; 32], text: &str) -> Result<SealedMessage> {
+        let cipher = self.cipher(to_key, &self.public, to_key)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, text.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt message"))?;
+        Ok(SealedMessage {
+            sender_key: self.public,
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+
+    fn seal_pending(
+        &mut self,
+        user: &str,
+        key: &[u8; 32],
+        notices: &mut Vec<Notice>,
+    ) -> Vec<Outgoing> {
+        let texts = self.pending.remove(user).unwrap_or_default();
+        let mut outgoing = Vec::new();
+        for text in texts {
+            match self.seal(key, &text) {
+                Ok(sealed) => outgoing.push(Outgoing {
+                    to: user.to_string(),
+                    message: MessageType::SealedDirect(user.to_string(), sealed),
+                    text,
+                }),
+                Err(e) => notices.push(Notice::Warning(format!("{e:#}"))),
+            }
+        }
+        outgoing
+    }
+
+    /// Cipher for messages between `sender` and `recipient`, keyed by our
+    /// Diffie-Hellman with `their_key`.
+    fn cipher(
+        &self,
+        their_key: &[u8; 32],
+        sender: &[u8; 32],
+        recipient: &[u8; 32],
+    ) -> Result<XChaCha20Poly1305> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*their_key));
+        if !shared.was_contributory() {
+            bail!("Refusing a low-order public key");
+        }
+        let info = [KEY_INFO, sender, recipient].concat();
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(&info, &mut key)
+            .map_err(|_| anyhow!("Failed to derive message key"))?;
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+
+    fn pin(&mut self, user: &str, key: [u8; 32]) -> Notice {
+        self.known.insert(user.to_string(), key);
+        if let Err(e) = self.save_known() {
+            return Notice::Warning(format!("Failed to save pinned keys: {e:#}"));
+        }
+        Notice::Info(format!("Pinned {user}'s key {}", fingerprint(&key)))
+    }
+
+    fn key_changed(&mut self, user: &str, known: [u8; 32], key: [u8; 32]) -> Notice {
+        self.changed.insert(user.to_string(), key);
+        Notice::Warning(format!(
+            "{user}'s key changed from {} to {}! Verify it with them, then run .trust {user}",
+            fingerprint(&known),
+            fingerprint(&key)
+        ))
+    }
+
+    fn save_known(&self) -> Result<()> {
+        let json = serde_json::to_vec_pretty(&self.known)?;
+        std::fs::write(&self.known_path, json)
+            .with_context(|| format!("Failed to write {}", self.known_path.display()))
+    }
+}
+
+/// Hex of the username, so every username gets its own key files.
+fn key_file_stem(username: &str) -> String {
+    username.bytes().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Renames key files from before they were named by [`key_file_stem`]. Back
+/// then other characters became `_`, so only alphanumeric usernames can be
+/// told apart and moved.
+fn migrate_key_files(dir: &Path, username: &str, stem: &str) -> Result<()> {
+    if username.is_empty() || !username.chars().all(char::is_alphanumeric) {
+        return Ok(());
+    }
+    for suffix in ["key", "known.json"] {
+        let old = dir.join(format!("{username}.{suffix}"));
+        let new = dir.join(format!("{stem}.{suffix}"));
+        if old.exists() && !new.exists() {
+            std::fs::rename(&old, &new)
+                .with_context(|| format!("Failed to rename {}", old.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a file only the current user can read.
+fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(bytes)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh identity for `username`, kept in its own directory.
+    fn identity(test: &str, username: &str) -> E2e {
+        let dir = key_dir(test, username);
+        let _ = std::fs::remove_dir_all(&dir);
+        E2e::load(&dir, username).unwrap()
+    }
+
+    fn key_dir(test: &str, username: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "hwork15-e2e-{test}-{username}-{}",
+            std::process::id()
+        ))
+    }
+
+    /// Seals `text` from `from` to `to`, which `from` pins on first sight.
+    fn seal_to(from: &mut E2e, to: &E2e, to_name: &str, text: &str) -> SealedMessage {
+        from.queue_direct(to_name.to_string(), text.to_string());
+        let (_, mut outgoing) = from.key_received(to_name, Some(to.public));
+        let Some(Outgoing {
+            message: MessageType::SealedDirect(_, sealed),
+            ..
+        }) = outgoing.pop()
+        else {
+            panic!("nothing sealed for {to_name}");
+        };
+        sealed
+    }
+
+    #[test]
+    fn sealed_messages_open_for_the_recipient_only() {
+        let mut alice = identity("round-trip", "alice");
+        let mut bob = identity("round-trip", "bob");
+        let mut carol = identity("round-trip", "carol");
+
+        let sealed = seal_to(&mut alice, &bob, "bob", "meet at noon");
+        assert_eq!(sealed.sender_key, alice.public);
+        let (text, notice) = bob.open("alice", &sealed).unwrap();
+        assert_eq!(text, "meet at noon");
+        assert!(matches!(notice, Some(Notice::Info(_))));
+        assert!(carol.open("alice", &sealed).is_err());
+    }
+
+    #[test]
+    fn keys_are_pinned_on_first_sight() {
+        let mut alice = identity("pinning", "alice");
+        let bob = identity("pinning", "bob");
+
+        let (notices, outgoing) = alice.key_received("bob", Some(bob.public));
+        assert!(
+            matches!(notices.as_slice(), [Notice::Info(pinned)] if pinned.starts_with("Pinned"))
+        );
+        assert!(outgoing.is_empty());
+        let (notices, _) = alice.key_received("bob", Some(bob.public));
+        assert!(notices.is_empty());
+
+        // The pin outlives the session.
+        let alice = E2e::load(&key_dir("pinning", "alice"), "alice").unwrap();
+        assert_eq!(alice.known.get("bob"), Some(&bob.public));
+    }
+
+    #[test]
+    fn changed_key_holds_messages_until_trusted() {
+        let mut alice = identity("changed", "alice");
+        let bob = identity("changed", "bob");
+        let mut new_bob = identity("changed", "new-bob");
+        alice.key_received("bob", Some(bob.public));
+
+        alice.queue_direct("bob".to_string(), "still there?".to_string());
+        let (notices, outgoing) = alice.key_received("bob", Some(new_bob.public));
+        assert!(matches!(notices.as_slice(), [Notice::Warning(_)]));
+        assert!(outgoing.is_empty());
+        assert_eq!(alice.known.get("bob"), Some(&bob.public));
+
+        let (_, mut outgoing) = alice.local_command(".trust bob").unwrap().unwrap();
+        assert_eq!(alice.known.get("bob"), Some(&new_bob.public));
+        let Some(Outgoing {
+            message: MessageType::SealedDirect(_, sealed),
+            ..
+        }) = outgoing.pop()
+        else {
+            panic!("held message not sent after .trust");
+        };
+        let (text, _) = new_bob.open("alice", &sealed).unwrap();
+        assert_eq!(text, "still there?");
+        assert!(alice.local_command(".trust bob").unwrap().is_err());
+    }
+
+    #[test]
+    fn low_order_keys_are_refused() {
+        let mut alice = identity("low-order", "alice");
+        let mut bob = identity("low-order", "bob");
+
+        alice.queue_direct("mallory".to_string(), "secret".to_string());
+        let (notices, outgoing) = alice.key_received("mallory", Some([0; 32]));
+        assert!(outgoing.is_empty());
+        assert!(notices.iter().any(
+            |notice| matches!(notice, Notice::Warning(warning) if warning.contains("low-order"))
+        ));
+
+        let mut sealed = seal_to(&mut alice, &bob, "bob", "hi");
+        sealed.sender_key = [0; 32];
+        assert!(bob.open("mallory", &sealed).is_err());
+    }
+
+    #[test]
+    fn similar_usernames_get_their_own_keys() {
+        let dir = key_dir("names", "shared");
+        let _ = std::fs::remove_dir_all(&dir);
+        let dotted = E2e::load(&dir, "bob.smith").unwrap();
+        let underscored = E2e::load(&dir, "bob_smith").unwrap();
+        assert_ne!(dotted.public, underscored.public);
+        assert_eq!(E2e::load(&dir, "bob.smith").unwrap().public, dotted.public);
+    }
+
+    #[test]
+    fn old_key_files_are_moved_to_the_new_names() {
+        let dir = key_dir("migrate", "shared");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let secret = StaticSecret::random_from_rng(OsRng);
+        std::fs::write(dir.join("alice.key"), secret.to_bytes()).unwrap();
+        std::fs::write(dir.join("bob_smith.key"), secret.to_bytes()).unwrap();
+
+        let alice = E2e::load(&dir, "alice").unwrap();
+        assert_eq!(alice.public, PublicKey::from(&secret).to_bytes());
+        assert!(!dir.join("alice.key").exists());
+        // bob_smith.key may have been bob.smith's, so it is left alone.
+        let bob = E2e::load(&dir, "bob_smith").unwrap();
+        assert_ne!(bob.public, alice.public);
+    }
+
+    #[test]
+    fn tampered_messages_fail_to_decrypt() {
+        let mut alice = identity("tampered", "alice");
+        let mut bob = identity("tampered", "bob");
+
+        let mut sealed = seal_to(&mut alice, &bob, "bob", "pay 10");
+        sealed.ciphertext[0] ^= 1;
+        let Err(error) = bob.open("alice", &sealed) else {
+            panic!("tampered message opened");
+        };
+        assert!(error.to_string().contains("Failed to decrypt"), "{error}");
+
+        let mut sealed = seal_to(&mut alice, &bob, "bob", "pay 10");
+        sealed.nonce[0] ^= 1;
+        assert!(bob.open("alice", &sealed).is_err());
+    }
+}
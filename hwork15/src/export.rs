@@ -0,0 +1,542 @@
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{NaiveDate, NaiveDateTime};
+use serde_json::json;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::config::confined_path;
+use crate::db::{StoredAttachment, StoredMessage};
+
+/// File formats a transcript can be exported in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line.
+    JsonLines,
+    Csv,
+    /// A single page with its styles inline.
+    Html,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::JsonLines => "jsonl",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Html => "html",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" | "json" => Ok(ExportFormat::JsonLines),
+            "csv" => Ok(ExportFormat::Csv),
+            "html" => Ok(ExportFormat::Html),
+            other => bail!("Unknown format {other}, use jsonl, csv or html"),
+        }
+    }
+}
+
+/// Which messages go into a transcript. Bounds are UTC times as stored,
+/// `YYYY-MM-DD HH:MM:SS`; `until` is exclusive.
+#[derive(Debug, Default)]
+pub struct TranscriptFilter {
+    pub user: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+impl TranscriptFilter {
+    /// Sets the date range from `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS` bounds,
+    /// both inclusive; a date alone for `until` includes that whole day.
+    pub fn between(mut self, since: Option<&str>, until: Option<&str>) -> Result<Self> {
+        self.since = since.map(|since| parse_bound(since, false)).transpose()?;
+        self.until = until.map(|until| parse_bound(until, true)).transpose()?;
+        Ok(self)
+    }
+}
+
+fn parse_bound(value: &str, end: bool) -> Result<String> {
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+    if let Ok(time) = NaiveDateTime::parse_from_str(&value.replace('T', " "), FORMAT) {
+        let time = if end {
+            time + chrono::Duration::seconds(1)
+        } else {
+            time
+        };
+        return Ok(time.format(FORMAT).to_string());
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .with_context(|| format!("Invalid date {value}, use YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS"))?;
+    let date = if end {
+        date.succ_opt().context("Date out of range")?
+    } else {
+        date
+    };
+    Ok(date
+        .and_hms_opt(0, 0, 0)
+        .unwrap_or_default()
+        .format(FORMAT)
+        .to_string())
+}
+
+/// A line of a transcript.
+pub enum TranscriptEntry {
+    Message(StoredMessage),
+    Attachment(StoredAttachment),
+}
+
+impl TranscriptEntry {
+    pub fn timestamp(&self) -> &str {
+        match self {
+            TranscriptEntry::Message(message) => &message.timestamp,
+            TranscriptEntry::Attachment(attachment) => &attachment.timestamp,
+        }
+    }
+}
+
+/// How attachments appear in a transcript.
+pub struct Attachments<'a> {
+    /// Storage root that attachment paths are relative to.
+    pub root: &'a Path,
+    /// Server files never embedded, from `ServerConfig::protected_files`.
+    pub protected: &'a [PathBuf],
+    /// Embed attachment contents instead of only referencing their paths.
+    /// CSV transcripts always reference.
+    pub bundle: bool,
+    /// Larger attachments are referenced even when bundling.
+    pub max_bundled_bytes: u64,
+}
+
+impl Attachments<'_> {
+    /// Contents of an attachment to embed, if bundling and it can be read.
+    fn bundled(&self, attachment: &StoredAttachment) -> Option<Vec<u8>> {
+        if !self.bundle {
+            return None;
+        }
+        // Records made before paths were checked may point outside the root.
+        let path = confined_path(self.root, &attachment.path, self.protected).ok()?;
+        let size = std::fs::metadata(&path).ok()?.len();
+        if size > self.max_bundled_bytes {
+            return None;
+        }
+        std::fs::read(path).ok()
+    }
+}
+
+/// Renders a transcript. Reads bundled attachments from disk, so run it off the async workers.
+pub fn render(
+    entries: &[TranscriptEntry],
+    format: ExportFormat,
+    attachments: &Attachments,
+) -> Result<Vec<u8>> {
+    let text = match format {
+        ExportFormat::JsonLines => json_lines(entries, attachments)?,
+        ExportFormat::Csv => csv(entries),
+        ExportFormat::Html => html(entries, attachments),
+    };
+    Ok(text.into_bytes())
+}
+
+fn json_lines(entries: &[TranscriptEntry], attachments: &Attachments) -> Result<String> {
+    let mut out = String::new();
+    for entry in entries {
+        let line = match entry {
+            TranscriptEntry::Message(m) => {
+                let mut line = json!({
+                    "type": "message",
+                    "id": m.id,
+                    "timestamp": m.timestamp,
+                    "user": m.username,
+                    "text": m.content,
+                });
+                if let Some(parent) = m.reply_to {
+                    line["reply_to"] = parent.into();
+                }
+                line
+            }
+            TranscriptEntry::Attachment(a) => {
+                let mut line = json!({
+                    "type": "attachment",
+                    "id": a.id,
+                    "timestamp": a.timestamp,
+                    "user": a.username,
+                    "name": a.name,
+                    "path": a.path,
+                });
+                if let Some(content) = attachments.bundled(a) {
+                    line["content_base64"] = BASE64.encode(content).into();
+                }
+                line
+            }
+        };
+        out.push_str(&serde_json::to_string(&line)?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+fn csv(entries: &[TranscriptEntry]) -> String {
+    let mut out = String::from("type,id,timestamp,user,text,attachment,reply_to\r\n");
+    for entry in entries {
+        let (kind, id, timestamp, user, text, path, reply_to) = match entry {
+            TranscriptEntry::Message(m) => (
+                "message",
+                m.id,
+                &m.timestamp,
+                &m.username,
+                m.content.as_str(),
+                "",
+                m.reply_to,
+            ),
+            TranscriptEntry::Attachment(a) => (
+                "attachment",
+                a.id,
+                &a.timestamp,
+                &a.username,
+                a.name.as_str(),
+                a.path.as_str(),
+                None,
+            ),
+        };
+        let fields = [
+            kind.to_string(),
+            id.to_string(),
+            csv_field(timestamp),
+            csv_field(user),
+            csv_field(text),
+            csv_field(path),
+            reply_to
+                .map(|parent| parent.to_string())
+                .unwrap_or_default(),
+        ];
+        out.push_str(&fields.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Quotes a CSV field when it holds a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>Chat transcript</title>
+<style>
+body { font-family: system-ui, sans-serif; max-width: 50em; margin: 2em auto; color: #222; }
+.entry { margin: 0.3em 0; }
+.time { color: #888; font-size: 0.85em; margin-right: 0.5em; }
+.user { font-weight: bold; margin-right: 0.3em; }
+.reply { color: #888; margin-right: 0.3em; }
+.attachment img { display: block; max-width: 100%; max-height: 20em; margin-top: 0.3em; }
+</style>
+</head>
+<body>
+<h1>Chat transcript</h1>
+"#;
+
+fn html(entries: &[TranscriptEntry], attachments: &Attachments) -> String {
+    let mut out = String::from(HTML_HEAD);
+    if entries.is_empty() {
+        out.push_str("<p>No messages.</p>\n");
+    }
+    for entry in entries {
+        // Messages get an anchor so replies can link to their parent.
+        let anchor = match entry {
+            TranscriptEntry::Message(m) => format!(r#" id="m{}""#, m.id),
+            TranscriptEntry::Attachment(_) => String::new(),
+        };
+        let _ = write!(
+            out,
+            r#"<div class="entry"{}><span class="time">{}</span><span class="user">{}:</span>"#,
+            anchor,
+            escape(entry.timestamp()),
+            escape(match entry {
+                TranscriptEntry::Message(m) => &m.username,
+                TranscriptEntry::Attachment(a) => &a.username,
+            })
+        );
+        match entry {
+            TranscriptEntry::Message(m) => {
+                if let Some(parent) = m.reply_to {
+                    let _ = write!(out, r##"<a class="reply" href="#m{0}">↪ #{0}</a>"##, parent);
+                }
+                let _ = write!(out, r#"<span class="text">{}</span>"#, escape(&m.content));
+            }
+            TranscriptEntry::Attachment(a) => {
+                out.push_str(r#"<span class="attachment">"#);
+                match attachments.bundled(a) {
+                    Some(content) => {
+                        let mime = mime_type(&a.name);
+                        let data = format!("data:{};base64,{}", mime, BASE64.encode(content));
+                        if mime.starts_with("image/") {
+                            let _ = write!(
+                                out,
+                                r#"{}<img src="{}" alt="{}">"#,
+                                escape(&a.name),
+                                data,
+                                escape(&a.name)
+                            );
+                        } else {
+                            let _ = write!(
+                                out,
+                                r#"<a download="{0}" href="{1}">{0}</a>"#,
+                                escape(&a.name),
+                                data
+                            );
+                        }
+                    }
+                    None => {
+                        let _ = write!(
+                            out,
+                            r#"shared <a href="{}">{}</a>"#,
+                            escape(&a.path),
+                            escape(&a.name)
+                        );
+                    }
+                }
+                out.push_str("</span>");
+            }
+        }
+        out.push_str("</div>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn mime_type(name: &str) -> &'static str {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("bmp") => "image/bmp",
+        Some("txt") => "text/plain",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// What `/export` was asked for.
+pub struct ExportRequest {
+    pub format: ExportFormat,
+    pub filter: TranscriptFilter,
+    pub bundle: bool,
+}
+
+impl ExportRequest {
+    /// Parses `[jsonl|csv|html] [user=<name>] [since=<date>] [until=<date>] [bundle]`.
+    pub fn parse(args: &str) -> Result<Self> {
+        let mut format = ExportFormat::Html;
+        let mut user = None;
+        let (mut since, mut until) = (None, None);
+        let mut bundle = false;
+        for arg in args.split_whitespace() {
+            if let Some(name) = arg.strip_prefix("user=") {
+                user = Some(name.to_string());
+            } else if let Some(date) = arg.strip_prefix("since=") {
+                since = Some(date);
+            } else if let Some(date) = arg.strip_prefix("until=") {
+                until = Some(date);
+            } else if arg == "bundle" {
+                bundle = true;
+            } else {
+                format = arg.parse()?;
+            }
+        }
+        let filter = TranscriptFilter {
+            user,
+            ..Default::default()
+        }
+        .between(since, until)?;
+        Ok(Self {
+            format,
+            filter,
+            bundle,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRICKY: &str = "a, \"b\"\nc\r<d> & 'e'";
+
+    fn message(id: i64, username: &str, content: &str) -> TranscriptEntry {
+        TranscriptEntry::Message(StoredMessage {
+            id,
+            username: username.to_string(),
+            content: content.to_string(),
+            timestamp: "2026-01-02 03:04:05".to_string(),
+            reply_to: None,
+        })
+    }
+
+    fn attachment(name: &str, path: &str) -> TranscriptEntry {
+        TranscriptEntry::Attachment(StoredAttachment {
+            id: 1,
+            username: "bob".to_string(),
+            name: name.to_string(),
+            path: path.to_string(),
+            timestamp: "2026-01-02 03:04:05".to_string(),
+        })
+    }
+
+    fn render_text(entries: &[TranscriptEntry], format: ExportFormat, root: &Path) -> String {
+        let attachments = Attachments {
+            root,
+            protected: &[],
+            bundle: true,
+            max_bundled_bytes: 1024,
+        };
+        String::from_utf8(render(entries, format, &attachments).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("plain text"), "plain text");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+    }
+
+    #[test]
+    fn csv_keeps_tricky_text_in_one_field() {
+        let csv = render_text(
+            &[message(7, "al,ice", TRICKY)],
+            ExportFormat::Csv,
+            Path::new("."),
+        );
+        assert_eq!(
+            csv,
+            "type,id,timestamp,user,text,attachment,reply_to\r\n\
+             message,7,2026-01-02 03:04:05,\"al,ice\",\"a, \"\"b\"\"\nc\r<d> & 'e'\",,\r\n"
+        );
+    }
+
+    #[test]
+    fn html_escapes_markup_everywhere() {
+        assert_eq!(
+            escape(r#"<a href="x">&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;"
+        );
+        let html = render_text(
+            &[
+                message(1, "<b>bob</b>", TRICKY),
+                attachment("<img>.txt", "missing/\"x\".txt"),
+            ],
+            ExportFormat::Html,
+            Path::new("/nonexistent"),
+        );
+        assert!(html.contains("&lt;b&gt;bob&lt;/b&gt;:"));
+        assert!(html.contains("a, &quot;b&quot;\nc\r&lt;d&gt; &amp; &#39;e&#39;"));
+        assert!(html.contains(r#"shared <a href="missing/&quot;x&quot;.txt">&lt;img&gt;.txt</a>"#));
+        assert!(!html.contains("<b>") && !html.contains("<img>"));
+    }
+
+    #[test]
+    fn json_lines_round_trip_tricky_text() {
+        let jsonl = render_text(
+            &[message(1, "bob", TRICKY)],
+            ExportFormat::JsonLines,
+            Path::new("."),
+        );
+        assert_eq!(jsonl.lines().count(), 1);
+        let line: serde_json::Value = serde_json::from_str(jsonl.trim_end()).unwrap();
+        assert_eq!(line["text"], TRICKY);
+        assert!(line.get("reply_to").is_none());
+    }
+
+    #[test]
+    fn bundling_never_reads_outside_the_root() {
+        let root = std::env::temp_dir().join(format!("hwork15-export-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("uploads")).unwrap();
+        std::fs::write(root.join("uploads/a.txt"), "inside").unwrap();
+        std::fs::write(root.with_extension("secret"), "outside").unwrap();
+        let secret = format!(
+            "../{}",
+            root.with_extension("secret")
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+        let jsonl = render_text(
+            &[
+                attachment("a.txt", "uploads/a.txt"),
+                attachment("s.txt", &secret),
+            ],
+            ExportFormat::JsonLines,
+            &root,
+        );
+        let lines: Vec<serde_json::Value> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["content_base64"], BASE64.encode("inside"));
+        assert!(lines[1].get("content_base64").is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_file(root.with_extension("secret")).unwrap();
+    }
+
+    #[test]
+    fn bounds_include_the_whole_until_day() {
+        let filter = TranscriptFilter::default()
+            .between(Some("2026-01-02"), Some("2026-01-02"))
+            .unwrap();
+        assert_eq!(filter.since.as_deref(), Some("2026-01-02 00:00:00"));
+        assert_eq!(filter.until.as_deref(), Some("2026-01-03 00:00:00"));
+        let filter = TranscriptFilter::default()
+            .between(None, Some("2026-01-02T10:00:00"))
+            .unwrap();
+        assert_eq!(filter.until.as_deref(), Some("2026-01-02 10:00:01"));
+        assert!(TranscriptFilter::default()
+            .between(Some("yesterday"), None)
+            .is_err());
+    }
+
+    #[test]
+    fn export_requests_parse_in_any_order() {
+        let request = ExportRequest::parse("bundle user=bob csv").unwrap();
+        assert_eq!(request.format, ExportFormat::Csv);
+        assert_eq!(request.filter.user.as_deref(), Some("bob"));
+        assert!(request.bundle);
+        assert!(ExportRequest::parse("pdf").is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use std::io::Cursor;
+use std::path::Path;
+
+/// An image that decoded cleanly, with EXIF orientation already applied.
+pub struct DecodedImage {
+    pub image: DynamicImage,
+    pub format: ImageFormat,
+}
+
+/// Decodes `bytes`, rejecting anything that is not an image or exceeds `max_dimension`
+/// pixels on either side. The size is checked before the pixels are allocated.
+pub fn decode(bytes: &[u8], max_dimension: u32) -> Result<DecodedImage> {
+    let reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .context("Failed to read image")?;
+    let format = reader.format().context("Not a supported image format")?;
+    let mut decoder = reader.into_decoder().context("Not a valid image")?;
+
+    let (width, height) = decoder.dimensions();
+    if width > max_dimension || height > max_dimension {
+        bail!("Image is {width}x{height} pixels, the limit is {max_dimension}x{max_dimension}");
+    }
+    let orientation = decoder.orientation().context("Not a valid image")?;
+    let mut image = DynamicImage::from_decoder(decoder).context("Not a valid image")?;
+    image.apply_orientation(orientation);
+    Ok(DecodedImage { image, format })
+}
+
+/// Re-encodes the full image in its original format, which leaves EXIF and other
+/// metadata behind. Formats that cannot be written fall back to PNG; the returned
+/// name carries the extension of the format actually used.
+pub fn encode_full(decoded: &DecodedImage, name: &str) -> Result<(String, Vec<u8>)> {
+    let image = match decoded.format {
+        // JPEG has no alpha channel.
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(decoded.image.to_rgb8()),
+        _ => decoded.image.clone(),
+    };
+    if let Ok(bytes) = encode(&image, decoded.format) {
+        return Ok((name.to_string(), bytes));
+    }
+    let bytes = encode(&image, ImageFormat::Png)?;
+    let name = Path::new(name).with_extension("png");
+    Ok((name.to_string_lossy().into_owned(), bytes))
+}
+
+/// JPEG thumbnail fitting in a `size`x`size` box; photos compress far better than as PNG.
+pub fn thumbnail(decoded: &DecodedImage, size: u32) -> Result<Vec<u8>> {
+    let thumbnail = decoded.image.thumbnail(size, size).to_rgb8();
+    encode(&DynamicImage::ImageRgb8(thumbnail), ImageFormat::Jpeg)
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>> {
+    let mut bytes = Cursor::new(Vec::new());
+    image
+        .write_to(&mut bytes, format)
+        .with_context(|| format!("Failed to encode {:?}", format))?;
+    Ok(bytes.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        encode(
+            &DynamicImage::ImageRgba8(RgbaImage::new(width, height)),
+            ImageFormat::Png,
+        )
+        .unwrap()
+    }
+
+    /// A BMP header claiming `width`x`height` pixels, with no pixels after it.
+    fn bmp_header(width: i32, height: i32) -> Vec<u8> {
+        let mut bytes = b"BM".to_vec();
+        bytes.extend(54u32.to_le_bytes()); // file size
+        bytes.extend(0u32.to_le_bytes()); // reserved
+        bytes.extend(54u32.to_le_bytes()); // pixel data offset
+        bytes.extend(40u32.to_le_bytes()); // info header size
+        bytes.extend(width.to_le_bytes());
+        bytes.extend(height.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes()); // planes
+        bytes.extend(24u16.to_le_bytes()); // bits per pixel
+        bytes.extend([0; 24]); // no compression, sizes and palette left at 0
+        bytes
+    }
+
+    #[test]
+    fn non_images_are_refused() {
+        assert!(decode(b"", 1024).is_err());
+        assert!(decode(b"just some text, not a picture", 1024).is_err());
+        assert!(decode(b"\x89PNG\r\n\x1a\n", 1024).is_err());
+        let truncated = png(16, 16);
+        assert!(decode(&truncated[..truncated.len() / 2], 1024).is_err());
+    }
+
+    #[test]
+    fn oversized_images_are_refused() {
+        let error = decode(&png(300, 10), 256).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Image is 300x10 pixels, the limit is 256x256"
+        );
+        assert!(decode(&png(10, 300), 256).is_err());
+        assert!(decode(&png(256, 256), 256).is_ok());
+    }
+
+    #[test]
+    fn size_is_checked_before_the_pixels_are_read() {
+        let error = decode(&bmp_header(5000, 5000), 4096).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Image is 5000x5000 pixels, the limit is 4096x4096"
+        );
+        // Within the limit, the missing pixels are what fails.
+        assert!(decode(&bmp_header(4000, 4000), 4096).is_err());
+    }
+
+    #[test]
+    fn images_keep_their_format_and_thumbnails_fit() {
+        let decoded = decode(&png(400, 100), 1024).unwrap();
+        assert_eq!(decoded.format, ImageFormat::Png);
+
+        let (name, bytes) = encode_full(&decoded, "wide.png").unwrap();
+        assert_eq!(name, "wide.png");
+        assert_eq!(decode(&bytes, 1024).unwrap().image.width(), 400);
+
+        let thumbnail = decode(&thumbnail(&decoded, 64).unwrap(), 1024).unwrap();
+        assert_eq!(thumbnail.format, ImageFormat::Jpeg);
+        assert_eq!(
+            (thumbnail.image.width(), thumbnail.image.height()),
+            (64, 16)
+        );
+    }
+}
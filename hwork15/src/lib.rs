@@ -1,7 +1,9 @@
 use bincode::{deserialize, serialize};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use thiserror::Error;
 use tracing::{error, info};
 
@@ -10,23 +12,168 @@ use std::marker::Unpin;
 use tokio::io;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 
+pub mod audit;
+mod chat_client;
+pub mod commands;
+pub mod config;
+pub mod db;
+pub mod export;
+mod images;
+mod metrics;
+pub mod passwords;
+mod retention;
+mod server;
+mod server_utils;
+pub mod storage;
+mod ws;
+
+pub use chat_client::{ChatClient, ChatClientError, ChatEvent, ChatEvents};
+pub use server::{Server, ServerBuilder};
+
+/// Client-generated id the server uses to acknowledge a message.
+pub type MessageId = u64;
+
 /// Defines the message types client ---> server.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum MessageType {
     File(String),
     Image(String),
     Text(String),
+    /// Chat message answering the chat message stored under this id.
+    Reply(i64, String),
+    /// Direct message to a single user, queued while they are offline.
+    Direct(String, String),
+    /// Receipt for the chat message stored under this server id.
+    Receipt(i64, ReceiptStatus),
+    /// Publishes this user's X25519 identity public key.
+    PublishKey([u8; 32]),
+    /// Asks for the identity public key of a user.
+    FetchKey(String),
+    /// Direct message encrypted for its recipient; the server only relays it.
+    SealedDirect(String, SealedMessage),
+    /// Full-resolution version of the image announced under this id by a thumbnail.
+    FetchImage(u64),
+    /// A local file sent to the server under this name, shared with everyone online.
+    Upload(String, Vec<u8>),
+    /// Server command `/name args`, run by the handler registered under `name`.
+    Command(String, String),
     Quit,
 }
 
+/// A direct message sealed with a key derived from the sender's and the
+/// recipient's X25519 identity keys.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SealedMessage {
+    /// Identity public key of the sender.
+    pub sender_key: [u8; 32],
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
+
+/// The start of the chat message a reply answers, for clients to quote.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Quote {
+    pub id: i64,
+    pub from: String,
+    /// Cut to a few words, ending in `…` when shortened.
+    pub text: String,
+}
+
+/// A client message tagged with its client-generated id.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientMessage {
+    pub id: MessageId,
+    pub message: MessageType,
+}
+
+impl ClientMessage {
+    /// Tags a message with the next id of this process.
+    pub fn new(message: MessageType) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            message,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptStatus {
+    Delivered,
+    Read,
+}
+
+impl fmt::Display for ReceiptStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiptStatus::Delivered => write!(f, "delivered"),
+            ReceiptStatus::Read => write!(f, "read"),
+        }
+    }
+}
+
 /// Defines the response types server ---> client.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ResponseType {
     File(String, Vec<u8>),
-    Image(String, Vec<u8>),
+    /// A full-resolution image, as asked for with `FetchImage`.
+    Image {
+        from: String,
+        name: String,
+        content: Vec<u8>,
+    },
     Text(String),
     Quit(String),
     Error(String),
+    Users(Vec<String>),
+    /// A chat message, stored under `id` on the server. `mentions` lists the
+    /// registered users it addresses as `@name`; `reply_to` quotes the message
+    /// it answers.
+    Chat {
+        id: i64,
+        from: String,
+        text: String,
+        mentions: Vec<String>,
+        reply_to: Option<Quote>,
+    },
+    /// A direct message; `queued_at` is set when it waited in the offline queue.
+    Direct {
+        id: i64,
+        from: String,
+        text: String,
+        queued_at: Option<String>,
+    },
+    /// An end-to-end encrypted direct message.
+    SealedDirect {
+        id: i64,
+        from: String,
+        sealed: SealedMessage,
+        queued_at: Option<String>,
+    },
+    /// Identity public key of `user`, if they published one.
+    PublicKey {
+        user: String,
+        key: Option<[u8; 32]>,
+    },
+    /// A validated image; the full resolution is sent on `FetchImage(id)`.
+    Thumbnail {
+        id: u64,
+        from: String,
+        name: String,
+        width: u32,
+        height: u32,
+        thumbnail: Vec<u8>,
+    },
+    /// The message sent under this id was stored and broadcast.
+    Ack(MessageId),
+    /// The message sent under this id was rejected, with the reason.
+    Nack(MessageId, String),
+    /// `user` received or read the message sent under this id.
+    Receipt {
+        id: MessageId,
+        user: String,
+        status: ReceiptStatus,
+    },
 }
 
 /// Custom error type for message parsing.
@@ -36,6 +183,8 @@ pub enum SharedLibError {
     InvalidOption(String),
     #[error("Missing argument for option: {0}")]
     MissingArgument(String),
+    #[error("Invalid argument for option: {0}")]
+    InvalidArgument(String),
     #[error("I/O error: {0}")]
     IoError(#[from] io::Error),
     #[error("Serialization error: {0}")]
@@ -46,6 +195,8 @@ pub enum SharedLibError {
     WriteError(String),
     #[error("Read error: {0}")]
     ReadError(String),
+    #[error("Frame of {0} bytes is larger than the limit of {1}")]
+    FrameTooLarge(usize, usize),
 }
 
 impl FromStr for MessageType {
@@ -68,7 +219,50 @@ impl FromStr for MessageType {
                 .get(1)
                 .map(|&text| MessageType::Text(text.to_string()))
                 .ok_or_else(|| SharedLibError::MissingArgument(option.to_string())),
+            ".reply" => input
+                .get(1)
+                .and_then(|rest| rest.trim().split_once(' '))
+                .ok_or_else(|| SharedLibError::MissingArgument(option.to_string()))
+                .and_then(|(id, text)| {
+                    let id = id
+                        .trim_start_matches('#')
+                        .parse()
+                        .map_err(|_| SharedLibError::InvalidArgument(option.to_string()))?;
+                    Ok(MessageType::Reply(id, text.trim().to_string()))
+                }),
+            ".thread" => input
+                .get(1)
+                .map(|id| MessageType::Command("thread".to_string(), id.trim().to_string()))
+                .ok_or_else(|| SharedLibError::MissingArgument(option.to_string())),
+            ".dm" => input
+                .get(1)
+                .and_then(|rest| rest.trim().split_once(' '))
+                .map(|(user, text)| MessageType::Direct(user.to_string(), text.trim().to_string()))
+                .ok_or_else(|| SharedLibError::MissingArgument(option.to_string())),
+            ".fetch" => {
+                let id = input
+                    .get(1)
+                    .ok_or_else(|| SharedLibError::MissingArgument(option.to_string()))?;
+                id.trim()
+                    .trim_start_matches('#')
+                    .parse()
+                    .map(MessageType::FetchImage)
+                    .map_err(|_| SharedLibError::InvalidArgument(option.to_string()))
+            }
+            ".audit" => Ok(MessageType::Command(
+                "audit".to_string(),
+                input.get(1).map_or("", |args| args.trim()).to_string(),
+            )),
+            ".export" => Ok(MessageType::Command(
+                "export".to_string(),
+                input.get(1).map_or("", |args| args.trim()).to_string(),
+            )),
+            ".mentions" => Ok(MessageType::Command("mentions".to_string(), String::new())),
             ".quit" => Ok(MessageType::Quit),
+            _ if option.len() > 1 && option.starts_with('/') => Ok(MessageType::Command(
+                option[1..].to_string(),
+                input.get(1).map_or("", |args| args.trim()).to_string(),
+            )),
             _ => Err(SharedLibError::InvalidOption(option.to_string())),
         }
     }
@@ -103,9 +297,23 @@ pub async fn send_message<T: Serialize, U: AsyncWriteExt + Unpin>(
     Ok(())
 }
 
-/// Receives a serialized message from a TCP stream.
+/// Longest frame clients accept from the server by default: room for a file of
+/// twice the server's default `limits.max_file_bytes`.
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 128 * 1024 * 1024;
+
+/// Receives a serialized message from a TCP stream, refusing frames longer than
+/// [`DEFAULT_MAX_FRAME_BYTES`].
 pub async fn receive_message<T: DeserializeOwned, U: AsyncReadExt + Unpin>(
     stream: &mut U,
+) -> Result<T, SharedLibError> {
+    receive_message_within(stream, DEFAULT_MAX_FRAME_BYTES).await
+}
+
+/// Receives a serialized message from a TCP stream, refusing frames longer than
+/// `max_len` bytes before anything is allocated for them.
+pub async fn receive_message_within<T: DeserializeOwned, U: AsyncReadExt + Unpin>(
+    stream: &mut U,
+    max_len: usize,
 ) -> Result<T, SharedLibError> {
     let mut len_buf = [0u8; 4];
     stream
@@ -113,6 +321,9 @@ pub async fn receive_message<T: DeserializeOwned, U: AsyncReadExt + Unpin>(
         .await
         .map_err(|e| SharedLibError::ReadError(format!("Failed to read length: {:?}", e)))?;
     let exact_len = u32::from_be_bytes(len_buf) as usize;
+    if exact_len > max_len {
+        return Err(SharedLibError::FrameTooLarge(exact_len, max_len));
+    }
     let mut message_buf = vec![0u8; exact_len];
     stream
         .read_exact(&mut message_buf)
@@ -144,3 +355,35 @@ pub async fn parse_input(
 pub fn parse_socket_addr(val: &str) -> Result<SocketAddr, SharedLibError> {
     SocketAddr::from_str(val).map_err(SharedLibError::AddressParsingError)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_within_the_limit_round_trip() {
+        let message = ClientMessage::new(MessageType::Upload("a.txt".into(), vec![7; 100]));
+        let mut wire = Vec::new();
+        send_message(&mut wire, &message).await.unwrap();
+        let received: ClientMessage = receive_message_within(&mut wire.as_slice(), wire.len() - 4)
+            .await
+            .unwrap();
+        assert_eq!(received.id, message.id);
+        assert!(
+            matches!(received.message, MessageType::Upload(name, content)
+            if name == "a.txt" && content == vec![7; 100])
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_before_reading_them() {
+        // Only the length is sent: reading the body would fail with a read error.
+        let wire = u32::MAX.to_be_bytes();
+        let err = receive_message_within::<ClientMessage, _>(&mut wire.as_slice(), 1024)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, SharedLibError::FrameTooLarge(len, 1024) if len == u32::MAX as usize)
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use hwork15::commands::CommandRegistry;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::FileHistory;
+use rustyline::{Context, Editor, ExternalPrinter, Helper, Highlighter, Hinter, Validator};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tracing::error;
+
+/// Usernames of online users, as last announced by the server.
+pub type OnlineUsers = Arc<Mutex<Vec<String>>>;
+
+/// Client commands; server commands come from the built-in command registry.
+const COMMANDS: [&str; 17] = [
+    ".file",
+    ".image",
+    ".fetch",
+    ".accept",
+    ".decline",
+    ".text",
+    ".reply",
+    ".thread",
+    ".dm",
+    ".trust",
+    ".fingerprint",
+    ".audit",
+    ".export",
+    ".mentions",
+    ".quit",
+    "AUTH",
+    "REGISTER",
+];
+
+/// Completes command names, local paths after `.file`/`.image` and online usernames.
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct ChatHelper {
+    files: FilenameCompleter,
+    users: OnlineUsers,
+    /// Every built-in server command as `/name`.
+    server_commands: Vec<String>,
+}
+
+impl ChatHelper {
+    fn new(users: OnlineUsers) -> Self {
+        let server_commands = CommandRegistry::with_builtins()
+            .iter()
+            .map(|handler| format!("/{}", handler.name()))
+            .collect();
+        Self {
+            files: FilenameCompleter::new(),
+            users,
+            server_commands,
+        }
+    }
+}
+
+impl Completer for ChatHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let word_start = before.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &before[word_start..];
+
+        if word_start == 0 {
+            let names = COMMANDS
+                .iter()
+                .copied()
+                .chain(self.server_commands.iter().map(String::as_str));
+            let mut commands = candidates(names, word);
+            for command in &mut commands {
+                command.replacement.push(' ');
+            }
+            return Ok((0, commands));
+        }
+        if before.starts_with(".file ") || before.starts_with(".image ") {
+            return self.files.complete(line, pos, ctx);
+        }
+
+        let (start, prefix) = match word.strip_prefix('@') {
+            Some(name) => (word_start + 1, name),
+            None => (word_start, word),
+        };
+        let users = self.users.lock().map(|u| u.clone()).unwrap_or_default();
+        Ok((start, candidates(users.iter().map(String::as_str), prefix)))
+    }
+}
+
+fn candidates<'a>(options: impl Iterator<Item = &'a str>, prefix: &str) -> Vec<Pair> {
+    options
+        .filter(|option| option.starts_with(prefix))
+        .map(|option| Pair {
+            display: option.to_string(),
+            replacement: option.to_string(),
+        })
+        .collect()
+}
+
+/// Forwards log output to the editor so it is printed above the prompt.
+pub struct PrinterWriter(Box<dyn ExternalPrinter + Send>);
+
+impl io::Write for PrinterWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .print(String::from_utf8_lossy(buf).into_owned())
+            .map_err(io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Interactive line editor with persistent history, running on its own thread.
+pub struct LineEditor {
+    editor: Editor<ChatHelper, FileHistory>,
+    history_path: Option<PathBuf>,
+}
+
+impl LineEditor {
+    pub fn new(users: OnlineUsers) -> rustyline::Result<Self> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(ChatHelper::new(users)));
+
+        let history_path = dirs::config_dir().map(|dir| dir.join("hwork15").join("history.txt"));
+        if let Some(path) = &history_path {
+            // A missing history file just means a first run.
+            let _ = editor.load_history(path);
+        }
+        Ok(Self {
+            editor,
+            history_path,
+        })
+    }
+
+    /// Printer for log lines, if the terminal supports printing around the prompt.
+    pub fn printer(&mut self) -> Option<PrinterWriter> {
+        self.editor
+            .create_external_printer()
+            .ok()
+            .map(|printer| PrinterWriter(Box::new(printer)))
+    }
+
+    /// Reads lines until EOF and hands them out through the returned channel.
+    /// Ctrl+C and Ctrl+D are turned into `.quit`.
+    pub fn spawn(mut self) -> UnboundedReceiver<String> {
+        let (send, recv) = mpsc::unbounded_channel();
+        std::thread::spawn(move || loop {
+            let line = match self.editor.readline("> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                    let _ = send.send(".quit".to_string());
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to read line: {:?}", e);
+                    break;
+                }
+            };
+            if let Some(entry) = history_entry(&line) {
+                self.remember(&entry);
+            }
+            if send.send(line).is_err() {
+                break;
+            }
+        });
+        recv
+    }
+
+    fn remember(&mut self, line: &str) {
+        if self.editor.add_history_entry(line).is_err() {
+            return;
+        }
+        let Some(path) = &self.history_path else {
+            return;
+        };
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        if let Err(e) = self.editor.append_history(path) {
+            error!("Failed to save history: {:?}", e);
+        }
+    }
+}
+
+/// What of an input line is remembered: client and server commands, never
+/// AUTH/REGISTER lines with passwords, and direct messages only up to the recipient.
+fn history_entry(line: &str) -> Option<String> {
+    let trimmed = line.trim_start();
+    if !trimmed.starts_with(['.', '/']) {
+        return None;
+    }
+    let mut words = trimmed.split_whitespace();
+    if words.next() == Some(".dm") {
+        return Some(
+            words
+                .next()
+                .map_or(".dm".to_string(), |to| format!(".dm {to}")),
+        );
+    }
+    Some(line.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustyline::history::DefaultHistory;
+
+    fn complete(line: &str) -> Vec<String> {
+        let helper = ChatHelper::new(OnlineUsers::default());
+        let history = DefaultHistory::new();
+        let (_, pairs) = helper
+            .complete(line, line.len(), &Context::new(&history))
+            .unwrap();
+        pairs.into_iter().map(|pair| pair.replacement).collect()
+    }
+
+    #[test]
+    fn completes_every_builtin_server_command() {
+        for name in [
+            "help", "echo", "time", "roll", "history", "mentions", "thread", "audit", "export",
+        ] {
+            assert!(
+                complete("/").contains(&format!("/{name} ")),
+                "/{name} missing"
+            );
+        }
+        assert_eq!(complete("/th"), ["/thread "]);
+    }
+
+    #[test]
+    fn history_keeps_commands_but_not_passwords_or_direct_messages() {
+        assert_eq!(history_entry(".text hi").as_deref(), Some(".text hi"));
+        assert_eq!(history_entry("/roll 2d6").as_deref(), Some("/roll 2d6"));
+        assert_eq!(history_entry("AUTH alice secret-pass"), None);
+        assert_eq!(history_entry("REGISTER bob secret-pass"), None);
+        assert_eq!(
+            history_entry(".dm  bob meet at noon").as_deref(),
+            Some(".dm bob")
+        );
+        assert_eq!(history_entry(".dm").as_deref(), Some(".dm"));
+    }
+
+    #[test]
+    fn completes_client_commands() {
+        assert_eq!(complete(".re"), [".reply "]);
+        assert_eq!(complete("REG"), ["REGISTER "]);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::ResponseType;
+use anyhow::{Context, Result};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info};
+
+/// Metrics of one server, each in its own registry so servers running in the
+/// same process (tests, embedders) do not count each other's clients.
+pub struct Metrics {
+    registry: Registry,
+    pub connected_clients: IntGauge,
+    pub authenticated_users: IntGauge,
+    pub messages_total: IntCounterVec,
+    pub message_bytes_total: IntCounterVec,
+    pub auth_failures_total: IntCounterVec,
+    pub broadcast_lag_total: IntCounter,
+    pub db_query_seconds: HistogramVec,
+    pub password_hash_queued: IntGauge,
+    pub password_hash_running: IntGauge,
+    pub password_hash_wait_seconds: Histogram,
+    pub password_hash_seconds: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let connected_clients =
+            IntGauge::new("chat_connected_clients", "Currently connected clients").unwrap();
+        let authenticated_users =
+            IntGauge::new("chat_authenticated_users", "Currently authenticated users").unwrap();
+        let messages_total = IntCounterVec::new(
+            Opts::new("chat_messages_total", "Broadcast messages by type"),
+            &["type"],
+        )
+        .unwrap();
+        let message_bytes_total = IntCounterVec::new(
+            Opts::new(
+                "chat_message_bytes_total",
+                "Broadcast payload bytes by type",
+            ),
+            &["type"],
+        )
+        .unwrap();
+        let auth_failures_total = IntCounterVec::new(
+            Opts::new(
+                "chat_auth_failures_total",
+                "Failed authentications and registrations",
+            ),
+            &["action"],
+        )
+        .unwrap();
+        let broadcast_lag_total = IntCounter::new(
+            "chat_broadcast_lag_total",
+            "Times a client fell behind the broadcast channel",
+        )
+        .unwrap();
+        let db_query_seconds = HistogramVec::new(
+            HistogramOpts::new("chat_db_query_seconds", "Database query latency"),
+            &["query"],
+        )
+        .unwrap();
+
+        let password_hash_queued = IntGauge::new(
+            "chat_password_hash_queued",
+            "Password hashes waiting for a hashing slot",
+        )
+        .unwrap();
+        let password_hash_running = IntGauge::new(
+            "chat_password_hash_running",
+            "Password hashes running on the blocking pool",
+        )
+        .unwrap();
+        let password_hash_wait_seconds = Histogram::with_opts(HistogramOpts::new(
+            "chat_password_hash_wait_seconds",
+            "Time password hashes waited for a hashing slot",
+        ))
+        .unwrap();
+        let password_hash_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "chat_password_hash_seconds",
+                "Time spent hashing or verifying a password",
+            ),
+            &["operation"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(connected_clients.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(authenticated_users.clone()))
+            .unwrap();
+        registry.register(Box::new(messages_total.clone())).unwrap();
+        registry
+            .register(Box::new(message_bytes_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(auth_failures_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(broadcast_lag_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_query_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(password_hash_queued.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(password_hash_running.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(password_hash_wait_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(password_hash_seconds.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            connected_clients,
+            authenticated_users,
+            messages_total,
+            message_bytes_total,
+            auth_failures_total,
+            broadcast_lag_total,
+            db_query_seconds,
+            password_hash_queued,
+            password_hash_running,
+            password_hash_wait_seconds,
+            password_hash_seconds,
+        }
+    }
+
+    /// Counts a response about to be broadcast.
+    pub fn record_response(&self, response: &ResponseType) {
+        let (kind, bytes) = match response {
+            ResponseType::File(_, content) => ("file", content.len()),
+            ResponseType::Image { content, .. } => ("image", content.len()),
+            ResponseType::Text(text) => ("text", text.len()),
+            ResponseType::Quit(addr) => ("quit", addr.len()),
+            ResponseType::Error(msg) => ("error", msg.len()),
+            ResponseType::Users(users) => ("users", users.iter().map(String::len).sum()),
+            ResponseType::Chat { text, .. } => ("chat", text.len()),
+            ResponseType::Direct { text, .. } => ("direct", text.len()),
+            ResponseType::SealedDirect { sealed, .. } => ("sealed_direct", sealed.ciphertext.len()),
+            ResponseType::PublicKey { .. } => ("public_key", 0),
+            ResponseType::Thumbnail { thumbnail, .. } => ("thumbnail", thumbnail.len()),
+            ResponseType::Ack(_) => ("ack", 0),
+            ResponseType::Nack(_, reason) => ("nack", reason.len()),
+            ResponseType::Receipt { .. } => ("receipt", 0),
+        };
+        self.messages_total.with_label_values(&[kind]).inc();
+        self.message_bytes_total
+            .with_label_values(&[kind])
+            .inc_by(bytes as u64);
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .context("Failed to encode metrics")?;
+        String::from_utf8(buf).context("Metrics are not valid UTF-8")
+    }
+}
+
+/// Serves `GET /metrics` in the Prometheus text format.
+pub async fn run_metrics_listener(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .context("Failed to bind metrics socket")?;
+
+    info!("Metrics available on http://{}/metrics", addr);
+
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            error!("Failed to accept metrics connection");
+            continue;
+        };
+
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(stream, &metrics).await {
+                error!("Error serving metrics to {}: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+async fn serve_metrics(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut request = [0u8; 2048];
+    let n = stream
+        .read(&mut request)
+        .await
+        .context("Failed to read request")?;
+    let request = String::from_utf8_lossy(&request[..n]);
+
+    let response = if request.starts_with("GET /metrics ") {
+        let body = metrics.encode()?;
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .context("Failed to write response")?;
+    stream
+        .shutdown()
+        .await
+        .context("Failed to close connection")?;
+    Ok(())
+}
@@ -0,0 +1,353 @@
+use anyhow::{anyhow, Context, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use prometheus::IntGauge;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+use tokio::task;
+
+use crate::config::AuthConfig;
+use crate::metrics::Metrics;
+
+/// Passwords refused whatever the configured list says, compared case-insensitively.
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456",
+    "123456789",
+    "12345678",
+    "12345",
+    "1234567",
+    "1234567890",
+    "111111",
+    "000000",
+    "123123",
+    "654321",
+    "666666",
+    "121212",
+    "password",
+    "password1",
+    "password123",
+    "passw0rd",
+    "qwerty",
+    "qwerty123",
+    "qwertyuiop",
+    "1q2w3e4r",
+    "1qaz2wsx",
+    "asdfgh",
+    "asdfghjkl",
+    "zxcvbnm",
+    "abc123",
+    "abcd1234",
+    "iloveyou",
+    "admin",
+    "admin123",
+    "welcome",
+    "welcome1",
+    "letmein",
+    "monkey",
+    "dragon",
+    "football",
+    "baseball",
+    "sunshine",
+    "princess",
+    "master",
+    "shadow",
+    "superman",
+    "trustno1",
+    "starwars",
+    "login",
+    "secret",
+    "changeme",
+    "hello123",
+];
+
+/// Why a password was refused at registration.
+#[derive(Error, Debug)]
+pub enum PolicyViolation {
+    #[error("password must be at least {0} characters")]
+    TooShort(usize),
+    #[error("password must be at most {0} characters")]
+    TooLong(usize),
+    #[error("password is too common")]
+    Common,
+    #[error("password must not be the username")]
+    SameAsUsername,
+}
+
+/// Result of checking a password against a stored hash.
+pub struct Verified {
+    pub valid: bool,
+    /// The hash is bcrypt or uses other Argon2 parameters and should be replaced.
+    pub needs_rehash: bool,
+}
+
+/// Password policy and hashing: new hashes are Argon2id, bcrypt hashes from
+/// before the switch are still accepted. Hashing runs on the blocking pool,
+/// at most `auth.max_concurrent_hashes` at a time, so logins never stall the
+/// async workers that deliver chat messages.
+pub struct Passwords {
+    argon2: Argon2<'static>,
+    min_length: usize,
+    max_length: usize,
+    banned: HashSet<String>,
+    slots: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+}
+
+/// Raises a gauge for as long as it is alive, even if the future holding it is dropped.
+struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Passwords {
+    pub fn from_config(auth: &AuthConfig) -> Result<Self> {
+        let params = Params::new(
+            auth.argon2_memory_kib,
+            auth.argon2_iterations,
+            auth.argon2_parallelism,
+            None,
+        )
+        .map_err(|e| anyhow!("Invalid Argon2 parameters: {e}"))?;
+
+        let mut banned: HashSet<String> = COMMON_PASSWORDS.iter().map(|p| p.to_string()).collect();
+        if let Some(path) = &auth.banned_passwords_file {
+            let list = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            banned.extend(
+                list.lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_lowercase),
+            );
+        }
+
+        Ok(Self {
+            argon2: Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+            min_length: auth.min_password_length,
+            max_length: auth.max_password_length,
+            banned,
+            slots: Arc::new(Semaphore::new(auth.max_concurrent_hashes)),
+            metrics: Arc::default(),
+        })
+    }
+
+    /// The metrics hashing is counted in, with a fresh registry per `Passwords`;
+    /// the [`Database`](crate::db::Database) built on it counts its queries there too.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Checks a new password against the policy.
+    pub fn check(&self, username: &str, password: &str) -> Result<(), PolicyViolation> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(PolicyViolation::TooShort(self.min_length));
+        }
+        if length > self.max_length {
+            return Err(PolicyViolation::TooLong(self.max_length));
+        }
+        let lowered = password.to_lowercase();
+        if lowered == username.to_lowercase() {
+            return Err(PolicyViolation::SameAsUsername);
+        }
+        if self.banned.contains(&lowered) {
+            return Err(PolicyViolation::Common);
+        }
+        Ok(())
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String> {
+        let argon2 = self.argon2.clone();
+        let password = password.to_string();
+        self.run_blocking("hash", move || hash(&argon2, &password))
+            .await?
+    }
+
+    pub async fn verify(&self, password: &str, stored: &str) -> Result<Verified> {
+        let argon2 = self.argon2.clone();
+        let (password, stored) = (password.to_string(), stored.to_string());
+        self.run_blocking("verify", move || verify(&argon2, &password, &stored))
+            .await?
+    }
+
+    /// Runs `work` on the blocking pool once a hashing slot is free.
+    async fn run_blocking<T: Send + 'static>(
+        &self,
+        operation: &'static str,
+        work: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T> {
+        let queued_at = Instant::now();
+        let queued = GaugeGuard::new(&self.metrics.password_hash_queued);
+        let permit = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .context("Password hashing is shut down")?;
+        drop(queued);
+        self.metrics
+            .password_hash_wait_seconds
+            .observe(queued_at.elapsed().as_secs_f64());
+
+        // The permit moves into the task, so an abandoned login still holds its
+        // slot until the hash it started is done.
+        let metrics = Arc::clone(&self.metrics);
+        let result = task::spawn_blocking(move || {
+            let _permit = permit;
+            let _running = GaugeGuard::new(&metrics.password_hash_running);
+            let _timer = metrics
+                .password_hash_seconds
+                .with_label_values(&[operation])
+                .start_timer();
+            work()
+        })
+        .await
+        .context("Password hashing task failed")?;
+        Ok(result)
+    }
+}
+
+fn hash(argon2: &Argon2, password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = argon2
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Failed to hash password: {e}"))?;
+    Ok(hash.to_string())
+}
+
+fn verify(argon2: &Argon2, password: &str, stored: &str) -> Result<Verified> {
+    if stored.starts_with("$2") {
+        let valid = bcrypt::verify(password, stored)?;
+        return Ok(Verified {
+            valid,
+            needs_rehash: true,
+        });
+    }
+    let hash = PasswordHash::new(stored).map_err(|e| anyhow!("Unreadable password hash: {e}"))?;
+    let valid = argon2.verify_password(password.as_bytes(), &hash).is_ok();
+    let params = argon2.params();
+    let current = hash.algorithm == Algorithm::Argon2id.ident()
+        && Params::try_from(&hash).is_ok_and(|stored| {
+            (stored.m_cost(), stored.t_cost(), stored.p_cost())
+                == (params.m_cost(), params.t_cost(), params.p_cost())
+        });
+    Ok(Verified {
+        valid,
+        needs_rehash: !current,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap Argon2 settings; the policy is the default one.
+    fn auth_config() -> AuthConfig {
+        AuthConfig {
+            argon2_memory_kib: 1024,
+            argon2_iterations: 1,
+            ..AuthConfig::default()
+        }
+    }
+
+    fn passwords() -> Passwords {
+        Passwords::from_config(&auth_config()).unwrap()
+    }
+
+    #[test]
+    fn policy_refuses_weak_passwords() {
+        let passwords = passwords();
+        assert!(matches!(
+            passwords.check("alice", "short1"),
+            Err(PolicyViolation::TooShort(8))
+        ));
+        assert!(matches!(
+            passwords.check("alice", &"x".repeat(129)),
+            Err(PolicyViolation::TooLong(128))
+        ));
+        assert!(matches!(
+            passwords.check("alice", "PassWord123"),
+            Err(PolicyViolation::Common)
+        ));
+        assert!(matches!(
+            passwords.check("Alice-Smith", "alice-smith"),
+            Err(PolicyViolation::SameAsUsername)
+        ));
+        assert!(passwords.check("alice", "tall-green-lamp").is_ok());
+    }
+
+    #[test]
+    fn policy_counts_characters_not_bytes() {
+        let passwords = passwords();
+        assert!(passwords.check("alice", "ääääääää").is_ok());
+        assert!(passwords.check("alice", &"ä".repeat(128)).is_ok());
+    }
+
+    #[test]
+    fn policy_adds_the_banned_passwords_file() {
+        let path = std::env::temp_dir().join(format!("hwork15-banned-{}", std::process::id()));
+        std::fs::write(&path, "# house list\n\n  Company-Name-2024  \n").unwrap();
+        let passwords = Passwords::from_config(&AuthConfig {
+            banned_passwords_file: Some(path.clone()),
+            ..auth_config()
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            passwords.check("alice", "company-name-2024"),
+            Err(PolicyViolation::Common)
+        ));
+        assert!(passwords.check("alice", "# house list").is_ok());
+        assert!(matches!(
+            passwords.check("alice", "qwerty123"),
+            Err(PolicyViolation::Common)
+        ));
+    }
+
+    #[tokio::test]
+    async fn argon2_hashes_verify_and_stay_current() {
+        let passwords = passwords();
+        let hash = passwords.hash("tall-green-lamp").await.unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+
+        let verified = passwords.verify("tall-green-lamp", &hash).await.unwrap();
+        assert!(verified.valid && !verified.needs_rehash);
+        let verified = passwords.verify("tall-green-lam", &hash).await.unwrap();
+        assert!(!verified.valid);
+    }
+
+    #[tokio::test]
+    async fn old_hashes_need_a_rehash() {
+        let passwords = passwords();
+        let bcrypt_hash = bcrypt::hash("tall-green-lamp", 4).unwrap();
+        let verified = passwords
+            .verify("tall-green-lamp", &bcrypt_hash)
+            .await
+            .unwrap();
+        assert!(verified.valid && verified.needs_rehash);
+
+        let stronger = Passwords::from_config(&AuthConfig {
+            argon2_iterations: 2,
+            ..auth_config()
+        })
+        .unwrap();
+        let hash = passwords.hash("tall-green-lamp").await.unwrap();
+        let verified = stronger.verify("tall-green-lamp", &hash).await.unwrap();
+        assert!(verified.valid && verified.needs_rehash);
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::server_utils::{is_upload_name, ServerState, UPLOADS_DIR};
+
+/// What one pruning run deleted.
+#[derive(Debug, Default)]
+struct Pruned {
+    messages: u64,
+    attachments: u64,
+    files: u64,
+}
+
+/// Applies the `retention` policy every `retention.interval_secs`, vacuuming
+/// when `retention.vacuum_interval_hours` have passed and rows were deleted since.
+pub async fn prune_periodically(state: Arc<ServerState>) {
+    let retention = &state.config.retention;
+    let vacuum_every = Duration::from_secs(retention.vacuum_interval_hours * 60 * 60);
+    let mut interval = tokio::time::interval(Duration::from_secs(retention.interval_secs));
+    let mut last_vacuum = Instant::now();
+    let mut deleted_since_vacuum = false;
+    loop {
+        interval.tick().await;
+        let started = Instant::now();
+        match prune(&state).await {
+            Ok(pruned) if pruned.messages + pruned.attachments > 0 => {
+                deleted_since_vacuum = true;
+                info!(
+                    "Retention removed {} messages, {} attachment records and {} upload files in {:?}",
+                    pruned.messages,
+                    pruned.attachments,
+                    pruned.files,
+                    started.elapsed()
+                );
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to apply the retention policy: {:?}", e),
+        }
+
+        if retention.vacuum_interval_hours > 0
+            && deleted_since_vacuum
+            && last_vacuum.elapsed() >= vacuum_every
+        {
+            let started = Instant::now();
+            match state.database.vacuum().await {
+                Ok(()) => info!("Vacuumed the database in {:?}", started.elapsed()),
+                Err(e) => error!("Failed to vacuum the database: {:?}", e),
+            }
+            last_vacuum = Instant::now();
+            deleted_since_vacuum = false;
+        }
+    }
+}
+
+async fn prune(state: &ServerState) -> Result<Pruned> {
+    let retention = &state.config.retention;
+    let database = &state.database;
+    let batch = retention.batch_size;
+    let mut pruned = Pruned::default();
+
+    if retention.max_age_days > 0 {
+        let days = retention.max_age_days;
+        pruned.messages +=
+            in_batches(batch, || database.delete_messages_older_than(days, batch)).await?;
+    }
+    // Attachments shared before the newest message pruned by count go with it.
+    let mut before = None;
+    if retention.max_messages > 0 {
+        let keep = retention.max_messages;
+        loop {
+            let timestamps = database.delete_messages_beyond(keep, batch).await?;
+            pruned.messages += timestamps.len() as u64;
+            let done = (timestamps.len() as u64) < batch;
+            before = before.max(timestamps.into_iter().max());
+            if done {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    // Attachments go with the history around them; uploaded files go once no
+    // record points at them any more. Files shared from the storage root belong
+    // to the server and are left alone.
+    let mut paths = BTreeSet::new();
+    loop {
+        let deleted = database
+            .delete_expired_attachments(retention.max_age_days, before.as_deref(), batch)
+            .await?;
+        pruned.attachments += deleted.len() as u64;
+        let done = (deleted.len() as u64) < batch;
+        paths.extend(deleted);
+        if done {
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+    for path in paths {
+        if database.attachment_referenced(&path).await? {
+            continue;
+        }
+        let Some(file) = upload_file(&state.config.storage.root, &path).await else {
+            continue;
+        };
+        match tokio::fs::remove_file(file).await {
+            Ok(()) => pruned.files += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to delete expired upload {}: {}", path, e),
+        }
+    }
+    Ok(pruned)
+}
+
+/// The file behind an attachment `path` the server stored itself, `uploads/<name>`,
+/// as long as it really is inside `<root>/uploads`.
+async fn upload_file(root: &Path, path: &str) -> Option<PathBuf> {
+    let name = path.strip_prefix(&format!("{UPLOADS_DIR}/"))?;
+    if !is_upload_name(name) {
+        return None;
+    }
+    let uploads = tokio::fs::canonicalize(root.join(UPLOADS_DIR)).await.ok()?;
+    let file = tokio::fs::canonicalize(uploads.join(name)).await.ok()?;
+    if file.parent() != Some(uploads.as_path()) {
+        warn!(
+            "Not deleting {}: it resolves outside the uploads directory",
+            path
+        );
+        return None;
+    }
+    Some(file)
+}
+
+/// Runs `delete` until it removes fewer than `batch` rows, letting other
+/// tasks use the database in between, and returns the total removed.
+async fn in_batches<F, Fut>(batch: u64, mut delete: F) -> Result<u64>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<u64>>,
+{
+    let mut total = 0;
+    loop {
+        let deleted = delete().await?;
+        total += deleted;
+        if deleted < batch {
+            return Ok(total);
+        }
+        tokio::task::yield_now().await;
+    }
+}
@@ -0,0 +1,377 @@
+use anyhow::Context;
+use clap::{Args, Subcommand};
+use futures_util::StreamExt;
+use hwork15::{ChatClient, ChatClientError, ChatEvent, ChatEvents, MessageId, MessageType};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use thiserror::Error;
+
+/// One-shot jobs for scripts and CI; they log in, do one thing and exit.
+#[derive(Subcommand)]
+pub enum Command {
+    /// Send a chat message and wait until the server has stored it.
+    Send {
+        #[arg(long)]
+        text: String,
+        /// Id of the chat message this one answers.
+        #[arg(long)]
+        reply_to: Option<i64>,
+    },
+    /// Upload a local file; the server stores it and shares it with everyone online.
+    Upload { file: PathBuf },
+    /// Print the latest chat messages, then keep printing new ones with --follow.
+    Tail {
+        /// Latest messages to print first.
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: u32,
+        /// Keep printing messages until the server closes or Ctrl+C.
+        #[arg(short, long)]
+        follow: bool,
+    },
+}
+
+/// How subcommands log in and report results.
+#[derive(Args)]
+pub struct ScriptArgs {
+    #[arg(long, global = true, env = "HWORK_USER")]
+    user: Option<String>,
+    #[arg(long, global = true, env = "HWORK_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+    /// File holding `username:password`; used instead of HWORK_USER/HWORK_PASSWORD.
+    #[arg(long, global = true, env = "HWORK_TOKEN_FILE")]
+    token_file: Option<PathBuf>,
+    /// Print one JSON object per line instead of plain text.
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+/// Failures of a subcommand, each with its own exit status.
+#[derive(Error, Debug)]
+enum ScriptError {
+    #[error("No credentials: set HWORK_USER and HWORK_PASSWORD or pass --token-file")]
+    MissingCredentials,
+    #[error("Failed to connect to {0}: {1}")]
+    Connect(SocketAddr, std::io::Error),
+    #[error("Authentication failed: {0}")]
+    Auth(String),
+    #[error("Rejected by server: {0}")]
+    Rejected(String),
+    #[error("{0:#}")]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<ChatClientError> for ScriptError {
+    fn from(e: ChatClientError) -> Self {
+        match e {
+            ChatClientError::Connect(addr, e) => ScriptError::Connect(addr, e),
+            ChatClientError::Login(reason) => ScriptError::Auth(reason),
+            other => ScriptError::Other(other.into()),
+        }
+    }
+}
+
+impl ScriptError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            ScriptError::Other(_) => 1,
+            ScriptError::MissingCredentials => 2,
+            ScriptError::Auth(_) => 3,
+            ScriptError::Connect(..) => 4,
+            ScriptError::Rejected(_) => 5,
+        }
+    }
+}
+
+/// A line of output, printed as text or as JSON.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Sent {
+        id: MessageId,
+    },
+    Uploaded {
+        id: MessageId,
+        name: &'a str,
+        bytes: usize,
+    },
+    History {
+        line: &'a str,
+    },
+    Chat {
+        id: i64,
+        from: &'a str,
+        text: &'a str,
+        #[serde(skip_serializing_if = "<[String]>::is_empty")]
+        mentions: &'a [String],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reply_to: Option<i64>,
+    },
+    Direct {
+        id: i64,
+        from: &'a str,
+        text: &'a str,
+        queued_at: Option<&'a str>,
+    },
+    Notice {
+        text: &'a str,
+    },
+    File {
+        name: &'a str,
+        bytes: usize,
+    },
+    Image {
+        id: u64,
+        from: &'a str,
+        name: &'a str,
+        width: u32,
+        height: u32,
+    },
+    Error {
+        code: u8,
+        message: String,
+    },
+}
+
+impl<'a> Event<'a> {
+    /// The event shown for an incoming message, if it is worth showing.
+    fn from_chat(event: &'a ChatEvent) -> Option<Self> {
+        let event = match event {
+            ChatEvent::Chat {
+                id,
+                from,
+                text,
+                mentions,
+                reply_to,
+            } => Event::Chat {
+                id: *id,
+                from,
+                text,
+                mentions,
+                reply_to: reply_to.as_ref().map(|quote| quote.id),
+            },
+            ChatEvent::Direct {
+                id,
+                from,
+                text,
+                queued_at,
+            } => Event::Direct {
+                id: *id,
+                from,
+                text,
+                queued_at: queued_at.as_deref(),
+            },
+            ChatEvent::Notice(text) | ChatEvent::Error(text) => Event::Notice { text },
+            ChatEvent::File { name, content } | ChatEvent::Image { name, content, .. } => {
+                Event::File {
+                    name,
+                    bytes: content.len(),
+                }
+            }
+            ChatEvent::Thumbnail {
+                id,
+                from,
+                name,
+                width,
+                height,
+                ..
+            } => Event::Image {
+                id: *id,
+                from,
+                name,
+                width: *width,
+                height: *height,
+            },
+            _ => return None,
+        };
+        Some(event)
+    }
+
+    fn print(&self, json: bool) {
+        if json {
+            if let Ok(line) = serde_json::to_string(self) {
+                println!("{line}");
+            }
+            return;
+        }
+        match self {
+            Event::Sent { .. } => println!("Message stored"),
+            Event::Uploaded { name, bytes, .. } => println!("Uploaded {name} ({bytes} bytes)"),
+            Event::History { line } | Event::Notice { text: line } => println!("{line}"),
+            Event::Chat {
+                id,
+                from,
+                text,
+                reply_to: Some(parent),
+                ..
+            } => println!("#{id} {from} ↪ #{parent}: {text}"),
+            Event::Chat { id, from, text, .. } => println!("#{id} {from}: {text}"),
+            Event::Direct {
+                id,
+                from,
+                text,
+                queued_at,
+            } => match queued_at {
+                Some(at) => println!("#{id} {from} → me (sent {at}): {text}"),
+                None => println!("#{id} {from} → me: {text}"),
+            },
+            Event::File { name, bytes } => println!("file: {name} ({bytes} bytes)"),
+            Event::Image {
+                id,
+                from,
+                name,
+                width,
+                height,
+            } => println!("#{id} {from} shared image {name} ({width}x{height})"),
+            Event::Error { message, .. } => eprintln!("Error: {message}"),
+        }
+    }
+}
+
+/// Runs a subcommand against the server at `addr` and maps the outcome to an exit status.
+pub async fn run(
+    addr: SocketAddr,
+    max_frame_bytes: usize,
+    args: ScriptArgs,
+    command: Command,
+) -> ExitCode {
+    match execute(addr, max_frame_bytes, &args, command).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            let code = e.exit_code();
+            Event::Error {
+                code,
+                message: e.to_string(),
+            }
+            .print(args.json);
+            ExitCode::from(code)
+        }
+    }
+}
+
+async fn execute(
+    addr: SocketAddr,
+    max_frame_bytes: usize,
+    args: &ScriptArgs,
+    command: Command,
+) -> Result<(), ScriptError> {
+    let (user, password) = credentials(args)?;
+    let (client, mut events) = ChatClient::connect_within(addr, max_frame_bytes).await?;
+    client.login(&user, &password).await?;
+
+    match command {
+        Command::Send { text, reply_to } => {
+            let message = match reply_to {
+                Some(parent) => MessageType::Reply(parent, text),
+                None => MessageType::Text(text),
+            };
+            let id = request(&client, &mut events, message, |_| {}).await?;
+            Event::Sent { id }.print(args.json);
+        }
+        Command::Upload { file } => {
+            let name = file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .context("Invalid file path")?
+                .to_string();
+            let content = tokio::fs::read(&file)
+                .await
+                .with_context(|| format!("Failed to read {}", file.display()))?;
+            let bytes = content.len();
+            let upload = MessageType::Upload(name.clone(), content);
+            let id = request(&client, &mut events, upload, |_| {}).await?;
+            Event::Uploaded {
+                id,
+                name: &name,
+                bytes,
+            }
+            .print(args.json);
+        }
+        Command::Tail { lines, follow } => {
+            let history = MessageType::Command("history".to_string(), lines.to_string());
+            // The history arrives as the command's text reply, before its ack.
+            request(&client, &mut events, history, |event| match event {
+                ChatEvent::Notice(text) => {
+                    for line in text.lines() {
+                        Event::History { line }.print(args.json);
+                    }
+                }
+                other => {
+                    if let Some(event) = Event::from_chat(other) {
+                        event.print(args.json);
+                    }
+                }
+            })
+            .await?;
+            if follow {
+                follow_chat(&mut events, args.json).await;
+            }
+        }
+    }
+
+    let _ = client.quit().await;
+    Ok(())
+}
+
+/// Credentials from the token file if given, otherwise from the environment.
+fn credentials(args: &ScriptArgs) -> Result<(String, String), ScriptError> {
+    if let Some(path) = &args.token_file {
+        return read_token_file(path);
+    }
+    match (&args.user, &args.password) {
+        (Some(user), Some(password)) => Ok((user.clone(), password.clone())),
+        _ => Err(ScriptError::MissingCredentials),
+    }
+}
+
+fn read_token_file(path: &Path) -> Result<(String, String), ScriptError> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read token file {}", path.display()))?;
+    let (user, password) = token
+        .trim()
+        .split_once(':')
+        .filter(|(user, password)| !user.is_empty() && !password.is_empty())
+        .ok_or(ScriptError::MissingCredentials)?;
+    Ok((user.to_string(), password.to_string()))
+}
+
+/// Sends `message` and waits for its ack, passing everything received meanwhile to `seen`.
+async fn request(
+    client: &ChatClient,
+    events: &mut ChatEvents,
+    message: MessageType,
+    mut seen: impl FnMut(&ChatEvent),
+) -> Result<MessageId, ScriptError> {
+    let sent = client.send(message).await?;
+    loop {
+        let event = events
+            .next()
+            .await
+            .context("Connection closed before the server answered")?;
+        match event {
+            ChatEvent::Acked(id) if id == sent => return Ok(id),
+            ChatEvent::Rejected { id, reason } if id == sent => {
+                return Err(ScriptError::Rejected(reason))
+            }
+            other => seen(&other),
+        }
+    }
+}
+
+/// Prints incoming messages until the server goes away.
+async fn follow_chat(events: &mut ChatEvents, json: bool) {
+    loop {
+        let event = tokio::select! {
+            event = events.next() => match event {
+                Some(event) => event,
+                // The server closing the connection ends the tail normally.
+                None => return,
+            },
+            _ = tokio::signal::ctrl_c() => return,
+        };
+        if let Some(event) = Event::from_chat(&event) {
+            event.print(json);
+        }
+    }
+}
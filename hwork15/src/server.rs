@@ -0,0 +1,203 @@
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tracing::{error, info};
+
+use crate::audit;
+use crate::commands::{CommandHandler, CommandRegistry};
+use crate::config::{ServerConfig, DEFAULT_STORAGE_ROOT};
+use crate::db::Database;
+use crate::metrics;
+use crate::passwords::Passwords;
+use crate::retention;
+use crate::server_utils::{spawn_connection, ServerState};
+use crate::storage::Storage;
+use crate::ws;
+
+/// Sets up a [`Server`]. Everything starts from a [`ServerConfig`]; the other
+/// methods override parts of it.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let server = hwork15::Server::builder()
+///     .listener(tokio::net::TcpListener::bind("127.0.0.1:0").await?)
+///     .database_url("memory:")
+///     .build()
+///     .await?;
+/// println!("Listening on {}", server.local_addr()?);
+/// server.run().await
+/// # }
+/// ```
+pub struct ServerBuilder {
+    config: ServerConfig,
+    listener: Option<TcpListener>,
+    storage: Option<Box<dyn Storage>>,
+    commands: CommandRegistry,
+}
+
+impl ServerBuilder {
+    /// Replaces every setting, e.g. with one from `ServerConfig::load`.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Serves TCP clients on an already bound listener instead of binding
+    /// `listeners.address`, for example one bound to port 0.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Database to use instead of `database.url`; `memory:` keeps
+    /// everything in memory.
+    pub fn database_url(mut self, url: impl Into<String>) -> Self {
+        self.config.database.url = url.into();
+        self
+    }
+
+    /// Keeps data in `storage` instead of opening `database.url`.
+    pub fn storage(mut self, storage: Box<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Adds a command handler next to the built-in ones, replacing any with the same name.
+    pub fn command(mut self, handler: impl CommandHandler + 'static) -> Self {
+        self.commands.register(handler);
+        self
+    }
+
+    /// Replaces every command handler, built-in ones included.
+    pub fn commands(mut self, commands: CommandRegistry) -> Self {
+        self.commands = commands;
+        self
+    }
+
+    /// Validates the settings, opens the database and binds the TCP listener.
+    pub async fn build(self) -> Result<Server> {
+        self.config.validate()?;
+        if self.config.storage.root == Path::new(DEFAULT_STORAGE_ROOT) {
+            std::fs::create_dir_all(DEFAULT_STORAGE_ROOT)
+                .with_context(|| format!("Failed to create {DEFAULT_STORAGE_ROOT}"))?;
+        }
+        let passwords = Passwords::from_config(&self.config.auth)?;
+        let database = match self.storage {
+            Some(storage) => Database::with_storage(storage, passwords),
+            None => Database::new(&self.config.database.url, passwords).await?,
+        };
+        let listener = match self.listener {
+            Some(listener) => listener,
+            None => TcpListener::bind(self.config.listeners.address)
+                .await
+                .context("Failed to bind to socket")?,
+        };
+
+        let (sender, _) = broadcast::channel(self.config.limits.broadcast_capacity);
+        let state = ServerState::new(sender, database, self.config, self.commands);
+        Ok(Server {
+            listener,
+            state: Arc::new(state),
+        })
+    }
+}
+
+/// A chat server serving TCP clients, plus the WebSocket gateway and the
+/// metrics endpoint when they are configured.
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<ServerState>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            config: ServerConfig::default(),
+            listener: None,
+            storage: None,
+            commands: CommandRegistry::with_builtins(),
+        }
+    }
+
+    /// Address the TCP listener is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener
+            .local_addr()
+            .context("Failed to read listener address")
+    }
+
+    /// Accepts clients until `shutdown` is called.
+    pub async fn run(&self) -> Result<()> {
+        info!("Server running on {}", self.local_addr()?);
+
+        // Background tasks, stopped with the server.
+        let mut tasks = Vec::new();
+        if let Some(ws_addr) = self.state.config.listeners.ws_address {
+            let state = Arc::clone(&self.state);
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = ws::run_ws_listener(ws_addr, state).await {
+                    error!("WebSocket gateway stopped: {:?}", e);
+                }
+            }));
+        }
+        if let Some(metrics_addr) = self.state.config.listeners.metrics_address {
+            let metrics = Arc::clone(&self.state.metrics);
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = metrics::run_metrics_listener(metrics_addr, metrics).await {
+                    error!("Metrics listener stopped: {:?}", e);
+                }
+            }));
+        }
+
+        if self.state.config.audit.retention_days > 0 {
+            tasks.push(tokio::spawn(audit::prune_periodically(Arc::clone(
+                &self.state,
+            ))));
+        }
+
+        if self.state.config.retention.enabled() {
+            tasks.push(tokio::spawn(retention::prune_periodically(Arc::clone(
+                &self.state,
+            ))));
+        }
+
+        let shutdown = self.state.shutdown_signal();
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = &mut shutdown => break,
+            };
+            let Ok((stream, addr)) = accepted else {
+                error!("Failed to accept connection");
+                continue;
+            };
+
+            info!("New connection from {}", addr);
+
+            let (stream_reader, stream_writer) = stream.into_split();
+            spawn_connection(stream_reader, stream_writer, addr, Arc::clone(&self.state));
+        }
+
+        for task in tasks {
+            task.abort();
+        }
+        info!("Server stopped");
+        Ok(())
+    }
+
+    /// This server's metrics in the Prometheus text format, as served on
+    /// `listeners.metrics_address`.
+    pub fn encode_metrics(&self) -> Result<String> {
+        self.state.metrics.encode()
+    }
+
+    /// Stops `run` and closes every client connection. A server that was shut
+    /// down stays down: `run` returns straight away afterwards.
+    pub fn shutdown(&self) {
+        self.state.shutdown();
+    }
+}
@@ -1,51 +1,465 @@
+use crate::{
+    receive_message_within, send_message, ClientMessage, MessageId, MessageType, Quote,
+    ReceiptStatus, ResponseType, SealedMessage, SharedLibError,
+};
 use anyhow::{Context, Result};
-use hwork15::{receive_message, send_message, MessageType, ResponseType};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::Component;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{net::SocketAddr, sync::Arc};
 use tokio::fs;
 use tokio::io::AsyncReadExt;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::sync::broadcast::Sender;
-use tokio::sync::Mutex;
+use tokio::sync::broadcast::{error::RecvError, Sender};
+use tokio::sync::{oneshot, watch, Mutex};
 use tracing::{error, info};
 
-use crate::db::Database;
+use crate::audit::{AuditAction, AuditEvent, AuditOutcome};
+use crate::commands::{CommandContext, CommandRegistry};
+use crate::config::ServerConfig;
+use crate::db::{AccountLocked, Database, StoredMessage};
+use crate::images;
+use crate::metrics::Metrics;
+use crate::passwords::PolicyViolation;
 
-pub async fn handle_client(
-    mut stream: OwnedReadHalf,
-    stream_w: &Arc<Mutex<OwnedWriteHalf>>,
+/// Reads whole protocol frames from a connected client.
+pub trait FrameReader: Send + 'static {
+    /// Reads the next frame, failing without reading it in if its encoding is
+    /// longer than `max_len` bytes.
+    fn read_frame<T: DeserializeOwned>(
+        &mut self,
+        max_len: usize,
+    ) -> impl Future<Output = Result<T>> + Send;
+}
+
+/// Writes whole protocol frames to a connected client.
+pub trait FrameWriter: Send + 'static {
+    fn write_frame<T: Serialize + Sync>(
+        &mut self,
+        message: &T,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// TCP clients speak length-prefixed bincode frames.
+impl FrameReader for OwnedReadHalf {
+    async fn read_frame<T: DeserializeOwned>(&mut self, max_len: usize) -> Result<T> {
+        Ok(receive_message_within(self, max_len).await?)
+    }
+}
+
+impl FrameWriter for OwnedWriteHalf {
+    async fn write_frame<T: Serialize + Sync>(&mut self, message: &T) -> Result<()> {
+        Ok(send_message(self, message).await?)
+    }
+}
+
+/// Who a response on the broadcast channel is meant for.
+#[derive(Clone, Debug)]
+pub enum Audience {
+    /// Every connection.
+    All,
+    /// Every connection except the one that caused it.
+    AllExcept(SocketAddr),
+    /// A single connection.
+    Only(SocketAddr),
+    /// Every connection logged in as this user.
+    User(String),
+}
+
+pub type Broadcast = Sender<(ResponseType, Audience)>;
+
+/// Directory under the storage root that uploads are saved to.
+pub(crate) const UPLOADS_DIR: &str = "uploads";
+
+/// Chat messages whose receipts are still relayed to their senders.
+const MAX_TRACKED_RECEIPTS: usize = 10_000;
+
+/// Distinct `@name` candidates looked up per chat message.
+const MAX_MENTIONS: usize = 20;
+
+/// Bytes allowed in a frame on top of its payload.
+const FRAME_OVERHEAD: usize = 4096;
+
+/// Images whose full resolution can still be fetched.
+const MAX_TRACKED_IMAGES: usize = 10_000;
+/// Most bytes of full-resolution images kept for fetching; the oldest go first.
+const MAX_TRACKED_IMAGE_BYTES: usize = 256 * 1024 * 1024;
+
+/// An announced image as validated and stripped, ready to be fetched.
+#[derive(Clone)]
+struct TrackedImage {
+    from: String,
+    name: String,
+    content: Arc<Vec<u8>>,
+}
+
+/// Recently announced images by image id, and their total size.
+#[derive(Default)]
+struct TrackedImages {
+    by_id: BTreeMap<u64, TrackedImage>,
+    bytes: usize,
+}
+
+/// State shared by every connection of the server.
+pub struct ServerState {
+    pub sender: Broadcast,
+    pub database: Database,
+    pub config: ServerConfig,
+    pub commands: CommandRegistry,
+    /// This server's metrics, shared with `database`.
+    pub metrics: Arc<Metrics>,
+    /// Usernames of the authenticated clients, by connection.
+    presence: Mutex<HashMap<SocketAddr, String>>,
+    /// Sender connection and client id of recently broadcast chat messages, by server id.
+    receipts: Mutex<BTreeMap<i64, (SocketAddr, MessageId)>>,
+    /// Recently announced images, served as they were when announced.
+    images: Mutex<TrackedImages>,
+    next_image_id: AtomicU64,
+    /// Set once the server shuts down; every connection closes when it is.
+    shutdown: watch::Sender<bool>,
+}
+
+impl ServerState {
+    pub fn new(
+        sender: Broadcast,
+        database: Database,
+        config: ServerConfig,
+        commands: CommandRegistry,
+    ) -> Self {
+        Self {
+            sender,
+            metrics: Arc::clone(database.metrics()),
+            database,
+            config,
+            commands,
+            presence: Mutex::default(),
+            receipts: Mutex::default(),
+            images: Mutex::default(),
+            next_image_id: AtomicU64::new(1),
+            shutdown: watch::Sender::new(false),
+        }
+    }
+
+    /// Longest frame accepted from a logged-in client: an upload of
+    /// `limits.max_file_bytes` plus room for its name and the encoding.
+    pub fn frame_limit(&self) -> usize {
+        (self.config.limits.max_file_bytes as usize).saturating_add(FRAME_OVERHEAD)
+    }
+
+    /// Longest frame accepted before login, enough for `REGISTER <name> <password>`.
+    pub fn auth_frame_limit(&self) -> usize {
+        FRAME_OVERHEAD + 4 * self.config.auth.max_password_length
+    }
+
+    /// Closes every connection and tells the listeners to stop accepting.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once `shutdown` has been called, even if it was called before.
+    pub fn shutdown_signal(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut shutdown = self.shutdown.subscribe();
+        async move {
+            let _ = shutdown.wait_for(|&done| done).await;
+        }
+    }
+
+    /// Records an audit event. A failure is logged but never fails the request.
+    pub async fn audit(&self, event: AuditEvent) {
+        if let Err(e) = self.database.record_audit(&event).await {
+            error!("Failed to record audit event {:?}: {:?}", event, e);
+        }
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.config
+            .auth
+            .admins
+            .iter()
+            .any(|admin| admin == username)
+    }
+
+    /// Whether the connection at `addr` should receive a response for `audience`.
+    async fn includes(&self, audience: &Audience, addr: SocketAddr) -> bool {
+        match audience {
+            Audience::All => true,
+            Audience::AllExcept(other) => *other != addr,
+            Audience::Only(other) => *other == addr,
+            Audience::User(user) => self.presence.lock().await.get(&addr) == Some(user),
+        }
+    }
+
+    async fn is_online(&self, username: &str) -> bool {
+        self.presence
+            .lock()
+            .await
+            .values()
+            .any(|user| user == username)
+    }
+
+    /// Sorted list of online users, as sent to clients.
+    async fn online_users(&self) -> ResponseType {
+        let mut users: Vec<String> = self.presence.lock().await.values().cloned().collect();
+        users.sort();
+        users.dedup();
+        ResponseType::Users(users)
+    }
+
+    async fn track_receipts(&self, message_id: i64, addr: SocketAddr, id: MessageId) {
+        let mut receipts = self.receipts.lock().await;
+        receipts.insert(message_id, (addr, id));
+        while receipts.len() > MAX_TRACKED_RECEIPTS {
+            receipts.pop_first();
+        }
+    }
+
+    /// Keeps an announced image for fetching and returns its id. The newest
+    /// image is always kept, however large.
+    async fn track_image(&self, image: TrackedImage) -> u64 {
+        let id = self.next_image_id.fetch_add(1, Ordering::Relaxed);
+        let mut images = self.images.lock().await;
+        images.bytes += image.content.len();
+        images.by_id.insert(id, image);
+        while images.by_id.len() > 1
+            && (images.by_id.len() > MAX_TRACKED_IMAGES || images.bytes > MAX_TRACKED_IMAGE_BYTES)
+        {
+            if let Some((_, oldest)) = images.by_id.pop_first() {
+                images.bytes -= oldest.content.len();
+            }
+        }
+        id
+    }
+
+    async fn tracked_image(&self, id: u64) -> Option<TrackedImage> {
+        self.images.lock().await.by_id.get(&id).cloned()
+    }
+
+    /// Forwards a recipient's receipt to the connection that sent the message.
+    async fn relay_receipt(&self, message_id: i64, user: &str, status: ReceiptStatus) {
+        let Some(&(addr, id)) = self.receipts.lock().await.get(&message_id) else {
+            return;
+        };
+        let receipt = ResponseType::Receipt {
+            id,
+            user: user.to_string(),
+            status,
+        };
+        let _ = self.sender.send((receipt, Audience::Only(addr)));
+    }
+}
+
+/// Spawns the tasks serving one client: its request loop and the broadcast fan-out to it.
+pub fn spawn_connection<R: FrameReader, W: FrameWriter>(
+    stream_reader: R,
+    stream_writer: W,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+) {
+    let mut receiver = state.sender.subscribe();
+
+    let stream_writer_sync = Arc::new(Mutex::new(stream_writer));
+    let stream_writer_clone = stream_writer_sync.clone();
+    let state_clone = Arc::clone(&state);
+    // Closes the connection once the request loop is over.
+    let (done_tx, mut done) = oneshot::channel::<()>();
+
+    let connected = state.metrics.connected_clients.clone();
+    connected.inc();
+    tokio::spawn(async move {
+        if let Err(e) = handle_client(stream_reader, &stream_writer_sync, addr, state_clone).await {
+            error!("Error handling client: {:?}", e);
+        }
+        connected.dec();
+        drop(done_tx);
+    });
+
+    tokio::spawn(async move {
+        let shutdown = state.shutdown_signal();
+        tokio::pin!(shutdown);
+        let mut finishing = false;
+        loop {
+            let received = if finishing {
+                // Deliver what was already sent to this client, such as a last error.
+                match receiver.try_recv() {
+                    Ok(item) => Ok(item),
+                    Err(_) => break,
+                }
+            } else {
+                tokio::select! {
+                    received = receiver.recv() => received,
+                    _ = &mut done => {
+                        finishing = true;
+                        continue;
+                    }
+                    _ = &mut shutdown => break,
+                }
+            };
+            let (msg, audience) = match received {
+                Ok(item) => item,
+                Err(RecvError::Lagged(skipped)) => {
+                    state.metrics.broadcast_lag_total.inc();
+                    error!("Client {} lagged behind by {} messages", addr, skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if !state.includes(&audience, addr).await {
+                continue;
+            }
+            let mut stream = stream_writer_clone.lock().await;
+            if let Err(e) = stream.write_frame(&msg).await {
+                error!("{e}");
+                break;
+            }
+            drop(stream);
+            // Only now has the recipient got it; until then it stays in the queue.
+            if let ResponseType::Direct { id, .. } | ResponseType::SealedDirect { id, .. } = msg {
+                if let Err(e) = state.database.mark_direct_message_delivered(id).await {
+                    error!("Failed to mark direct message {} delivered: {:?}", id, e);
+                }
+            }
+        }
+    });
+}
+
+pub async fn handle_client<R: FrameReader, W: FrameWriter>(
+    mut stream: R,
+    stream_w: &Arc<Mutex<W>>,
     addr: SocketAddr,
-    sender: Sender<(ResponseType, SocketAddr)>,
-    database: Arc<Database>,
+    state: Arc<ServerState>,
 ) -> Result<()> {
-    let username = handle_authentication_or_registration(
-        &mut stream,
-        stream_w.clone(),
-        addr,
-        database.clone(),
-    )
-    .await?;
+    let login = handle_authentication_or_registration(&mut stream, stream_w.clone(), addr, &state);
+    let username = tokio::select! {
+        username = login => username?,
+        _ = state.shutdown_signal() => return Ok(()),
+    };
     info!("User {username} authenticated.");
+    state.metrics.authenticated_users.inc();
+    state.presence.lock().await.insert(addr, username.clone());
+    let online = state.online_users().await;
+    stream_w.lock().await.write_frame(&online).await?;
+    let _ = state.sender.send((online, Audience::AllExcept(addr)));
+    flush_direct_messages(&state, &username, stream_w).await?;
+
+    let res = tokio::select! {
+        res = serve_authenticated(&mut stream, addr, &username, &state) => res,
+        _ = state.shutdown_signal() => Ok(()),
+    };
+
+    state.presence.lock().await.remove(&addr);
+    if let Err(e) = state.database.mark_seen(&username).await {
+        error!("Failed to record last-seen time of {}: {:?}", username, e);
+    }
+    let online = state.online_users().await;
+    let _ = state.sender.send((online, Audience::AllExcept(addr)));
+    state.metrics.authenticated_users.dec();
+    res
+}
+
+async fn serve_authenticated<R: FrameReader>(
+    stream: &mut R,
+    addr: SocketAddr,
+    username: &str,
+    state: &ServerState,
+) -> Result<()> {
     loop {
-        let cli_message = match receive_message::<MessageType, OwnedReadHalf>(&mut stream).await {
+        let ClientMessage { id, message } = match stream
+            .read_frame::<ClientMessage>(state.frame_limit())
+            .await
+        {
             Ok(msg) => msg,
             Err(e) => {
+                // The rest of the frame is never read, so the session cannot go on.
+                if let Some(too_large @ SharedLibError::FrameTooLarge(..)) = e.downcast_ref() {
+                    let error = ResponseType::Error(too_large.to_string());
+                    let _ = state.sender.send((error, Audience::Only(addr)));
+                }
                 error!("Error receiving message from {}: {:?}", addr, e);
                 break;
             }
         };
 
-        let res = match cli_message {
-            MessageType::File(path) => handle_file(&path).await.unwrap_or_else(|e| {
-                ResponseType::Error(format!("Error handling file{}: {}", path, e))
-            }),
-            MessageType::Image(path) => handle_image(&path).await.unwrap_or_else(|e| {
-                ResponseType::Error(format!("Error handling image {}: {}", path, e))
-            }),
-            MessageType::Text(text) => {
-                if let Err(e) = database.save_message_by_username(&username, &text).await {
-                    error!("Failed to save message to database: {:?}", e);
+        let audited = audited_request(&message);
+        let attachment = shared_attachment(&message);
+        // Ok(Some(..)) is broadcast to everyone else, Ok(None) was already delivered.
+        let res = match message {
+            MessageType::File(path) => handle_file(&path, &state.config)
+                .await
+                .map(Some)
+                .map_err(|e| format!("Error handling file {}: {}", path, e)),
+            MessageType::Image(path) => handle_image(&path, username, state)
+                .await
+                .map(Some)
+                .map_err(|e| format!("Error handling image {}: {}", path, e)),
+            MessageType::FetchImage(image_id) => match fetch_image(image_id, state).await {
+                Ok(image) => {
+                    state.metrics.record_response(&image);
+                    let _ = state.sender.send((image, Audience::Only(addr)));
+                    Ok(None)
                 }
-                ResponseType::Text(format!("{}: {}", username, text))
+                Err(e) => Err(format!("Error fetching image #{}: {}", image_id, e)),
+            },
+            MessageType::Upload(name, content) => handle_upload(&name, content, &state.config)
+                .await
+                .map(Some)
+                .map_err(|e| format!("Error storing upload {}: {}", name, e)),
+            MessageType::Text(text) => save_chat(state, username, addr, id, text, None)
+                .await
+                .map(Some),
+            MessageType::Reply(parent, text) => {
+                save_chat(state, username, addr, id, text, Some(parent))
+                    .await
+                    .map(Some)
+            }
+            MessageType::Direct(to, text) => {
+                send_direct(state, username, &to, DirectBody::Text(text))
+                    .await
+                    .map(|()| None)
+            }
+            MessageType::SealedDirect(to, sealed) => {
+                send_direct(state, username, &to, DirectBody::Sealed(sealed))
+                    .await
+                    .map(|()| None)
+            }
+            MessageType::PublishKey(key) => state
+                .database
+                .set_public_key(username, &key)
+                .await
+                .map(|()| None)
+                .map_err(|e| {
+                    error!("Failed to store public key of {}: {:?}", username, e);
+                    "Failed to store public key".to_string()
+                }),
+            MessageType::FetchKey(user) => match state.database.public_key(&user).await {
+                Ok(key) => {
+                    let key = key.and_then(|key| key.try_into().ok());
+                    let response = ResponseType::PublicKey { user, key };
+                    state.metrics.record_response(&response);
+                    let _ = state.sender.send((response, Audience::Only(addr)));
+                    Ok(None)
+                }
+                Err(e) => {
+                    error!("Failed to look up public key of {}: {:?}", user, e);
+                    Err("Failed to look up public key".to_string())
+                }
+            },
+            MessageType::Command(name, args) => run_command(state, username, addr, &name, &args)
+                .await
+                .map(|()| None),
+            MessageType::Receipt(message_id, status) => {
+                if status == ReceiptStatus::Read {
+                    if let Err(e) = state
+                        .database
+                        .mark_mentions_read(username, message_id)
+                        .await
+                    {
+                        error!("Failed to mark mentions of {} read: {:?}", username, e);
+                    }
+                }
+                state.relay_receipt(message_id, username, status).await;
+                continue;
             }
             MessageType::Quit => {
                 info!("Client {} has disconnected.", addr);
@@ -53,7 +467,31 @@ pub async fn handle_client(
             }
         };
 
-        if sender.send((res, addr)).is_err() {
+        if let Some((action, target)) = audited {
+            let event = AuditEvent::for_result(username, addr, action, &res).target(target);
+            state.audit(event).await;
+        }
+        if let (Some((name, path)), Ok(_)) = (attachment, &res) {
+            if let Err(e) = state
+                .database
+                .record_attachment(username, &name, &path)
+                .await
+            {
+                error!("Failed to record attachment {}: {:?}", path, e);
+            }
+        }
+
+        let (res, ack) = match res {
+            Ok(res) => (res, ResponseType::Ack(id)),
+            Err(reason) => (None, ResponseType::Nack(id, reason)),
+        };
+        if let Some(res) = res {
+            state.metrics.record_response(&res);
+            if state.sender.send((res, Audience::AllExcept(addr))).is_err() {
+                break;
+            }
+        }
+        if state.sender.send((ack, Audience::Only(addr))).is_err() {
             break;
         }
     }
@@ -61,20 +499,392 @@ pub async fn handle_client(
     Ok(())
 }
 
-async fn handle_file(path: &str) -> Result<ResponseType> {
+/// The audit action and target of requests that read or write files.
+fn audited_request(message: &MessageType) -> Option<(AuditAction, String)> {
+    match message {
+        MessageType::File(path) => Some((AuditAction::FileRequest, path.clone())),
+        MessageType::Image(path) => Some((AuditAction::ImageRequest, path.clone())),
+        MessageType::FetchImage(id) => Some((AuditAction::ImageFetch, format!("#{id}"))),
+        MessageType::Upload(name, _) => Some((AuditAction::Upload, name.clone())),
+        _ => None,
+    }
+}
+
+/// Name and storage path of files shared with everyone, kept for transcripts.
+/// Paths are recorded normalized, and never when they could leave the storage root.
+fn shared_attachment(message: &MessageType) -> Option<(String, String)> {
+    match message {
+        MessageType::File(path) | MessageType::Image(path) => {
+            Some((get_file_name(path).ok()?, relative_path(path)?))
+        }
+        MessageType::Upload(name, _) if is_upload_name(name) => {
+            Some((name.clone(), format!("{UPLOADS_DIR}/{name}")))
+        }
+        _ => None,
+    }
+}
+
+/// `path` as `a/b/c`, or `None` if it is absolute or contains `..`.
+fn relative_path(path: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in std::path::Path::new(path).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+/// Whether `name` can be stored as `uploads/<name>`: a plain, visible file name.
+pub(crate) fn is_upload_name(name: &str) -> bool {
+    get_file_name(name).ok().as_deref() == Some(name) && !name.starts_with('.')
+}
+
+/// Stores a chat message, answering `reply_to` if set, and returns the event to broadcast.
+async fn save_chat(
+    state: &ServerState,
+    username: &str,
+    addr: SocketAddr,
+    id: MessageId,
+    text: String,
+    reply_to: Option<i64>,
+) -> Result<ResponseType, String> {
+    let storage_error = |e: anyhow::Error| {
+        error!("Failed to save message to database: {:?}", e);
+        "Failed to store message".to_string()
+    };
+    let quote = match reply_to {
+        Some(parent) => match state
+            .database
+            .message(parent)
+            .await
+            .map_err(storage_error)?
+        {
+            Some(parent) => Some(quote(&parent)),
+            None => return Err(format!("Unknown message #{parent}")),
+        },
+        None => None,
+    };
+    let message_id = state
+        .database
+        .save_message_by_username(username, &text, reply_to)
+        .await
+        .map_err(storage_error)?;
+    state.track_receipts(message_id, addr, id).await;
+    let mentions = record_mentions(state, username, message_id, &text).await;
+    Ok(ResponseType::Chat {
+        id: message_id,
+        from: username.to_string(),
+        text,
+        mentions,
+        reply_to: quote,
+    })
+}
+
+/// Characters of a message quoted in replies to it.
+const QUOTE_CHARS: usize = 60;
+
+fn quote(message: &StoredMessage) -> Quote {
+    let mut text: String = message.content.chars().take(QUOTE_CHARS).collect();
+    if text.len() < message.content.len() {
+        text.push('…');
+    }
+    Quote {
+        id: message.id,
+        from: message.username.clone(),
+        text,
+    }
+}
+
+/// Users a chat message addresses as `@name`, each once, ignoring anything
+/// that is not a registered user and the sender themselves. A mention failing
+/// to be stored still leaves the message delivered.
+async fn record_mentions(
+    state: &ServerState,
+    from: &str,
+    message_id: i64,
+    text: &str,
+) -> Vec<String> {
+    let mut mentions: Vec<String> = Vec::new();
+    'words: for names in mention_candidates(text).take(MAX_MENTIONS) {
+        for name in names {
+            // Mentioning yourself or someone already mentioned is plain text;
+            // the rest of the message is still checked.
+            if name == from || mentions.iter().any(|user| user == name) {
+                continue 'words;
+            }
+            match state.database.user_exists(name).await {
+                Ok(true) => {
+                    mentions.push(name.to_string());
+                    continue 'words;
+                }
+                Ok(false) => {}
+                Err(e) => error!("Failed to look up mentioned user {}: {:?}", name, e),
+            }
+        }
+    }
+    if !mentions.is_empty() {
+        if let Err(e) = state.database.record_mentions(message_id, &mentions).await {
+            error!(
+                "Failed to record mentions of message {}: {:?}",
+                message_id, e
+            );
+        }
+    }
+    mentions
+}
+
+/// Possible names of each word starting with `@`, best first. Usernames may
+/// contain punctuation, so `@bob,` means the user `bob,` if there is one, else `bob`.
+fn mention_candidates(text: &str) -> impl Iterator<Item = Vec<&str>> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| {
+            let trimmed = name.trim_end_matches(|c: char| c.is_ascii_punctuation());
+            let mut names = vec![name];
+            if trimmed != name {
+                names.push(trimmed);
+            }
+            names.retain(|name| !name.is_empty());
+            names
+        })
+}
+
+/// Runs the registered handler for `/name args`; its replies are already sent.
+async fn run_command(
+    state: &ServerState,
+    username: &str,
+    addr: SocketAddr,
+    name: &str,
+    args: &str,
+) -> Result<(), String> {
+    let Some(handler) = state.commands.get(name) else {
+        return Err(format!("Unknown command /{name}, try /help"));
+    };
+    info!("{} ran /{} {}", username, name, args);
+    let ctx = CommandContext::new(username, addr, state);
+    handler
+        .run(&ctx, args)
+        .await
+        .map_err(|e| format!("/{name}: {e:#}"))
+}
+
+/// Body of a direct message: readable by the server, or sealed for the recipient.
+enum DirectBody {
+    Text(String),
+    Sealed(SealedMessage),
+}
+
+impl DirectBody {
+    fn into_response(self, id: i64, from: String, queued_at: Option<String>) -> ResponseType {
+        match self {
+            DirectBody::Text(text) => ResponseType::Direct {
+                id,
+                from,
+                text,
+                queued_at,
+            },
+            DirectBody::Sealed(sealed) => ResponseType::SealedDirect {
+                id,
+                from,
+                sealed,
+                queued_at,
+            },
+        }
+    }
+}
+
+/// Queues a direct message for `to` and hands it to their connections if online;
+/// it stays queued until one of them has written it.
+async fn send_direct(
+    state: &ServerState,
+    from: &str,
+    to: &str,
+    body: DirectBody,
+) -> Result<(), String> {
+    let limits = &state.config.limits;
+    let database = &state.database;
+    let storage_error = |e: anyhow::Error| {
+        error!("Failed to store direct message: {:?}", e);
+        "Failed to store message".to_string()
+    };
+
+    if !database.user_exists(to).await.map_err(storage_error)? {
+        return Err(format!("Unknown user {}", to));
+    }
+    let online = state.is_online(to).await;
+    if !online {
+        let queued = database
+            .count_queued_direct_messages(to, limits.offline_queue_ttl_secs)
+            .await
+            .map_err(storage_error)?;
+        if queued >= limits.offline_queue_limit {
+            return Err(format!("{}'s offline queue is full", to));
+        }
+    }
+
+    let (content, sealed) = match &body {
+        DirectBody::Text(text) => (text.as_str(), None),
+        DirectBody::Sealed(sealed) => (
+            "",
+            Some(bincode::serialize(sealed).map_err(|e| storage_error(e.into()))?),
+        ),
+    };
+    let id = database
+        .queue_direct_message(from, to, content, sealed.as_deref())
+        .await
+        .map_err(storage_error)?;
+    if online {
+        let direct = body.into_response(id, from.to_string(), None);
+        state.metrics.record_response(&direct);
+        let _ = state.sender.send((direct, Audience::User(to.to_string())));
+    }
+    Ok(())
+}
+
+/// Sends a freshly authenticated user everything waiting in their offline queue, in order.
+async fn flush_direct_messages<W: FrameWriter>(
+    state: &ServerState,
+    username: &str,
+    stream_w: &Arc<Mutex<W>>,
+) -> Result<()> {
+    let queued = state
+        .database
+        .queued_direct_messages(username, state.config.limits.offline_queue_ttl_secs)
+        .await?;
+    if !queued.is_empty() {
+        info!(
+            "Delivering {} queued direct messages to {}",
+            queued.len(),
+            username
+        );
+    }
+    for message in queued {
+        let body = match message.sealed {
+            Some(sealed) => match bincode::deserialize(&sealed) {
+                Ok(sealed) => DirectBody::Sealed(sealed),
+                Err(e) => {
+                    error!("Skipping unreadable sealed message {}: {:?}", message.id, e);
+                    continue;
+                }
+            },
+            None => DirectBody::Text(message.content),
+        };
+        let direct = body.into_response(message.id, message.sender, Some(message.timestamp));
+        stream_w.lock().await.write_frame(&direct).await?;
+        state
+            .database
+            .mark_direct_message_delivered(message.id)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn handle_file(path: &str, config: &ServerConfig) -> Result<ResponseType> {
     let file_name = get_file_name(path).context("Failed to get file name")?;
-    let contents = read_file(path).await.context("Failed to read file")?;
+    let contents = read_file(path, config)
+        .await
+        .context("Failed to read file")?;
     Ok(ResponseType::File(file_name, contents))
 }
 
-async fn handle_image(path: &str) -> Result<ResponseType> {
-    let file_name = get_file_name(path).context("Failed to get image name")?;
-    let contents = read_file(path).await.context("Failed to read image")?;
-    Ok(ResponseType::Image(file_name, contents))
+/// Validates the image at `path` and announces it with a thumbnail. The
+/// stripped full-resolution image is kept for `fetch_image`.
+async fn handle_image(path: &str, from: &str, state: &ServerState) -> Result<ResponseType> {
+    let name = get_file_name(path).context("Failed to get image name")?;
+    let contents = read_file(path, &state.config)
+        .await
+        .context("Failed to read image")?;
+    let limits = &state.config.limits;
+    let (max_dimension, size) = (limits.max_image_dimension, limits.thumbnail_size);
+    let announced = name.clone();
+    let (width, height, thumbnail, (full_name, full)) = tokio::task::spawn_blocking(move || {
+        let decoded = images::decode(&contents, max_dimension)?;
+        let thumbnail = images::thumbnail(&decoded, size)?;
+        let full = images::encode_full(&decoded, &announced)?;
+        Ok::<_, anyhow::Error>((
+            decoded.image.width(),
+            decoded.image.height(),
+            thumbnail,
+            full,
+        ))
+    })
+    .await??;
+
+    let tracked = TrackedImage {
+        from: from.to_string(),
+        name: full_name,
+        content: Arc::new(full),
+    };
+    Ok(ResponseType::Thumbnail {
+        id: state.track_image(tracked).await,
+        from: from.to_string(),
+        name,
+        width,
+        height,
+        thumbnail,
+    })
+}
+
+/// Full-resolution image with its metadata stripped, as it was announced.
+async fn fetch_image(id: u64, state: &ServerState) -> Result<ResponseType> {
+    let image = state
+        .tracked_image(id)
+        .await
+        .context("Unknown or expired image")?;
+    Ok(ResponseType::Image {
+        from: image.from,
+        name: image.name,
+        content: image.content.to_vec(),
+    })
+}
+
+/// Stores an uploaded file as `uploads/<name>` under the storage root.
+async fn handle_upload(
+    name: &str,
+    content: Vec<u8>,
+    config: &ServerConfig,
+) -> Result<ResponseType> {
+    if !is_upload_name(name) {
+        return Err(anyhow::anyhow!("Invalid file name"));
+    }
+    if content.len() as u64 > config.limits.max_file_bytes {
+        return Err(anyhow::anyhow!(
+            "File is {} bytes, the limit is {}",
+            content.len(),
+            config.limits.max_file_bytes
+        ));
+    }
+    let dir = config.storage.root.join(UPLOADS_DIR);
+    fs::create_dir_all(&dir)
+        .await
+        .context("Failed to create uploads directory")?;
+    fs::write(dir.join(name), &content)
+        .await
+        .context("Failed to write file")?;
+    info!(
+        "Stored upload {}/{} ({} bytes)",
+        UPLOADS_DIR,
+        name,
+        content.len()
+    );
+    Ok(ResponseType::File(name.to_string(), content))
 }
 
-async fn read_file(path: &str) -> Result<Vec<u8>> {
-    let mut file = fs::File::open(path).await.context("Failed to open file")?;
+async fn read_file(path: &str, config: &ServerConfig) -> Result<Vec<u8>> {
+    let mut file = fs::File::open(config.storage_path(path)?)
+        .await
+        .context("Failed to open file")?;
+    let size = file.metadata().await.context("Failed to stat file")?.len();
+    if size > config.limits.max_file_bytes {
+        return Err(anyhow::anyhow!(
+            "File is {} bytes, the limit is {}",
+            size,
+            config.limits.max_file_bytes
+        ));
+    }
     let mut contents = Vec::new();
     file.read_to_end(&mut contents)
         .await
@@ -92,19 +902,20 @@ fn get_file_name(path: &str) -> Result<String> {
     Ok(name)
 }
 
-async fn handle_authentication_or_registration(
-    stream: &mut OwnedReadHalf,
-    stream_w: Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+async fn handle_authentication_or_registration<R: FrameReader, W: FrameWriter>(
+    stream: &mut R,
+    stream_w: Arc<Mutex<W>>,
     addr: std::net::SocketAddr,
-    database: Arc<Database>,
+    state: &ServerState,
 ) -> Result<String> {
+    let database = &state.database;
     loop {
         info!("Server is ready to authenticate you.");
-        let auth_message = match receive_message::<String, OwnedReadHalf>(stream).await {
+        let auth_message = match stream.read_frame::<String>(state.auth_frame_limit()).await {
             Ok(msg) => msg,
             Err(e) => {
                 error!("Error receiving auth message from {}: {:?}", addr, e);
-                return Err(e.into());
+                return Err(e);
             }
         };
 
@@ -120,43 +931,318 @@ async fn handle_authentication_or_registration(
         if action == "REGISTER" {
             match database.create_user(username, password).await {
                 Ok(_) => {
+                    state
+                        .audit(AuditEvent::new(
+                            username,
+                            addr,
+                            AuditAction::Register,
+                            AuditOutcome::Success,
+                        ))
+                        .await;
                     let mut stream = stream_w.lock().await;
-                    send_message(
-                        &mut *stream,
-                        &ResponseType::Text("Registration successful".to_string()),
-                    )
-                    .await?;
+                    stream
+                        .write_frame(&ResponseType::Text("Registration successful".to_string()))
+                        .await?;
                     drop(stream);
                     return Ok(username.to_string());
                 }
                 Err(e) => {
                     error!("Registration failed for {}: {:?}", addr, e);
-                    let mut stream = stream_w.lock().await;
-                    send_message(
-                        &mut *stream,
-                        &ResponseType::Error("Registration failed".to_string()),
+                    state
+                        .metrics
+                        .auth_failures_total
+                        .with_label_values(&["register"])
+                        .inc();
+                    // Policy violations are worth explaining; other failures stay vague.
+                    let violation = e.downcast_ref::<PolicyViolation>();
+                    let event = AuditEvent::new(
+                        username,
+                        addr,
+                        AuditAction::Register,
+                        AuditOutcome::Failure,
                     )
-                    .await?;
+                    .detail(violation.map_or_else(
+                        || "user exists or could not be stored".to_string(),
+                        ToString::to_string,
+                    ));
+                    state.audit(event).await;
+                    let message = match violation {
+                        Some(violation) => format!("Registration failed: {}", violation),
+                        None => "Registration failed".to_string(),
+                    };
+                    let mut stream = stream_w.lock().await;
+                    stream.write_frame(&ResponseType::Error(message)).await?;
                     drop(stream);
                 }
             }
         } else if action == "AUTH" {
             match database.authenticate_user(username, password).await {
                 Ok(_) => {
+                    state
+                        .audit(AuditEvent::new(
+                            username,
+                            addr,
+                            AuditAction::Login,
+                            AuditOutcome::Success,
+                        ))
+                        .await;
                     let mut stream = stream_w.lock().await;
-                    send_message(&mut *stream, &ResponseType::Text("AUTH OK".to_string())).await?;
+                    stream
+                        .write_frame(&ResponseType::Text("AUTH OK".to_string()))
+                        .await?;
                     drop(stream);
                     return Ok(username.to_string());
                 }
                 Err(e) => {
                     error!("Authentication failed for {}: {:?}", addr, e);
-                    let failure_message = ResponseType::Error(format!("AUTH FAILED: {:?}", e));
+                    state
+                        .metrics
+                        .auth_failures_total
+                        .with_label_values(&["auth"])
+                        .inc();
+                    let locked = e.is::<AccountLocked>();
+                    let event =
+                        AuditEvent::new(username, addr, AuditAction::Login, AuditOutcome::Failure)
+                            .detail(if locked {
+                                "account locked"
+                            } else {
+                                "invalid username or password"
+                            });
+                    state.audit(event).await;
+                    let failure_message = if locked {
+                        ResponseType::Error(format!("AUTH FAILED: {}", e))
+                    } else {
+                        ResponseType::Error(format!("AUTH FAILED: {:?}", e))
+                    };
 
                     let mut stream = stream_w.lock().await;
-                    send_message(&mut *stream, &failure_message).await?;
+                    stream.write_frame(&failure_message).await?;
                     drop(stream);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthConfig;
+    use crate::passwords::Passwords;
+    use crate::storage::{MemoryStorage, Storage};
+    use tokio::sync::broadcast;
+
+    /// Server state over in-memory storage holding `users`.
+    async fn state_with_users(users: &[&str]) -> ServerState {
+        state_with_config(users, ServerConfig::default()).await
+    }
+
+    async fn state_with_config(users: &[&str], config: ServerConfig) -> ServerState {
+        state_with_storage(storage_with_users(users).await, config)
+    }
+
+    async fn storage_with_users(users: &[&str]) -> MemoryStorage {
+        let storage = MemoryStorage::default();
+        for user in users {
+            storage.create_user(user, "unused-hash").await.unwrap();
+        }
+        storage
+    }
+
+    fn state_with_storage(storage: MemoryStorage, config: ServerConfig) -> ServerState {
+        let passwords = Passwords::from_config(&AuthConfig::default()).unwrap();
+        let database = Database::with_storage(Box::new(storage), passwords);
+        let (sender, _) = broadcast::channel(16);
+        ServerState::new(sender, database, config, CommandRegistry::with_builtins())
+    }
+
+    #[test]
+    fn mention_candidates_try_the_whole_word_first() {
+        let candidates: Vec<_> = mention_candidates("hi @bob, @carol mail@x @ @!").collect();
+        assert_eq!(
+            candidates,
+            [vec!["bob,", "bob"], vec!["carol"], vec![], vec!["!"]]
+        );
+    }
+
+    #[tokio::test]
+    async fn mentions_skip_self_duplicates_and_unknown_users() {
+        let state = state_with_users(&["alice", "bob", "carol"]).await;
+        let mentions =
+            record_mentions(&state, "alice", 1, "@alice @bob @nobody @bob! @carol").await;
+        assert_eq!(mentions, ["bob", "carol"]);
+    }
+
+    #[tokio::test]
+    async fn mentions_prefer_usernames_with_punctuation() {
+        let state = state_with_users(&["bob", "bob,"]).await;
+        let mentions = record_mentions(&state, "alice", 1, "@bob, and @bob.").await;
+        assert_eq!(mentions, ["bob,", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn mentions_are_capped_per_message() {
+        let names: Vec<String> = (0..MAX_MENTIONS + 5).map(|i| format!("user{i}")).collect();
+        let users: Vec<&str> = names.iter().map(String::as_str).collect();
+        let state = state_with_users(&users).await;
+        let text: Vec<String> = names.iter().map(|name| format!("@{name}")).collect();
+        let mentions = record_mentions(&state, "alice", 1, &text.join(" ")).await;
+        assert_eq!(mentions.len(), MAX_MENTIONS);
+    }
+
+    fn text(text: &str) -> DirectBody {
+        DirectBody::Text(text.to_string())
+    }
+
+    #[tokio::test]
+    async fn offline_queue_is_capped_per_recipient() {
+        let mut config = ServerConfig::default();
+        config.limits.offline_queue_limit = 2;
+        let state = state_with_config(&["alice", "bob", "carol"], config).await;
+
+        send_direct(&state, "alice", "bob", text("one"))
+            .await
+            .unwrap();
+        send_direct(&state, "carol", "bob", text("two"))
+            .await
+            .unwrap();
+        let full = send_direct(&state, "alice", "bob", text("three")).await;
+        assert_eq!(full.unwrap_err(), "bob's offline queue is full");
+        // Another recipient's queue is separate.
+        send_direct(&state, "alice", "carol", text("one"))
+            .await
+            .unwrap();
+
+        let unknown = send_direct(&state, "alice", "nobody", text("hi")).await;
+        assert_eq!(unknown.unwrap_err(), "Unknown user nobody");
+    }
+
+    #[tokio::test]
+    async fn offline_messages_expire_after_the_ttl() {
+        const TTL: u64 = 60 * 60;
+        let storage = storage_with_users(&["alice", "bob"]).await;
+        storage
+            .queue_direct_message("alice", "bob", "old", None)
+            .await
+            .unwrap();
+        storage.queued_ago(TTL + 1);
+        let mut config = ServerConfig::default();
+        config.limits.offline_queue_limit = 1;
+        config.limits.offline_queue_ttl_secs = TTL;
+        let state = state_with_storage(storage, config);
+
+        // The old message has expired and no longer fills the queue.
+        send_direct(&state, "alice", "bob", text("new"))
+            .await
+            .unwrap();
+        let queued = state
+            .database
+            .queued_direct_messages("bob", TTL)
+            .await
+            .unwrap();
+        let contents: Vec<_> = queued.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["new"]);
+        // The fresh one has not, so the queue is full.
+        assert!(send_direct(&state, "alice", "bob", text("newer"))
+            .await
+            .is_err());
+    }
+
+    /// Reads nothing, leaving the connection waiting for a login.
+    struct Idle;
+
+    impl FrameReader for Idle {
+        async fn read_frame<T: DeserializeOwned>(&mut self, _max_len: usize) -> Result<T> {
+            std::future::pending().await
+        }
+    }
+
+    /// Accepts every frame, or fails every write when `broken`.
+    struct Wire {
+        broken: bool,
+    }
+
+    impl FrameWriter for Wire {
+        async fn write_frame<T: Serialize + Sync>(&mut self, _message: &T) -> Result<()> {
+            if self.broken {
+                anyhow::bail!("Connection reset");
+            }
+            Ok(())
+        }
+    }
+
+    /// Server state with `bob` connected over `wire`, once alice has sent him a direct message.
+    async fn direct_message_to_bob(wire: Wire) -> Arc<ServerState> {
+        let state = Arc::new(state_with_users(&["alice", "bob"]).await);
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        spawn_connection(Idle, wire, addr, Arc::clone(&state));
+        state.presence.lock().await.insert(addr, "bob".to_string());
+        send_direct(&state, "alice", "bob", text("hi"))
+            .await
+            .unwrap();
+        state
+    }
+
+    async fn queued_for_bob(state: &ServerState) -> usize {
+        let queued = state.database.queued_direct_messages("bob", 60).await;
+        queued.unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn direct_messages_are_delivered_once_written() {
+        let state = direct_message_to_bob(Wire { broken: false }).await;
+        let delivered = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while queued_for_bob(&state).await > 0 {
+                tokio::task::yield_now().await;
+            }
+        });
+        delivered.await.expect("still queued");
+    }
+
+    #[tokio::test]
+    async fn direct_messages_stay_queued_when_the_write_fails() {
+        let state = direct_message_to_bob(Wire { broken: true }).await;
+        // The writer gives up on the connection after the failed write.
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while state.sender.receiver_count() > 0 {
+                tokio::task::yield_now().await;
+            }
+        });
+        closed.await.expect("connection still open");
+        assert_eq!(queued_for_bob(&state).await, 1);
+    }
+
+    #[tokio::test]
+    async fn fetched_images_are_the_ones_announced() {
+        let root = std::env::temp_dir().join(format!("hwork15-fetch-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut png = Vec::new();
+        image::RgbImage::new(40, 20)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        std::fs::write(root.join("photo.png"), png).unwrap();
+        let mut config = ServerConfig::default();
+        config.storage.root = root.clone();
+        let state = state_with_config(&["alice"], config).await;
+
+        let ResponseType::Thumbnail { id, .. } =
+            handle_image("photo.png", "alice", &state).await.unwrap()
+        else {
+            panic!("no thumbnail");
+        };
+        // Whatever happens to the file afterwards, the validated image is served.
+        std::fs::write(root.join("photo.png"), b"no longer an image").unwrap();
+        let ResponseType::Image {
+            from,
+            name,
+            content,
+        } = fetch_image(id, &state).await.unwrap()
+        else {
+            panic!("no image");
+        };
+        assert_eq!((from.as_str(), name.as_str()), ("alice", "photo.png"));
+        let fetched = images::decode(&content, 1024).unwrap();
+        assert_eq!((fetched.image.width(), fetched.image.height()), (40, 20));
+        assert!(fetch_image(id + 1, &state).await.is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use sqlx::FromRow;
+
+use crate::audit::{AuditEvent, AuditFilter};
+use crate::db::{
+    DatabaseStats, QueuedDirectMessage, StoredAttachment, StoredAuditEvent, StoredMessage,
+    UserMessages, UserSummary,
+};
+use crate::export::TranscriptFilter;
+
+mod memory;
+mod postgres;
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+/// What is needed to check a login.
+#[derive(FromRow)]
+pub struct Credentials {
+    pub id: i64,
+    pub password_hash: String,
+    pub locked: bool,
+}
+
+/// Where users, messages and everything else the server keeps are stored.
+/// Password hashing and policy stay in [`Database`](crate::db::Database), so
+/// a backend only stores and queries rows.
+///
+/// Timestamps are UTC, formatted `YYYY-MM-DD HH:MM:SS`, so they sort as text.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Fails if the username is taken.
+    async fn create_user(&self, username: &str, password_hash: &str) -> Result<()>;
+    async fn user_exists(&self, username: &str) -> Result<bool>;
+    async fn credentials(&self, username: &str) -> Result<Option<Credentials>>;
+    /// False if there is no such user.
+    async fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<bool>;
+    /// False if there is no such user.
+    async fn set_locked(&self, username: &str, locked: bool) -> Result<bool>;
+    /// Every user with their chat message count, by name.
+    async fn users(&self) -> Result<Vec<UserSummary>>;
+    /// Sets `username`'s last-seen time to now; called when a session starts and ends.
+    async fn mark_seen(&self, username: &str) -> Result<()>;
+    async fn set_public_key(&self, username: &str, public_key: &[u8]) -> Result<()>;
+    async fn public_key(&self, username: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Stores a chat message, answering `reply_to` if set, and returns its id.
+    async fn save_message(
+        &self,
+        username: &str,
+        content: &str,
+        reply_to: Option<i64>,
+    ) -> Result<i64>;
+    async fn message(&self, id: i64) -> Result<Option<StoredMessage>>;
+    /// The thread `id` belongs to: its oldest remaining ancestor and every
+    /// reply below that, oldest first.
+    async fn thread(&self, id: i64) -> Result<Vec<StoredMessage>>;
+    /// The latest `limit` chat messages, oldest first.
+    async fn recent_messages(&self, limit: i64) -> Result<Vec<StoredMessage>>;
+    /// Chat messages matching `filter`, oldest first.
+    async fn messages(&self, filter: &TranscriptFilter) -> Result<Vec<StoredMessage>>;
+    /// Chat and sent direct messages of `username`.
+    async fn count_user_messages(&self, username: &str) -> Result<UserMessages>;
+    /// Deletes the chat messages and sent direct messages of `username` at once.
+    async fn purge_user_messages(&self, username: &str) -> Result<UserMessages>;
+    /// Deletes up to `batch` chat messages older than `days` days and returns how many.
+    async fn delete_messages_older_than(&self, days: u64, batch: u64) -> Result<u64>;
+    /// Deletes up to `batch` of the oldest chat messages beyond the newest `keep`.
+    /// Returns the timestamps of the deleted messages.
+    async fn delete_messages_beyond(&self, keep: u64, batch: u64) -> Result<Vec<String>>;
+    /// Records that chat message `message_id` mentions `usernames`, as unread.
+    /// Mentions go when their message is deleted.
+    async fn record_mentions(&self, message_id: i64, usernames: &[String]) -> Result<()>;
+    /// Chat messages mentioning `username` that they have not read, oldest first.
+    async fn unread_mentions(&self, username: &str) -> Result<Vec<StoredMessage>>;
+    /// Marks the mentions of `username` up to chat message `up_to` as read; returns how many.
+    async fn mark_mentions_read(&self, username: &str, up_to: i64) -> Result<u64>;
+
+    /// Stores a direct message in the `queued` state and returns its id.
+    async fn queue_direct_message(
+        &self,
+        sender: &str,
+        recipient: &str,
+        content: &str,
+        sealed: Option<&[u8]>,
+    ) -> Result<i64>;
+    async fn mark_direct_message_delivered(&self, id: i64) -> Result<()>;
+    /// Marks messages queued for `recipient` longer than `ttl_secs` as expired.
+    async fn expire_direct_messages(&self, recipient: &str, ttl_secs: u64) -> Result<()>;
+    async fn count_queued_direct_messages(&self, recipient: &str) -> Result<i64>;
+    /// Messages queued for `recipient`, oldest first.
+    async fn queued_direct_messages(&self, recipient: &str) -> Result<Vec<QueuedDirectMessage>>;
+
+    async fn record_attachment(&self, username: &str, name: &str, path: &str) -> Result<()>;
+    /// Attachments matching `filter`, oldest first.
+    async fn attachments(&self, filter: &TranscriptFilter) -> Result<Vec<StoredAttachment>>;
+    /// Deletes up to `batch` attachment records older than `days` days (unless 0) or,
+    /// when `before` is set, sent before that timestamp.
+    /// Returns the storage paths of the deleted records.
+    async fn delete_expired_attachments(
+        &self,
+        days: u64,
+        before: Option<&str>,
+        batch: u64,
+    ) -> Result<Vec<String>>;
+    /// Whether any attachment record still points at `path`.
+    async fn attachment_referenced(&self, path: &str) -> Result<bool>;
+
+    async fn record_audit(&self, event: &AuditEvent) -> Result<()>;
+    /// The latest audit events matching `filter`, oldest first.
+    async fn audit_events(&self, filter: &AuditFilter) -> Result<Vec<StoredAuditEvent>>;
+    /// Deletes audit events older than `days` days and returns how many.
+    async fn prune_audit_events(&self, days: u64) -> Result<u64>;
+
+    async fn stats(&self) -> Result<DatabaseStats>;
+    /// Gives the space of deleted rows back to the file system, where that applies.
+    async fn vacuum(&self) -> Result<()>;
+}
+
+/// Database URL schemes and the backend each selects.
+pub const URL_SCHEMES: [&str; 4] = ["sqlite:", "postgres://", "postgresql://", "memory:"];
+
+/// Opens the backend named by the URL scheme: `sqlite:`, `postgres://`
+/// (or `postgresql://`) or `memory:`.
+pub async fn open(url: &str) -> Result<Box<dyn Storage>> {
+    if url.starts_with("sqlite:") {
+        Ok(Box::new(SqliteStorage::connect(url).await?))
+    } else if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(Box::new(PostgresStorage::connect(url).await?))
+    } else if url.starts_with("memory:") {
+        Ok(Box::new(MemoryStorage::default()))
+    } else {
+        bail!("Unsupported database URL {url}, use sqlite:, postgres:// or memory:")
+    }
+}
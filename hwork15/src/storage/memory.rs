@@ -0,0 +1,541 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::{Credentials, Storage};
+use crate::audit::{AuditEvent, AuditFilter};
+use crate::db::{
+    DatabaseStats, QueuedDirectMessage, StoredAttachment, StoredAuditEvent, StoredMessage,
+    UserMessages, UserSummary,
+};
+use crate::export::TranscriptFilter;
+
+/// Keeps everything in process memory, for tests and throwaway servers
+/// (`memory:`). Nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>,
+}
+
+#[derive(Default)]
+struct Tables {
+    users: BTreeMap<String, User>,
+    public_keys: HashMap<String, Vec<u8>>,
+    messages: Vec<StoredMessage>,
+    direct_messages: Vec<DirectMessage>,
+    attachments: Vec<StoredAttachment>,
+    audit_events: Vec<StoredAuditEvent>,
+    mentions: Vec<Mention>,
+    /// Last id handed out, by table.
+    last_ids: HashMap<&'static str, i64>,
+}
+
+impl Tables {
+    fn next_id(&mut self, table: &'static str) -> i64 {
+        let id = self.last_ids.entry(table).or_default();
+        *id += 1;
+        *id
+    }
+
+    /// Drops the mentions of deleted chat messages.
+    fn forget_mentions(&mut self) {
+        let messages: HashSet<i64> = self.messages.iter().map(|m| m.id).collect();
+        self.mentions
+            .retain(|mention| messages.contains(&mention.message_id));
+    }
+}
+
+struct User {
+    id: i64,
+    password_hash: String,
+    locked: bool,
+    last_seen: Option<String>,
+}
+
+struct Mention {
+    message_id: i64,
+    username: String,
+    read: bool,
+}
+
+struct DirectMessage {
+    message: QueuedDirectMessage,
+    recipient: String,
+    state: &'static str,
+}
+
+/// Current UTC time minus `seconds`, formatted like SQLite's `CURRENT_TIMESTAMP`.
+fn timestamp_ago(seconds: u64) -> String {
+    (Utc::now() - Duration::seconds(seconds as i64))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn now() -> String {
+    timestamp_ago(0)
+}
+
+fn matches(filter: &TranscriptFilter, username: &str, timestamp: &str) -> bool {
+    filter.user.as_deref().is_none_or(|user| user == username)
+        && filter
+            .since
+            .as_deref()
+            .is_none_or(|since| timestamp >= since)
+        && filter
+            .until
+            .as_deref()
+            .is_none_or(|until| timestamp < until)
+}
+
+/// Removes and returns up to `limit` items matching `pred`, oldest first.
+fn remove_first<T>(items: &mut Vec<T>, limit: u64, pred: impl Fn(&T) -> bool) -> Vec<T> {
+    let mut removed = Vec::new();
+    let mut kept = Vec::with_capacity(items.len());
+    for item in items.drain(..) {
+        if (removed.len() as u64) < limit && pred(&item) {
+            removed.push(item);
+        } else {
+            kept.push(item);
+        }
+    }
+    *items = kept;
+    removed
+}
+
+impl MemoryStorage {
+    fn tables(&self) -> std::sync::MutexGuard<'_, Tables> {
+        self.tables.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    /// Pretends every direct message was queued `seconds` ago.
+    pub(crate) fn queued_ago(&self, seconds: u64) {
+        for direct in self.tables().direct_messages.iter_mut() {
+            direct.message.timestamp = timestamp_ago(seconds);
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn create_user(&self, username: &str, password_hash: &str) -> Result<()> {
+        let mut tables = self.tables();
+        if tables.users.contains_key(username) {
+            bail!("User {username} already exists");
+        }
+        let id = tables.next_id("users");
+        tables.users.insert(
+            username.to_string(),
+            User {
+                id,
+                password_hash: password_hash.to_string(),
+                locked: false,
+                last_seen: None,
+            },
+        );
+        Ok(())
+    }
+
+    async fn user_exists(&self, username: &str) -> Result<bool> {
+        Ok(self.tables().users.contains_key(username))
+    }
+
+    async fn credentials(&self, username: &str) -> Result<Option<Credentials>> {
+        Ok(self.tables().users.get(username).map(|user| Credentials {
+            id: user.id,
+            password_hash: user.password_hash.clone(),
+            locked: user.locked,
+        }))
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<bool> {
+        Ok(match self.tables().users.get_mut(username) {
+            Some(user) => {
+                user.password_hash = password_hash.to_string();
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn set_locked(&self, username: &str, locked: bool) -> Result<bool> {
+        Ok(match self.tables().users.get_mut(username) {
+            Some(user) => {
+                user.locked = locked;
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn users(&self) -> Result<Vec<UserSummary>> {
+        let tables = self.tables();
+        Ok(tables
+            .users
+            .iter()
+            .map(|(username, user)| UserSummary {
+                username: username.clone(),
+                locked: user.locked,
+                last_seen: user.last_seen.clone(),
+                messages: tables
+                    .messages
+                    .iter()
+                    .filter(|m| &m.username == username)
+                    .count() as i64,
+            })
+            .collect())
+    }
+
+    async fn mark_seen(&self, username: &str) -> Result<()> {
+        if let Some(user) = self.tables().users.get_mut(username) {
+            user.last_seen = Some(now());
+        }
+        Ok(())
+    }
+
+    async fn set_public_key(&self, username: &str, public_key: &[u8]) -> Result<()> {
+        self.tables()
+            .public_keys
+            .insert(username.to_string(), public_key.to_vec());
+        Ok(())
+    }
+
+    async fn public_key(&self, username: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.tables().public_keys.get(username).cloned())
+    }
+
+    async fn save_message(
+        &self,
+        username: &str,
+        content: &str,
+        reply_to: Option<i64>,
+    ) -> Result<i64> {
+        let mut tables = self.tables();
+        let id = tables.next_id("messages");
+        tables.messages.push(StoredMessage {
+            id,
+            username: username.to_string(),
+            content: content.to_string(),
+            timestamp: now(),
+            reply_to,
+        });
+        Ok(id)
+    }
+
+    async fn message(&self, id: i64) -> Result<Option<StoredMessage>> {
+        Ok(self.tables().messages.iter().find(|m| m.id == id).cloned())
+    }
+
+    async fn thread(&self, id: i64) -> Result<Vec<StoredMessage>> {
+        let tables = self.tables();
+        let by_id: HashMap<i64, &StoredMessage> =
+            tables.messages.iter().map(|m| (m.id, m)).collect();
+        let Some(mut root) = by_id.get(&id).copied() else {
+            return Ok(Vec::new());
+        };
+        while let Some(parent) = root.reply_to.and_then(|parent| by_id.get(&parent)) {
+            root = parent;
+        }
+        // Messages are kept in id order, so every reply comes after its parent.
+        let mut thread = HashSet::from([root.id]);
+        let mut messages = Vec::new();
+        for message in tables.messages.iter().filter(|m| m.id >= root.id) {
+            if message.id == root.id || message.reply_to.is_some_and(|p| thread.contains(&p)) {
+                thread.insert(message.id);
+                messages.push(message.clone());
+            }
+        }
+        Ok(messages)
+    }
+
+    async fn recent_messages(&self, limit: i64) -> Result<Vec<StoredMessage>> {
+        let tables = self.tables();
+        let skip = tables.messages.len().saturating_sub(limit.max(0) as usize);
+        Ok(tables.messages[skip..].to_vec())
+    }
+
+    async fn messages(&self, filter: &TranscriptFilter) -> Result<Vec<StoredMessage>> {
+        Ok(self
+            .tables()
+            .messages
+            .iter()
+            .filter(|m| matches(filter, &m.username, &m.timestamp))
+            .cloned()
+            .collect())
+    }
+
+    async fn count_user_messages(&self, username: &str) -> Result<UserMessages> {
+        let tables = self.tables();
+        Ok(UserMessages {
+            chat: tables
+                .messages
+                .iter()
+                .filter(|m| m.username == username)
+                .count() as u64,
+            direct: tables
+                .direct_messages
+                .iter()
+                .filter(|d| d.message.sender == username)
+                .count() as u64,
+        })
+    }
+
+    async fn purge_user_messages(&self, username: &str) -> Result<UserMessages> {
+        let mut tables = self.tables();
+        let chat = remove_first(&mut tables.messages, u64::MAX, |m| m.username == username);
+        let direct = remove_first(&mut tables.direct_messages, u64::MAX, |d| {
+            d.message.sender == username
+        });
+        tables.forget_mentions();
+        Ok(UserMessages {
+            chat: chat.len() as u64,
+            direct: direct.len() as u64,
+        })
+    }
+
+    async fn delete_messages_older_than(&self, days: u64, batch: u64) -> Result<u64> {
+        let cutoff = timestamp_ago(days * 24 * 60 * 60);
+        let mut tables = self.tables();
+        let removed = remove_first(&mut tables.messages, batch, |m| m.timestamp < cutoff);
+        tables.forget_mentions();
+        Ok(removed.len() as u64)
+    }
+
+    async fn delete_messages_beyond(&self, keep: u64, batch: u64) -> Result<Vec<String>> {
+        let mut tables = self.tables();
+        let excess = (tables.messages.len() as u64).saturating_sub(keep);
+        let removed = excess.min(batch) as usize;
+        let timestamps = tables
+            .messages
+            .drain(..removed)
+            .map(|m| m.timestamp)
+            .collect();
+        tables.forget_mentions();
+        Ok(timestamps)
+    }
+
+    async fn record_mentions(&self, message_id: i64, usernames: &[String]) -> Result<()> {
+        let mut tables = self.tables();
+        for username in usernames {
+            let known = tables
+                .mentions
+                .iter()
+                .any(|m| m.message_id == message_id && &m.username == username);
+            if !known {
+                tables.mentions.push(Mention {
+                    message_id,
+                    username: username.clone(),
+                    read: false,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn unread_mentions(&self, username: &str) -> Result<Vec<StoredMessage>> {
+        let tables = self.tables();
+        let unread: HashSet<i64> = tables
+            .mentions
+            .iter()
+            .filter(|m| m.username == username && !m.read)
+            .map(|m| m.message_id)
+            .collect();
+        Ok(tables
+            .messages
+            .iter()
+            .filter(|m| unread.contains(&m.id))
+            .cloned()
+            .collect())
+    }
+
+    async fn mark_mentions_read(&self, username: &str, up_to: i64) -> Result<u64> {
+        let mut marked = 0;
+        for mention in self.tables().mentions.iter_mut() {
+            if mention.username == username && mention.message_id <= up_to && !mention.read {
+                mention.read = true;
+                marked += 1;
+            }
+        }
+        Ok(marked)
+    }
+
+    async fn queue_direct_message(
+        &self,
+        sender: &str,
+        recipient: &str,
+        content: &str,
+        sealed: Option<&[u8]>,
+    ) -> Result<i64> {
+        let mut tables = self.tables();
+        let id = tables.next_id("direct_messages");
+        tables.direct_messages.push(DirectMessage {
+            message: QueuedDirectMessage {
+                id,
+                sender: sender.to_string(),
+                content: content.to_string(),
+                sealed: sealed.map(<[u8]>::to_vec),
+                timestamp: now(),
+            },
+            recipient: recipient.to_string(),
+            state: "queued",
+        });
+        Ok(id)
+    }
+
+    async fn mark_direct_message_delivered(&self, id: i64) -> Result<()> {
+        let mut tables = self.tables();
+        if let Some(direct) = tables
+            .direct_messages
+            .iter_mut()
+            .find(|d| d.message.id == id)
+        {
+            direct.state = "delivered";
+        }
+        Ok(())
+    }
+
+    async fn expire_direct_messages(&self, recipient: &str, ttl_secs: u64) -> Result<()> {
+        let cutoff = timestamp_ago(ttl_secs);
+        for direct in self.tables().direct_messages.iter_mut() {
+            if direct.recipient == recipient
+                && direct.state == "queued"
+                && direct.message.timestamp < cutoff
+            {
+                direct.state = "expired";
+            }
+        }
+        Ok(())
+    }
+
+    async fn count_queued_direct_messages(&self, recipient: &str) -> Result<i64> {
+        Ok(self
+            .tables()
+            .direct_messages
+            .iter()
+            .filter(|d| d.recipient == recipient && d.state == "queued")
+            .count() as i64)
+    }
+
+    async fn queued_direct_messages(&self, recipient: &str) -> Result<Vec<QueuedDirectMessage>> {
+        Ok(self
+            .tables()
+            .direct_messages
+            .iter()
+            .filter(|d| d.recipient == recipient && d.state == "queued")
+            .map(|d| d.message.clone())
+            .collect())
+    }
+
+    async fn record_attachment(&self, username: &str, name: &str, path: &str) -> Result<()> {
+        let mut tables = self.tables();
+        let id = tables.next_id("attachments");
+        tables.attachments.push(StoredAttachment {
+            id,
+            username: username.to_string(),
+            name: name.to_string(),
+            path: path.to_string(),
+            timestamp: now(),
+        });
+        Ok(())
+    }
+
+    async fn attachments(&self, filter: &TranscriptFilter) -> Result<Vec<StoredAttachment>> {
+        Ok(self
+            .tables()
+            .attachments
+            .iter()
+            .filter(|a| matches(filter, &a.username, &a.timestamp))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_expired_attachments(
+        &self,
+        days: u64,
+        before: Option<&str>,
+        batch: u64,
+    ) -> Result<Vec<String>> {
+        let mut tables = self.tables();
+        let cutoff = (days > 0).then(|| timestamp_ago(days * 24 * 60 * 60));
+        let removed = remove_first(&mut tables.attachments, batch, |a| {
+            cutoff.as_ref().is_some_and(|cutoff| &a.timestamp < cutoff)
+                || before.is_some_and(|before| a.timestamp.as_str() < before)
+        });
+        Ok(removed.into_iter().map(|a| a.path).collect())
+    }
+
+    async fn attachment_referenced(&self, path: &str) -> Result<bool> {
+        Ok(self.tables().attachments.iter().any(|a| a.path == path))
+    }
+
+    async fn record_audit(&self, event: &AuditEvent) -> Result<()> {
+        let mut tables = self.tables();
+        let id = tables.next_id("audit_events");
+        tables.audit_events.push(StoredAuditEvent {
+            id,
+            timestamp: now(),
+            actor: event.actor.clone(),
+            peer: event.peer.to_string(),
+            action: event.action.as_str().to_string(),
+            target: event.target.clone(),
+            outcome: event.outcome.as_str().to_string(),
+            detail: event.detail.clone(),
+        });
+        Ok(())
+    }
+
+    async fn audit_events(&self, filter: &AuditFilter) -> Result<Vec<StoredAuditEvent>> {
+        let tables = self.tables();
+        let events: Vec<&StoredAuditEvent> = tables
+            .audit_events
+            .iter()
+            .filter(|e| filter.actor.as_ref().is_none_or(|actor| &e.actor == actor))
+            .filter(|e| {
+                filter
+                    .action
+                    .as_ref()
+                    .is_none_or(|action| &e.action == action)
+            })
+            .collect();
+        let skip = match filter.limit {
+            Some(limit) => events.len().saturating_sub(limit.max(0) as usize),
+            None => 0,
+        };
+        Ok(events[skip..].iter().map(|&e| e.clone()).collect())
+    }
+
+    async fn prune_audit_events(&self, days: u64) -> Result<u64> {
+        let cutoff = timestamp_ago(days * 24 * 60 * 60);
+        let removed = remove_first(&mut self.tables().audit_events, u64::MAX, |e| {
+            e.timestamp < cutoff
+        });
+        Ok(removed.len() as u64)
+    }
+
+    async fn stats(&self) -> Result<DatabaseStats> {
+        let tables = self.tables();
+        let mut direct_messages = BTreeMap::new();
+        for direct in &tables.direct_messages {
+            *direct_messages.entry(direct.state.to_string()).or_insert(0) += 1;
+        }
+        Ok(DatabaseStats {
+            users: tables.users.len() as i64,
+            locked_users: tables.users.values().filter(|u| u.locked).count() as i64,
+            messages: tables.messages.len() as i64,
+            oldest_message: tables.messages.iter().map(|m| m.timestamp.clone()).min(),
+            newest_message: tables.messages.iter().map(|m| m.timestamp.clone()).max(),
+            direct_messages: direct_messages.into_iter().collect(),
+            attachments: tables.attachments.len() as i64,
+            audit_events: tables.audit_events.len() as i64,
+            size_bytes: 0,
+        })
+    }
+
+    async fn vacuum(&self) -> Result<()> {
+        Ok(())
+    }
+}
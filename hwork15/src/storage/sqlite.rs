@@ -0,0 +1,713 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::SqlitePool;
+
+use super::{Credentials, Storage};
+use crate::audit::{AuditEvent, AuditFilter};
+use crate::db::{
+    DatabaseStats, QueuedDirectMessage, StoredAttachment, StoredAuditEvent, StoredMessage,
+    UserMessages, UserSummary,
+};
+use crate::export::TranscriptFilter;
+
+/// The SQLite database the server has always used, e.g. `sqlite:./db.sqlite`.
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `database_url`; `sqlite::memory:` gives a private
+    /// in-memory database that lives as long as the returned value.
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let mut options = SqlitePoolOptions::new();
+        if database_url.contains(":memory:") || database_url.contains("mode=memory") {
+            // The database disappears with its last connection, so never close one.
+            options = options
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+        let pool = options.connect(database_url).await?;
+        let storage = Self { pool };
+        storage.create_tables().await?;
+        Ok(storage)
+    }
+
+    async fn create_tables(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL UNIQUE,
+                password_hash TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL,
+                content TEXT NOT NULL,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+
+            CREATE TABLE IF NOT EXISTS direct_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sender TEXT NOT NULL,
+                recipient TEXT NOT NULL,
+                content TEXT NOT NULL,
+                state TEXT NOT NULL DEFAULT 'queued',
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+                delivered_at DATETIME
+            );
+
+            CREATE INDEX IF NOT EXISTS direct_messages_queue
+                ON direct_messages (recipient, state, id);
+
+            CREATE TABLE IF NOT EXISTS public_keys (
+                username TEXT PRIMARY KEY,
+                public_key BLOB NOT NULL,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+
+            CREATE TABLE IF NOT EXISTS attachments (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL,
+                name TEXT NOT NULL,
+                path TEXT NOT NULL,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+
+            CREATE TABLE IF NOT EXISTS audit_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+                actor TEXT NOT NULL,
+                peer TEXT NOT NULL,
+                action TEXT NOT NULL,
+                target TEXT,
+                outcome TEXT NOT NULL,
+                detail TEXT
+            );
+
+            CREATE INDEX IF NOT EXISTS audit_events_timestamp
+                ON audit_events (timestamp);
+
+            CREATE TABLE IF NOT EXISTS mentions (
+                message_id INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+                username TEXT NOT NULL,
+                read INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (username, message_id)
+            );
+
+            CREATE INDEX IF NOT EXISTS mentions_message
+                ON mentions (message_id);
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        self.add_column_if_missing("direct_messages", "sealed", "BLOB")
+            .await?;
+        self.add_column_if_missing("users", "locked", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        self.add_column_if_missing("users", "last_seen", "DATETIME")
+            .await?;
+        self.add_column_if_missing("messages", "reply_to", "INTEGER")
+            .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS messages_reply_to ON messages (reply_to)")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Adds a column that databases created by older versions lack.
+    async fn add_column_if_missing(&self, table: &str, column: &str, decl: &str) -> Result<()> {
+        let exists: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM pragma_table_info(?) WHERE name = ?)
+            "#,
+        )
+        .bind(table)
+        .bind(column)
+        .fetch_one(&self.pool)
+        .await?;
+        if !exists {
+            sqlx::query(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn create_user(&self, username: &str, password_hash: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO users (username, password_hash) VALUES (?, ?)
+            "#,
+        )
+        .bind(username)
+        .bind(password_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn user_exists(&self, username: &str) -> Result<bool> {
+        let exists: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM users WHERE username = ?)
+            "#,
+        )
+        .bind(username)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(exists)
+    }
+
+    async fn credentials(&self, username: &str) -> Result<Option<Credentials>> {
+        let credentials = sqlx::query_as(
+            r#"
+            SELECT id, password_hash, locked FROM users WHERE username = ?
+            "#,
+        )
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(credentials)
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE users SET password_hash = ? WHERE username = ?
+            "#,
+        )
+        .bind(password_hash)
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_locked(&self, username: &str, locked: bool) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE users SET locked = ? WHERE username = ?
+            "#,
+        )
+        .bind(locked)
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn users(&self) -> Result<Vec<UserSummary>> {
+        let users = sqlx::query_as(
+            r#"
+            SELECT u.username, u.locked, CAST(u.last_seen AS TEXT) AS last_seen,
+                   (SELECT COUNT(*) FROM messages m WHERE m.username = u.username) AS messages
+            FROM users u
+            ORDER BY u.username
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(users)
+    }
+
+    async fn mark_seen(&self, username: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE users SET last_seen = CURRENT_TIMESTAMP WHERE username = ?
+            "#,
+        )
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_public_key(&self, username: &str, public_key: &[u8]) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO public_keys (username, public_key) VALUES (?, ?)
+            ON CONFLICT (username)
+            DO UPDATE SET public_key = excluded.public_key, updated_at = CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(username)
+        .bind(public_key)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn public_key(&self, username: &str) -> Result<Option<Vec<u8>>> {
+        let key = sqlx::query_scalar(
+            r#"
+            SELECT public_key FROM public_keys WHERE username = ?
+            "#,
+        )
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(key)
+    }
+
+    async fn save_message(
+        &self,
+        username: &str,
+        content: &str,
+        reply_to: Option<i64>,
+    ) -> Result<i64> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO messages (username, content, reply_to)
+            VALUES (?, ?, ?)
+            "#,
+        )
+        .bind(username)
+        .bind(content)
+        .bind(reply_to)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    async fn message(&self, id: i64) -> Result<Option<StoredMessage>> {
+        let message = sqlx::query_as(
+            r#"
+            SELECT id, username, content, CAST(timestamp AS TEXT) AS timestamp, reply_to
+            FROM messages
+            WHERE id = ?
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(message)
+    }
+
+    async fn thread(&self, id: i64) -> Result<Vec<StoredMessage>> {
+        // Replies always come after what they answer, so the lowest id up the
+        // chain is the root, even when retention deleted the ones above it.
+        let messages = sqlx::query_as(
+            r#"
+            WITH RECURSIVE
+                ancestors (id, reply_to) AS (
+                    SELECT id, reply_to FROM messages WHERE id = ?
+                    UNION
+                    SELECT m.id, m.reply_to FROM messages m JOIN ancestors a ON m.id = a.reply_to
+                ),
+                thread (id) AS (
+                    SELECT MIN(id) FROM ancestors
+                    UNION
+                    SELECT m.id FROM messages m JOIN thread t ON m.reply_to = t.id
+                )
+            SELECT id, username, content, CAST(timestamp AS TEXT) AS timestamp, reply_to
+            FROM messages
+            WHERE id IN (SELECT id FROM thread)
+            ORDER BY id
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    async fn recent_messages(&self, limit: i64) -> Result<Vec<StoredMessage>> {
+        let mut messages: Vec<StoredMessage> = sqlx::query_as(
+            r#"
+            SELECT id, username, content, CAST(timestamp AS TEXT) AS timestamp, reply_to
+            FROM messages
+            ORDER BY id DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn messages(&self, filter: &TranscriptFilter) -> Result<Vec<StoredMessage>> {
+        let messages = sqlx::query_as(
+            r#"
+            SELECT id, username, content, CAST(timestamp AS TEXT) AS timestamp, reply_to
+            FROM messages
+            WHERE (?1 IS NULL OR username = ?1)
+              AND (?2 IS NULL OR timestamp >= ?2)
+              AND (?3 IS NULL OR timestamp < ?3)
+            ORDER BY id
+            "#,
+        )
+        .bind(&filter.user)
+        .bind(&filter.since)
+        .bind(&filter.until)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    async fn count_user_messages(&self, username: &str) -> Result<UserMessages> {
+        let (chat, direct): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT (SELECT COUNT(*) FROM messages WHERE username = ?1),
+                   (SELECT COUNT(*) FROM direct_messages WHERE sender = ?1)
+            "#,
+        )
+        .bind(username)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(UserMessages {
+            chat: chat as u64,
+            direct: direct as u64,
+        })
+    }
+
+    async fn purge_user_messages(&self, username: &str) -> Result<UserMessages> {
+        let mut tx = self.pool.begin().await?;
+        let chat = sqlx::query("DELETE FROM messages WHERE username = ?")
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+        let direct = sqlx::query("DELETE FROM direct_messages WHERE sender = ?")
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(UserMessages {
+            chat: chat.rows_affected(),
+            direct: direct.rows_affected(),
+        })
+    }
+
+    async fn delete_messages_older_than(&self, days: u64, batch: u64) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM messages WHERE id IN (
+                SELECT id FROM messages WHERE timestamp < datetime('now', ?) ORDER BY id LIMIT ?
+            )
+            "#,
+        )
+        .bind(format!("-{} days", days))
+        .bind(batch as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_messages_beyond(&self, keep: u64, batch: u64) -> Result<Vec<String>> {
+        let timestamps = sqlx::query_scalar(
+            r#"
+            DELETE FROM messages WHERE id IN (
+                SELECT id FROM messages
+                WHERE id < (SELECT id FROM messages ORDER BY id DESC LIMIT 1 OFFSET ?1 - 1)
+                ORDER BY id LIMIT ?2
+            )
+            RETURNING CAST(timestamp AS TEXT)
+            "#,
+        )
+        .bind(keep as i64)
+        .bind(batch as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(timestamps)
+    }
+
+    async fn record_mentions(&self, message_id: i64, usernames: &[String]) -> Result<()> {
+        for username in usernames {
+            sqlx::query(
+                r#"
+                INSERT INTO mentions (message_id, username) VALUES (?, ?)
+                ON CONFLICT DO NOTHING
+                "#,
+            )
+            .bind(message_id)
+            .bind(username)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn unread_mentions(&self, username: &str) -> Result<Vec<StoredMessage>> {
+        let messages = sqlx::query_as(
+            r#"
+            SELECT m.id, m.username, m.content, CAST(m.timestamp AS TEXT) AS timestamp,
+                   m.reply_to
+            FROM mentions n JOIN messages m ON m.id = n.message_id
+            WHERE n.username = ? AND n.read = 0
+            ORDER BY m.id
+            "#,
+        )
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    async fn mark_mentions_read(&self, username: &str, up_to: i64) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            UPDATE mentions SET read = 1
+            WHERE username = ? AND message_id <= ? AND read = 0
+            "#,
+        )
+        .bind(username)
+        .bind(up_to)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn queue_direct_message(
+        &self,
+        sender: &str,
+        recipient: &str,
+        content: &str,
+        sealed: Option<&[u8]>,
+    ) -> Result<i64> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO direct_messages (sender, recipient, content, sealed)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(sender)
+        .bind(recipient)
+        .bind(content)
+        .bind(sealed)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    async fn mark_direct_message_delivered(&self, id: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE direct_messages
+            SET state = 'delivered', delivered_at = CURRENT_TIMESTAMP
+            WHERE id = ?
+            "#,
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn expire_direct_messages(&self, recipient: &str, ttl_secs: u64) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE direct_messages SET state = 'expired'
+            WHERE recipient = ? AND state = 'queued' AND timestamp < datetime('now', ?)
+            "#,
+        )
+        .bind(recipient)
+        .bind(format!("-{} seconds", ttl_secs))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn count_queued_direct_messages(&self, recipient: &str) -> Result<i64> {
+        let count: i64 = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM direct_messages WHERE recipient = ? AND state = 'queued'
+            "#,
+        )
+        .bind(recipient)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+
+    async fn queued_direct_messages(&self, recipient: &str) -> Result<Vec<QueuedDirectMessage>> {
+        let messages = sqlx::query_as(
+            r#"
+            SELECT id, sender, content, sealed, CAST(timestamp AS TEXT) AS timestamp
+            FROM direct_messages
+            WHERE recipient = ? AND state = 'queued'
+            ORDER BY id
+            "#,
+        )
+        .bind(recipient)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    async fn record_attachment(&self, username: &str, name: &str, path: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO attachments (username, name, path) VALUES (?, ?, ?)
+            "#,
+        )
+        .bind(username)
+        .bind(name)
+        .bind(path)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn attachments(&self, filter: &TranscriptFilter) -> Result<Vec<StoredAttachment>> {
+        let attachments = sqlx::query_as(
+            r#"
+            SELECT id, username, name, path, CAST(timestamp AS TEXT) AS timestamp
+            FROM attachments
+            WHERE (?1 IS NULL OR username = ?1)
+              AND (?2 IS NULL OR timestamp >= ?2)
+              AND (?3 IS NULL OR timestamp < ?3)
+            ORDER BY id
+            "#,
+        )
+        .bind(&filter.user)
+        .bind(&filter.since)
+        .bind(&filter.until)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(attachments)
+    }
+
+    async fn delete_expired_attachments(
+        &self,
+        days: u64,
+        before: Option<&str>,
+        batch: u64,
+    ) -> Result<Vec<String>> {
+        let paths = sqlx::query_scalar(
+            r#"
+            DELETE FROM attachments WHERE id IN (
+                SELECT id FROM attachments
+                WHERE (?1 > 0 AND timestamp < datetime('now', '-' || ?1 || ' days'))
+                   OR (?2 IS NOT NULL AND timestamp < ?2)
+                ORDER BY id LIMIT ?3
+            )
+            RETURNING path
+            "#,
+        )
+        .bind(days as i64)
+        .bind(before)
+        .bind(batch as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(paths)
+    }
+
+    async fn attachment_referenced(&self, path: &str) -> Result<bool> {
+        let referenced = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM attachments WHERE path = ?)
+            "#,
+        )
+        .bind(path)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(referenced)
+    }
+
+    async fn record_audit(&self, event: &AuditEvent) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO audit_events (actor, peer, action, target, outcome, detail)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&event.actor)
+        .bind(event.peer.to_string())
+        .bind(event.action.as_str())
+        .bind(&event.target)
+        .bind(event.outcome.as_str())
+        .bind(&event.detail)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn audit_events(&self, filter: &AuditFilter) -> Result<Vec<StoredAuditEvent>> {
+        let mut events: Vec<StoredAuditEvent> = sqlx::query_as(
+            r#"
+            SELECT id, CAST(timestamp AS TEXT) AS timestamp, actor, peer, action, target,
+                   outcome, detail
+            FROM audit_events
+            WHERE (?1 IS NULL OR actor = ?1) AND (?2 IS NULL OR action = ?2)
+            ORDER BY id DESC
+            LIMIT ?3
+            "#,
+        )
+        .bind(&filter.actor)
+        .bind(&filter.action)
+        // SQLite treats a negative limit as no limit.
+        .bind(filter.limit.unwrap_or(-1))
+        .fetch_all(&self.pool)
+        .await?;
+        events.reverse();
+        Ok(events)
+    }
+
+    async fn prune_audit_events(&self, days: u64) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM audit_events WHERE timestamp < datetime('now', ?)
+            "#,
+        )
+        .bind(format!("-{} days", days))
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn stats(&self) -> Result<DatabaseStats> {
+        let (users, locked_users): (i64, i64) =
+            sqlx::query_as("SELECT COUNT(*), COALESCE(SUM(locked), 0) FROM users")
+                .fetch_one(&self.pool)
+                .await?;
+        let (messages, oldest_message, newest_message): (i64, Option<String>, Option<String>) =
+            sqlx::query_as(
+                r#"
+                SELECT COUNT(*), CAST(MIN(timestamp) AS TEXT), CAST(MAX(timestamp) AS TEXT)
+                FROM messages
+                "#,
+            )
+            .fetch_one(&self.pool)
+            .await?;
+        let direct_messages = sqlx::query_as(
+            "SELECT state, COUNT(*) FROM direct_messages GROUP BY state ORDER BY state",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let attachments = sqlx::query_scalar("SELECT COUNT(*) FROM attachments")
+            .fetch_one(&self.pool)
+            .await?;
+        let audit_events = sqlx::query_scalar("SELECT COUNT(*) FROM audit_events")
+            .fetch_one(&self.pool)
+            .await?;
+        let size_bytes = sqlx::query_scalar(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(DatabaseStats {
+            users,
+            locked_users,
+            messages,
+            oldest_message,
+            newest_message,
+            direct_messages,
+            attachments,
+            audit_events,
+            size_bytes,
+        })
+    }
+
+    async fn vacuum(&self) -> Result<()> {
+        sqlx::query("VACUUM").execute(&self.pool).await?;
+        Ok(())
+    }
+}
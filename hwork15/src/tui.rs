@@ -0,0 +1,724 @@
+use anyhow::{Context, Result};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use futures_util::StreamExt;
+use hwork15::{
+    receive_message_within, send_message, ClientMessage, MessageId, MessageType, ReceiptStatus,
+    ResponseType,
+};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph};
+use ratatui::{Frame, Terminal};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{stdout, Stdout};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::client_utils::{ring_bell, send_receipt};
+use crate::downloads::{Downloads, FileOutcome};
+use crate::e2e::{E2e, Notice, Outgoing};
+
+enum AuthState {
+    LoggedOut,
+    Pending(String),
+    LoggedIn(String),
+}
+
+enum LineKind {
+    Chat,
+    /// A chat message mentioning this user.
+    Mention,
+    Direct,
+    Own,
+    Info,
+    Error,
+}
+
+struct ChatLine {
+    kind: LineKind,
+    text: String,
+    /// Delivery state of a message this client sent.
+    delivery: Option<Delivery>,
+}
+
+#[derive(Default)]
+struct Delivery {
+    /// `None` until the server acks or rejects the message.
+    stored: Option<Result<(), String>>,
+    delivered: Vec<String>,
+    read: Vec<String>,
+}
+
+impl Delivery {
+    fn status(&self) -> String {
+        let mut status = match &self.stored {
+            None => "…".to_string(),
+            Some(Ok(())) => "✓".to_string(),
+            Some(Err(reason)) => format!("✗ {reason}"),
+        };
+        if !self.delivered.is_empty() {
+            status.push_str(&format!(" delivered: {}", self.delivered.join(", ")));
+        }
+        if !self.read.is_empty() {
+            status.push_str(&format!(" read: {}", self.read.join(", ")));
+        }
+        status
+    }
+}
+
+struct App {
+    server_addr: SocketAddr,
+    connected: bool,
+    auth: AuthState,
+    history: Vec<ChatLine>,
+    input: String,
+    /// Cursor position in `input`, in chars.
+    cursor: usize,
+    /// How many lines the chat pane is scrolled up from the bottom.
+    scroll: usize,
+    /// Height of the chat pane at the last draw, used for page scrolling.
+    page: Cell<usize>,
+    users: Vec<String>,
+    quit: bool,
+    /// Whether to send delivered/read receipts for incoming chat messages.
+    receipts: bool,
+    /// Incoming chat messages not yet marked as read.
+    unread: Vec<i64>,
+    /// Whether to ring the terminal bell when someone mentions this user.
+    bell: bool,
+    /// History index of each message sent, by client id.
+    sent: HashMap<MessageId, usize>,
+    downloads: Downloads,
+    /// Key directory when end-to-end encryption is on.
+    key_dir: Option<PathBuf>,
+    /// Encryption state, loaded once logged in.
+    e2e: Option<E2e>,
+}
+
+/// Restores the terminal even when the TUI exits with an error.
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(stdout(), LeaveAlternateScreen);
+    }
+}
+
+/// Runs the full-screen client until the user quits or the server goes away.
+pub async fn run(
+    stream: TcpStream,
+    server_addr: SocketAddr,
+    receipts: bool,
+    bell: bool,
+    downloads: Downloads,
+    key_dir: Option<PathBuf>,
+    max_frame_bytes: usize,
+) -> Result<()> {
+    enable_raw_mode().context("Failed to enable raw mode")?;
+    let _guard = TerminalGuard;
+    execute!(stdout(), EnterAlternateScreen).context("Failed to enter alternate screen")?;
+    let mut terminal =
+        Terminal::new(CrosstermBackend::new(stdout())).context("Failed to create terminal")?;
+
+    let (mut stream_r, mut stream_w) = stream.into_split();
+    let (resp_send, mut resp_recv) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(response) =
+            receive_message_within::<ResponseType, _>(&mut stream_r, max_frame_bytes).await
+        {
+            if resp_send.send(response).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut app = App::new(server_addr, receipts, bell, downloads, key_dir);
+    let mut events = EventStream::new();
+
+    while !app.quit {
+        draw_frame(&mut terminal, &app)?;
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    app.handle_key(key, &mut stream_w).await?;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e).context("Failed to read terminal event"),
+                None => break,
+            },
+            response = resp_recv.recv(), if app.connected => match response {
+                Some(response) => app.handle_response(response, &mut stream_w).await?,
+                None => {
+                    app.connected = false;
+                    app.push(LineKind::Error, "Connection to server lost.".to_string());
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn draw_frame(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &App) -> Result<()> {
+    terminal
+        .draw(|frame| draw(frame, app))
+        .context("Failed to draw")?;
+    Ok(())
+}
+
+impl App {
+    fn new(
+        server_addr: SocketAddr,
+        receipts: bool,
+        bell: bool,
+        downloads: Downloads,
+        key_dir: Option<PathBuf>,
+    ) -> Self {
+        let mut app = Self {
+            server_addr,
+            connected: true,
+            auth: AuthState::LoggedOut,
+            history: Vec::new(),
+            input: String::new(),
+            cursor: 0,
+            scroll: 0,
+            page: Cell::new(10),
+            users: Vec::new(),
+            quit: false,
+            receipts,
+            unread: Vec::new(),
+            bell,
+            sent: HashMap::new(),
+            downloads,
+            key_dir,
+            e2e: None,
+        };
+        app.push(
+            LineKind::Info,
+            "Enter REGISTER or AUTH followed by username and password.".to_string(),
+        );
+        app
+    }
+
+    fn push(&mut self, kind: LineKind, text: String) {
+        self.history.push(ChatLine {
+            kind,
+            text,
+            delivery: None,
+        });
+    }
+
+    async fn handle_key(&mut self, key: KeyEvent, stream_w: &mut OwnedWriteHalf) -> Result<()> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') | KeyCode::Char('d') if ctrl => {
+                if let AuthState::LoggedIn(_) = self.auth {
+                    let _ = send_message(stream_w, &ClientMessage::new(MessageType::Quit)).await;
+                }
+                self.quit = true;
+            }
+            KeyCode::Enter => self.submit(stream_w).await?,
+            KeyCode::Char(c) => {
+                let at = self.byte_index();
+                self.input.insert(at, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                let at = self.byte_index();
+                self.input.remove(at);
+            }
+            KeyCode::Delete if self.cursor < self.input.chars().count() => {
+                let at = self.byte_index();
+                self.input.remove(at);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.chars().count()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.chars().count(),
+            KeyCode::Up => self.scroll += 1,
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll += self.page.get(),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page.get()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn byte_index(&self) -> usize {
+        self.input
+            .char_indices()
+            .nth(self.cursor)
+            .map_or(self.input.len(), |(i, _)| i)
+    }
+
+    async fn submit(&mut self, stream_w: &mut OwnedWriteHalf) -> Result<()> {
+        let line = std::mem::take(&mut self.input);
+        self.cursor = 0;
+        self.scroll = 0;
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        if !self.connected {
+            self.push(LineKind::Error, "Not connected.".to_string());
+            return Ok(());
+        }
+
+        if let AuthState::LoggedIn(_) = self.auth {
+            if let Some(res) = self.downloads.local_command(line).await {
+                match res {
+                    Ok(done) => self.push(LineKind::Info, done),
+                    Err(e) => self.push(LineKind::Error, format!("{e:#}")),
+                }
+                return Ok(());
+            }
+            if let Some(res) = self.e2e.as_mut().and_then(|e2e| e2e.local_command(line)) {
+                match res {
+                    Ok((notices, outgoing)) => {
+                        self.notify(notices);
+                        self.send_sealed(outgoing, stream_w).await?;
+                    }
+                    Err(e) => self.push(LineKind::Error, format!("{e:#}")),
+                }
+                return Ok(());
+            }
+            match line.parse::<MessageType>() {
+                Ok(msg) => {
+                    // With end-to-end encryption a direct message waits for the recipient's key.
+                    let msg = match (msg, self.e2e.as_mut()) {
+                        (MessageType::Direct(to, text), Some(e2e)) => e2e.queue_direct(to, text),
+                        (msg, _) => msg,
+                    };
+                    // Sending something means the user has seen the chat so far.
+                    for id in self.unread.drain(..) {
+                        send_receipt(stream_w, id, ReceiptStatus::Read).await?;
+                    }
+                    let msg = ClientMessage::new(msg);
+                    send_message(stream_w, &msg).await?;
+                    match msg.message {
+                        MessageType::Text(text) => {
+                            self.push(LineKind::Own, format!("me: {text}"));
+                            self.track(msg.id);
+                        }
+                        MessageType::Reply(parent, text) => {
+                            self.push(LineKind::Own, format!("me ↪ #{parent}: {text}"));
+                            self.track(msg.id);
+                        }
+                        MessageType::Direct(to, text) => {
+                            self.push(LineKind::Own, format!("me → {to}: {text}"));
+                            self.track(msg.id);
+                        }
+                        MessageType::File(path) | MessageType::Image(path) => {
+                            self.push(LineKind::Own, format!("requested {path}"));
+                            self.track(msg.id);
+                        }
+                        MessageType::Command(name, args) => {
+                            self.push(LineKind::Own, format!("/{name} {args}"));
+                            self.track(msg.id);
+                        }
+                        MessageType::Quit => self.quit = true,
+                        MessageType::FetchImage(id) => {
+                            self.push(LineKind::Own, format!("fetching image #{id}"));
+                            self.track(msg.id);
+                        }
+                        MessageType::Receipt(..)
+                        | MessageType::Upload(..)
+                        | MessageType::PublishKey(_)
+                        | MessageType::FetchKey(_)
+                        | MessageType::SealedDirect(..) => {}
+                    }
+                }
+                Err(e) => self.push(LineKind::Error, e.to_string()),
+            }
+            return Ok(());
+        }
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() == 3 && (parts[0] == "REGISTER" || parts[0] == "AUTH") {
+            send_message(stream_w, &parts.join(" ")).await?;
+            self.push(LineKind::Info, format!("{} {}...", parts[0], parts[1]));
+            self.auth = AuthState::Pending(parts[1].to_string());
+        } else {
+            self.push(
+                LineKind::Error,
+                "Use REGISTER or AUTH followed by username and password.".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Sends direct messages sealed once their recipient's key arrived.
+    async fn send_sealed(
+        &mut self,
+        outgoing: Vec<Outgoing>,
+        stream_w: &mut OwnedWriteHalf,
+    ) -> Result<()> {
+        for Outgoing { to, text, message } in outgoing {
+            let msg = ClientMessage::new(message);
+            send_message(stream_w, &msg).await?;
+            self.push(LineKind::Own, format!("me → {to} 🔒: {text}"));
+            self.track(msg.id);
+        }
+        Ok(())
+    }
+
+    fn notify(&mut self, notices: Vec<Notice>) {
+        for notice in notices {
+            match notice {
+                Notice::Info(text) => self.push(LineKind::Info, text),
+                Notice::Warning(text) => self.push(LineKind::Error, text),
+            }
+        }
+    }
+
+    /// Marks the last history line as a sent message awaiting delivery.
+    fn track(&mut self, id: MessageId) {
+        let index = self.history.len() - 1;
+        self.history[index].delivery = Some(Delivery::default());
+        self.sent.insert(id, index);
+    }
+
+    fn delivery(&mut self, id: MessageId) -> Option<&mut Delivery> {
+        let index = *self.sent.get(&id)?;
+        self.history.get_mut(index)?.delivery.as_mut()
+    }
+
+    async fn handle_response(
+        &mut self,
+        response: ResponseType,
+        stream_w: &mut OwnedWriteHalf,
+    ) -> Result<()> {
+        match response {
+            ResponseType::Text(msg)
+                if matches!(self.auth, AuthState::Pending(_))
+                    && (msg.contains("AUTH OK") || msg.contains("Registration successful")) =>
+            {
+                if let AuthState::Pending(user) =
+                    std::mem::replace(&mut self.auth, AuthState::LoggedOut)
+                {
+                    if let Some(dir) = &self.key_dir {
+                        match E2e::load(dir, &user) {
+                            Ok(e2e) => {
+                                send_message(stream_w, &ClientMessage::new(e2e.publish())).await?;
+                                let key = format!("Your encryption key: {}", e2e.fingerprint());
+                                self.push(LineKind::Info, key);
+                                self.e2e = Some(e2e);
+                            }
+                            Err(e) => self.push(LineKind::Error, format!("{e:#}")),
+                        }
+                    }
+                    self.auth = AuthState::LoggedIn(user);
+                }
+                self.push(LineKind::Info, msg);
+            }
+            ResponseType::Text(msg) => self.push(LineKind::Chat, msg),
+            ResponseType::Error(msg) => {
+                if let AuthState::Pending(_) = self.auth {
+                    self.auth = AuthState::LoggedOut;
+                }
+                self.push(LineKind::Error, format!("Server: {msg}"));
+            }
+            ResponseType::File(name, content) => {
+                let size = content.len();
+                match self.downloads.receive_file(&name, content).await {
+                    Ok(FileOutcome::Saved(path)) => self.push(
+                        LineKind::Info,
+                        format!("Saved file {name} as {}", path.display()),
+                    ),
+                    Ok(FileOutcome::Pending(id)) => self.push(
+                        LineKind::Info,
+                        format!(
+                            "File {name} ({size} bytes) is waiting: .accept {id} or .decline {id}"
+                        ),
+                    ),
+                    Err(e) => self.push(
+                        LineKind::Error,
+                        format!("Failed to save file {name}: {e:#}"),
+                    ),
+                }
+            }
+            ResponseType::Image {
+                from,
+                name,
+                content,
+            } => match self.downloads.save_image(&from, &name, content).await {
+                Ok(path) => self.push(
+                    LineKind::Info,
+                    format!("Saved image {name} from {from} as {}", path.display()),
+                ),
+                Err(e) => self.push(
+                    LineKind::Error,
+                    format!("Failed to save image {name}: {e:#}"),
+                ),
+            },
+            ResponseType::Thumbnail {
+                id,
+                from,
+                name,
+                width,
+                height,
+                ..
+            } => self.push(
+                LineKind::Chat,
+                format!("#{id} {from} shared {name} ({width}x{height}), .fetch {id} to save it"),
+            ),
+            ResponseType::Quit(addr) => {
+                self.push(LineKind::Info, format!("{addr} has disconnected"))
+            }
+            ResponseType::Users(users) => self.users = users,
+            ResponseType::Chat {
+                id,
+                from,
+                text,
+                mentions,
+                reply_to,
+            } => {
+                if let Some(quote) = reply_to {
+                    self.push(
+                        LineKind::Info,
+                        format!("  ↪ #{} {}: {}", quote.id, quote.from, quote.text),
+                    );
+                }
+                let mentioned = matches!(
+                    &self.auth,
+                    AuthState::LoggedIn(me) if mentions.contains(me)
+                );
+                if mentioned {
+                    self.push(LineKind::Mention, format!("#{id} {from} @me: {text}"));
+                    if self.bell {
+                        ring_bell();
+                    }
+                } else {
+                    self.push(LineKind::Chat, format!("#{id} {from}: {text}"));
+                }
+                if self.receipts {
+                    send_receipt(stream_w, id, ReceiptStatus::Delivered).await?;
+                    self.unread.push(id);
+                }
+            }
+            ResponseType::Direct {
+                id,
+                from,
+                text,
+                queued_at,
+            } => {
+                let when = queued_at
+                    .map(|at| format!(" (sent {at})"))
+                    .unwrap_or_default();
+                self.push(LineKind::Direct, format!("#{id} {from} → me{when}: {text}"));
+            }
+            ResponseType::SealedDirect {
+                id,
+                from,
+                sealed,
+                queued_at,
+            } => {
+                let when = queued_at
+                    .map(|at| format!(" (sent {at})"))
+                    .unwrap_or_default();
+                let Some(e2e) = self.e2e.as_mut() else {
+                    self.push(
+                        LineKind::Direct,
+                        format!("#{id} {from} → me{when}: [encrypted, restart with --e2e to read]"),
+                    );
+                    return Ok(());
+                };
+                match e2e.open(&from, &sealed) {
+                    Ok((text, notice)) => {
+                        self.notify(notice.into_iter().collect());
+                        self.push(
+                            LineKind::Direct,
+                            format!("#{id} {from} → me 🔒{when}: {text}"),
+                        );
+                    }
+                    Err(e) => self.push(LineKind::Error, format!("#{id}: {e:#}")),
+                }
+            }
+            ResponseType::PublicKey { user, key } => {
+                if let Some(e2e) = self.e2e.as_mut() {
+                    let (notices, outgoing) = e2e.key_received(&user, key);
+                    self.notify(notices);
+                    self.send_sealed(outgoing, stream_w).await?;
+                }
+            }
+            ResponseType::Ack(id) => {
+                if let Some(delivery) = self.delivery(id) {
+                    delivery.stored = Some(Ok(()));
+                }
+            }
+            ResponseType::Nack(id, reason) => {
+                if let Some(delivery) = self.delivery(id) {
+                    delivery.stored = Some(Err(reason));
+                }
+            }
+            ResponseType::Receipt { id, user, status } => {
+                if let Some(delivery) = self.delivery(id) {
+                    let users = match status {
+                        ReceiptStatus::Delivered => &mut delivery.delivered,
+                        ReceiptStatus::Read => &mut delivery.read,
+                    };
+                    if !users.contains(&user) {
+                        users.push(user);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn draw(frame: &mut Frame, app: &App) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .split(frame.size());
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(20), Constraint::Length(22)])
+        .split(rows[0]);
+
+    // Chat pane: wrap by hand so scrolling counts screen lines.
+    let chat_area = columns[0];
+    let width = chat_area.width.saturating_sub(2).max(1) as usize;
+    let height = chat_area.height.saturating_sub(2) as usize;
+    app.page.set(height.max(1));
+    let wrapped: Vec<Line> = app
+        .history
+        .iter()
+        .flat_map(|line| {
+            let style = line_style(&line.kind);
+            let text = match &line.delivery {
+                Some(delivery) => format!("{}  {}", line.text, delivery.status()),
+                None => line.text.clone(),
+            };
+            let chars: Vec<char> = text.chars().collect();
+            let chunks: Vec<String> = if chars.is_empty() {
+                vec![String::new()]
+            } else {
+                chars.chunks(width).map(|c| c.iter().collect()).collect()
+            };
+            chunks.into_iter().map(move |c| Line::styled(c, style))
+        })
+        .collect();
+    let max_scroll = wrapped.len().saturating_sub(height);
+    let scroll = app.scroll.min(max_scroll);
+    let end = wrapped.len() - scroll;
+    let start = end.saturating_sub(height);
+    let title = if scroll > 0 {
+        format!("Chat (scrolled up {scroll})")
+    } else {
+        "Chat".to_string()
+    };
+    frame.render_widget(
+        Paragraph::new(wrapped[start..end].to_vec())
+            .block(Block::default().borders(Borders::ALL).title(title)),
+        chat_area,
+    );
+
+    let users: Vec<ListItem> = app
+        .users
+        .iter()
+        .map(|u| ListItem::new(u.as_str()))
+        .collect();
+    frame.render_widget(
+        List::new(users).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Online ({})", app.users.len())),
+        ),
+        columns[1],
+    );
+
+    let input_area = rows[1];
+    let shown = if matches!(app.auth, AuthState::LoggedIn(_)) {
+        app.input.clone()
+    } else {
+        mask_password(&app.input)
+    };
+    // Keep the cursor visible by scrolling long input horizontally.
+    let input_width = input_area.width.saturating_sub(2).max(1) as usize;
+    let offset = app.cursor.saturating_sub(input_width - 1);
+    let visible: String = shown.chars().skip(offset).take(input_width).collect();
+    frame.render_widget(
+        Paragraph::new(visible).block(Block::default().borders(Borders::ALL).title("Input")),
+        input_area,
+    );
+    frame.set_cursor(
+        input_area.x + 1 + (app.cursor - offset) as u16,
+        input_area.y + 1,
+    );
+
+    let connection = if app.connected {
+        format!("Connected to {}", app.server_addr)
+    } else {
+        "Disconnected".to_string()
+    };
+    let auth = match &app.auth {
+        AuthState::LoggedOut => "Not authenticated".to_string(),
+        AuthState::Pending(user) => format!("Authenticating as {user}"),
+        AuthState::LoggedIn(user) => format!("Logged in as {user}"),
+    };
+    frame.render_widget(
+        Paragraph::new(format!(
+            " {connection} | {auth} | Up/Down/PgUp/PgDn scroll, Ctrl+C quit"
+        ))
+        .style(Style::default().bg(Color::Blue).fg(Color::White)),
+        rows[2],
+    );
+}
+
+fn line_style(kind: &LineKind) -> Style {
+    match kind {
+        LineKind::Chat => Style::default(),
+        LineKind::Mention => Style::default()
+            .fg(Color::Yellow)
+            .add_modifier(Modifier::BOLD),
+        LineKind::Direct => Style::default().fg(Color::Magenta),
+        LineKind::Own => Style::default().fg(Color::Cyan),
+        LineKind::Info => Style::default()
+            .fg(Color::DarkGray)
+            .add_modifier(Modifier::ITALIC),
+        LineKind::Error => Style::default().fg(Color::Red),
+    }
+}
+
+/// Hides the password while an AUTH/REGISTER line is being typed.
+fn mask_password(input: &str) -> String {
+    let mut words = 0;
+    let mut in_word = false;
+    input
+        .chars()
+        .map(|c| {
+            if c.is_whitespace() {
+                in_word = false;
+                c
+            } else {
+                if !in_word {
+                    in_word = true;
+                    words += 1;
+                }
+                if words >= 3 {
+                    '*'
+                } else {
+                    c
+                }
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,468 @@
+use anyhow::{bail, Context, Result};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{ready, Context as TaskContext, Poll};
+use std::time::Duration;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::{error, info};
+
+use crate::server_utils::{spawn_connection, FrameReader, FrameWriter, ServerState};
+use crate::SharedLibError;
+
+/// Browser client served to plain HTTP requests on the WebSocket listener.
+const INDEX_HTML: &str = include_str!("../static/chat.html");
+
+/// JSON spells each byte of file content as up to four characters (`255,`).
+const JSON_BYTES_PER_BYTE: usize = 4;
+
+/// Longest message a WebSocket client may send next, in bytes. The reader sets
+/// it before each read: small before login, `frame_limit` after.
+type MessageLimit = Arc<AtomicUsize>;
+
+/// A WebSocket client's TCP stream, following the frames it sends so that a
+/// message longer than the [`MessageLimit`] fails as soon as the frame header
+/// announcing it arrives. tungstenite has one limit for the whole connection
+/// and buffers a message whole before the reader sees it.
+pub struct LimitedStream {
+    inner: TcpStream,
+    limit: MessageLimit,
+    /// Bytes of the HTTP request head still to pass before the frames start.
+    skip: usize,
+    /// Header of the frame being received, until it is complete.
+    header: Vec<u8>,
+    payload_left: u64,
+    /// Length so far of the message being received, across its fragments.
+    message_len: u64,
+}
+
+impl LimitedStream {
+    fn new(inner: TcpStream, head_len: usize, limit: MessageLimit) -> Self {
+        Self {
+            inner,
+            limit,
+            skip: head_len,
+            header: Vec::new(),
+            payload_left: 0,
+            message_len: 0,
+        }
+    }
+
+    /// Follows the frames through freshly read `bytes`.
+    fn inspect(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            if self.skip > 0 {
+                let n = self.skip.min(bytes.len());
+                self.skip -= n;
+                bytes = &bytes[n..];
+                continue;
+            }
+            if self.payload_left > 0 {
+                let n = self.payload_left.min(bytes.len() as u64);
+                self.payload_left -= n;
+                bytes = &bytes[n as usize..];
+                continue;
+            }
+            self.header.push(bytes[0]);
+            bytes = &bytes[1..];
+            let Some(frame) = FrameHeader::parse(&self.header) else {
+                continue;
+            };
+            self.header.clear();
+            self.payload_left = frame.len;
+            // Control frames sit between fragments and are never longer than 125 bytes.
+            if frame.control {
+                continue;
+            }
+            self.message_len = self.message_len.saturating_add(frame.len);
+            let limit = self.limit.load(Ordering::Relaxed);
+            if self.message_len > limit as u64 {
+                let too_large = SharedLibError::FrameTooLarge(self.message_len as usize, limit);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, too_large));
+            }
+            if frame.fin {
+                self.message_len = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a WebSocket frame header says about the payload after it.
+struct FrameHeader {
+    len: u64,
+    /// Last fragment of its message.
+    fin: bool,
+    control: bool,
+}
+
+impl FrameHeader {
+    /// `None` until `header` holds a whole frame header.
+    fn parse(header: &[u8]) -> Option<Self> {
+        let (&first, &second) = (header.first()?, header.get(1)?);
+        let extended = match second & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask = if second & 0x80 != 0 { 4 } else { 0 };
+        if header.len() < 2 + extended + mask {
+            return None;
+        }
+        let len = match extended {
+            0 => u64::from(second & 0x7f),
+            2 => u64::from(u16::from_be_bytes([header[2], header[3]])),
+            _ => u64::from_be_bytes(header[2..10].try_into().ok()?),
+        };
+        Some(Self {
+            len,
+            fin: first & 0x80 != 0,
+            control: first & 0x08 != 0,
+        })
+    }
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        Poll::Ready(self.inspect(&buf.filled()[filled..]))
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reading half of a WebSocket client and the limit its stream enforces.
+pub struct WsReader {
+    frames: SplitStream<WebSocketStream<LimitedStream>>,
+    limit: MessageLimit,
+}
+
+/// A read error, as `FrameTooLarge` if [`LimitedStream`] refused the message.
+fn read_error(e: WsError) -> anyhow::Error {
+    if let WsError::Io(io) = &e {
+        let inner = io
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<SharedLibError>());
+        if let Some(&SharedLibError::FrameTooLarge(len, max_len)) = inner {
+            return SharedLibError::FrameTooLarge(len, max_len).into();
+        }
+    }
+    anyhow::Error::new(e).context("Failed to read WebSocket frame")
+}
+
+/// WebSocket clients speak the same messages as JSON text frames.
+impl FrameReader for WsReader {
+    async fn read_frame<T: DeserializeOwned>(&mut self, max_len: usize) -> Result<T> {
+        self.limit.store(
+            max_len.saturating_mul(JSON_BYTES_PER_BYTE),
+            Ordering::Relaxed,
+        );
+        loop {
+            let frame = self
+                .frames
+                .next()
+                .await
+                .context("WebSocket closed")?
+                .map_err(read_error)?;
+            match frame {
+                Message::Text(text) => {
+                    return serde_json::from_str(&text).context("Invalid JSON frame");
+                }
+                Message::Binary(data) => {
+                    return serde_json::from_slice(&data).context("Invalid JSON frame");
+                }
+                Message::Close(_) => bail!("WebSocket closed by peer"),
+                // Ping/pong are answered by tungstenite itself.
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl FrameWriter for SplitSink<WebSocketStream<LimitedStream>, Message> {
+    async fn write_frame<T: Serialize + Sync>(&mut self, message: &T) -> Result<()> {
+        let json = serde_json::to_string(message).context("Failed to encode JSON frame")?;
+        self.send(Message::Text(json))
+            .await
+            .context("Failed to send WebSocket frame")
+    }
+}
+
+/// Accepts WebSocket clients and joins them to the same chat as TCP clients.
+pub async fn run_ws_listener(addr: SocketAddr, state: Arc<ServerState>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .context("Failed to bind WebSocket socket")?;
+
+    info!("WebSocket gateway running on http://{}", addr);
+
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            error!("Failed to accept WebSocket connection");
+            continue;
+        };
+
+        let state = Arc::clone(&state);
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_ws_connection(stream, addr, state).await {
+                error!("Error handling WebSocket client {}: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_ws_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+) -> Result<()> {
+    let Some(head_len) = upgrade_head_len(&stream).await? else {
+        return serve_index(stream).await;
+    };
+
+    // Until login, messages may only be as long as the login frame; the reader
+    // raises the limit afterwards. tungstenite keeps the overall ceiling.
+    let limit = MessageLimit::new(AtomicUsize::new(
+        state.auth_frame_limit().saturating_mul(JSON_BYTES_PER_BYTE),
+    ));
+    let stream = LimitedStream::new(stream, head_len, Arc::clone(&limit));
+    let max_len = state.frame_limit().saturating_mul(JSON_BYTES_PER_BYTE);
+    let config = WebSocketConfig {
+        max_message_size: Some(max_len),
+        max_frame_size: Some(max_len),
+        ..Default::default()
+    };
+    let ws_stream = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+        .await
+        .context("WebSocket handshake failed")?;
+    info!("New WebSocket connection from {}", addr);
+
+    let (ws_writer, frames) = ws_stream.split();
+    spawn_connection(WsReader { frames, limit }, ws_writer, addr, state);
+    Ok(())
+}
+
+/// Bytes of the request head looked at, how long to wait for all of it and
+/// how often to look while it arrives.
+const REQUEST_HEAD_LIMIT: usize = 8192;
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Peeks at the HTTP request head to tell WebSocket upgrades from page loads,
+/// returning the length of the head for an upgrade. The head may arrive in
+/// several segments, so it peeks until the blank line ending it, the size
+/// limit or the timeout. A head longer than the limit is not taken as an upgrade.
+async fn upgrade_head_len(stream: &TcpStream) -> Result<Option<usize>> {
+    let mut buf = vec![0u8; REQUEST_HEAD_LIMIT];
+    let deadline = Instant::now() + REQUEST_HEAD_TIMEOUT;
+    loop {
+        let n = tokio::time::timeout_at(deadline, stream.peek(&mut buf))
+            .await
+            .context("Timed out waiting for the request head")?
+            .context("Failed to peek request")?;
+        let head = &buf[..n];
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&head[..end]).to_ascii_lowercase();
+            return Ok(head.contains("upgrade: websocket").then_some(end + 4));
+        }
+        if n == REQUEST_HEAD_LIMIT {
+            return Ok(None);
+        }
+        // Peeking again straight away would return the same bytes.
+        tokio::time::sleep_until((Instant::now() + PEEK_INTERVAL).min(deadline)).await;
+    }
+}
+
+async fn serve_index(mut stream: TcpStream) -> Result<()> {
+    let mut request = [0u8; 2048];
+    let _ = stream
+        .read(&mut request)
+        .await
+        .context("Failed to read request")?;
+
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        INDEX_HTML.len(),
+        INDEX_HTML
+    );
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .context("Failed to write response")?;
+    stream
+        .shutdown()
+        .await
+        .context("Failed to close connection")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `segments` with a pause in between and checks the server's verdict.
+    async fn upgrade_verdict(segments: &[&str]) -> Result<Option<usize>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+        let segments: Vec<String> = segments.iter().map(|s| s.to_string()).collect();
+        let writer = tokio::spawn(async move {
+            for segment in segments {
+                client.write_all(segment.as_bytes()).await?;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            Ok::<_, std::io::Error>(client)
+        });
+        let verdict = upgrade_head_len(&server).await;
+        writer.await??;
+        verdict
+    }
+
+    #[tokio::test]
+    async fn upgrade_split_across_segments_is_recognised() {
+        let segments = [
+            "GET / HTTP/1.1\r\nHost: x\r\n",
+            "Connection: Upgrade\r\nUpgrade: websocket\r\n",
+            "\r\n",
+        ];
+        let verdict = upgrade_verdict(&segments).await.unwrap();
+        assert_eq!(verdict, Some(segments.concat().len()));
+    }
+
+    #[tokio::test]
+    async fn page_load_is_not_an_upgrade() {
+        let verdict = upgrade_verdict(&["GET / HTTP/1.1\r\n", "Host: x\r\n\r\n"])
+            .await
+            .unwrap();
+        assert_eq!(verdict, None);
+    }
+
+    #[tokio::test]
+    async fn oversized_head_is_not_an_upgrade() {
+        let header = format!("X-Filler: {}\r\n", "a".repeat(REQUEST_HEAD_LIMIT));
+        let verdict = upgrade_verdict(&["GET / HTTP/1.1\r\nUpgrade: websocket\r\n", &header])
+            .await
+            .unwrap();
+        assert_eq!(verdict, None);
+    }
+
+    /// A client frame header: masked, `len` bytes of payload.
+    fn frame_header(opcode: u8, fin: bool, len: usize) -> Vec<u8> {
+        let mut header = vec![opcode | if fin { 0x80 } else { 0 }];
+        match len {
+            0..=125 => header.push(0x80 | len as u8),
+            126..=0xffff => {
+                header.push(0x80 | 126);
+                header.extend((len as u16).to_be_bytes());
+            }
+            _ => {
+                header.push(0x80 | 127);
+                header.extend((len as u64).to_be_bytes());
+            }
+        }
+        header.extend([1, 2, 3, 4]);
+        header
+    }
+
+    fn frame(opcode: u8, fin: bool, len: usize) -> Vec<u8> {
+        let mut frame = frame_header(opcode, fin, len);
+        frame.resize(frame.len() + len, b'x');
+        frame
+    }
+
+    /// A stream with `limit` that skips `skip` bytes, and the client end feeding it.
+    async fn limited(limit: usize, skip: usize) -> (LimitedStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let limit = MessageLimit::new(AtomicUsize::new(limit));
+        (LimitedStream::new(server, skip, limit), client)
+    }
+
+    #[tokio::test]
+    async fn frames_within_the_limit_pass_byte_by_byte() {
+        let (mut stream, _client) = limited(70_000, 3).await;
+        let mut bytes = b"GET".to_vec();
+        bytes.extend(frame(0x1, true, 200));
+        bytes.extend(frame(0x9, true, 100)); // ping
+        bytes.extend(frame(0x2, true, 70_000));
+        for byte in &bytes {
+            stream.inspect(std::slice::from_ref(byte)).unwrap();
+        }
+        assert_eq!(stream.message_len, 0);
+    }
+
+    #[tokio::test]
+    async fn fragments_count_towards_one_message() {
+        let (mut stream, _client) = limited(150, 0).await;
+        stream.inspect(&frame(0x1, false, 100)).unwrap();
+        stream.inspect(&frame(0xA, true, 100)).unwrap(); // pong between fragments
+        let error = stream.inspect(&frame(0x0, true, 51)).unwrap_err();
+        assert!(error.to_string().contains("151 bytes"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn oversized_message_fails_on_its_header() {
+        let (mut stream, mut client) = limited(1024, 0).await;
+        // Only the header is sent: the payload is never waited for.
+        client
+            .write_all(&frame_header(0x1, true, 256 * 1024 * 1024))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let error = loop {
+            match stream.read(&mut buf).await {
+                Ok(_) => continue,
+                Err(e) => break e,
+            }
+        };
+        let inner = error.get_ref().unwrap().downcast_ref::<SharedLibError>();
+        assert!(matches!(
+            inner,
+            Some(SharedLibError::FrameTooLarge(268_435_456, 1024))
+        ));
+    }
+
+    #[tokio::test]
+    async fn limit_changes_apply_to_the_next_message() {
+        let (mut stream, _client) = limited(100, 0).await;
+        assert!(stream.inspect(&frame(0x1, true, 100)).is_ok());
+        stream.limit.store(1000, Ordering::Relaxed);
+        assert!(stream.inspect(&frame(0x1, true, 1000)).is_ok());
+        stream.limit.store(10, Ordering::Relaxed);
+        assert!(stream.inspect(&frame(0x1, true, 11)).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use futures_util::StreamExt;
+use hwork15::config::ServerConfig;
+use hwork15::{ChatClient, ChatEvent, ChatEvents, Server};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+/// A server on a free local port keeping everything in memory, with cheap
+/// password hashing so the tests stay fast.
+async fn start_server() -> Arc<Server> {
+    let mut config = ServerConfig::default();
+    config.auth.argon2_memory_kib = 1024;
+    config.auth.argon2_iterations = 1;
+    config.storage.root = std::env::temp_dir();
+    let server = Server::builder()
+        .config(config)
+        .listener(TcpListener::bind("127.0.0.1:0").await.unwrap())
+        .database_url("memory:")
+        .build()
+        .await
+        .unwrap();
+    let server = Arc::new(server);
+    let running = Arc::clone(&server);
+    tokio::spawn(async move { running.run().await });
+    server
+}
+
+async fn register(server: &Server, username: &str) -> (ChatClient, ChatEvents) {
+    let (client, events) = ChatClient::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    client
+        .register(username, &format!("{username}-secret-pass"))
+        .await
+        .unwrap();
+    (client, events)
+}
+
+/// The first event `pick` accepts, skipping the rest.
+async fn next_matching<T>(
+    events: &mut ChatEvents,
+    mut pick: impl FnMut(ChatEvent) -> Option<T>,
+) -> T {
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let event = events.next().await.expect("connection closed");
+            if let Some(found) = pick(event) {
+                return found;
+            }
+        }
+    })
+    .await
+    .expect("no matching event within 10 seconds")
+}
+
+#[tokio::test]
+async fn chat_message_is_acked_and_broadcast() {
+    let server = start_server().await;
+    let (alice, mut alice_events) = register(&server, "alice").await;
+    let (_bob, mut bob_events) = register(&server, "bob").await;
+
+    let id = alice.send_text("hello @bob").await.unwrap();
+
+    let acked = next_matching(&mut alice_events, |event| match event {
+        ChatEvent::Acked(acked) => Some(acked),
+        ChatEvent::Rejected { reason, .. } => panic!("message rejected: {reason}"),
+        _ => None,
+    })
+    .await;
+    assert_eq!(acked, id);
+
+    let (from, text, mentions) = next_matching(&mut bob_events, |event| match event {
+        ChatEvent::Chat {
+            from,
+            text,
+            mentions,
+            ..
+        } => Some((from, text, mentions)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(from, "alice");
+    assert_eq!(text, "hello @bob");
+    assert_eq!(mentions, ["bob"]);
+
+    server.shutdown();
+    let closed = timeout(Duration::from_secs(10), async {
+        while bob_events.next().await.is_some() {}
+    })
+    .await;
+    assert!(closed.is_ok(), "connections stay open after shutdown");
+}
+
+#[tokio::test]
+async fn client_refuses_frames_over_its_limit() {
+    let server = start_server().await;
+    let (alice, _alice_events) = register(&server, "alice").await;
+    let addr = server.local_addr().unwrap();
+    let (bob, mut bob_events) = ChatClient::connect_within(addr, 1024).await.unwrap();
+    bob.register("bob", "bob-secret-pass").await.unwrap();
+    next_matching(&mut bob_events, |event| match event {
+        ChatEvent::Users(users) if users.len() == 2 => Some(()),
+        _ => None,
+    })
+    .await;
+
+    alice.send_text("short").await.unwrap();
+    alice.send_text("x".repeat(2048)).await.unwrap();
+
+    let text = next_matching(&mut bob_events, |event| match event {
+        ChatEvent::Chat { text, .. } => Some(text),
+        _ => None,
+    })
+    .await;
+    assert_eq!(text, "short");
+    let rest = timeout(Duration::from_secs(10), async {
+        let mut rest = Vec::new();
+        while let Some(event) = bob_events.next().await {
+            rest.push(event);
+        }
+        rest
+    })
+    .await
+    .expect("the connection stayed open");
+    assert!(
+        !rest
+            .iter()
+            .any(|event| matches!(event, ChatEvent::Chat { .. })),
+        "{rest:?}"
+    );
+}
+
+#[tokio::test]
+async fn servers_keep_their_own_metrics() {
+    let first = start_server().await;
+    let second = start_server().await;
+    let (_alice, mut events) = register(&first, "alice").await;
+    // The online list follows the login, once the user is counted.
+    next_matching(&mut events, |event| {
+        matches!(event, ChatEvent::Users(_)).then_some(())
+    })
+    .await;
+
+    let gauge = |text: String| {
+        text.lines()
+            .find_map(|line| line.strip_prefix("chat_authenticated_users "))
+            .map(str::to_string)
+    };
+    assert_eq!(gauge(first.encode_metrics().unwrap()).as_deref(), Some("1"));
+    assert_eq!(
+        gauge(second.encode_metrics().unwrap()).as_deref(),
+        Some("0")
+    );
+
+    first.shutdown();
+    second.shutdown();
+}
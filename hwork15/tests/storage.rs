@@ -0,0 +1,458 @@
+use hwork15::audit::{AuditAction, AuditEvent, AuditFilter, AuditOutcome};
+use hwork15::export::TranscriptFilter;
+use hwork15::storage::{self, MemoryStorage, SqliteStorage, Storage};
+
+/// Postgres server to run the suite against, e.g. `postgres://chat@localhost/chat`.
+/// Each run creates and drops a database of its own there.
+const POSTGRES_URL_VAR: &str = "HWORK_TEST_POSTGRES_URL";
+
+/// Runs every check against `storage`, which must be empty. Every backend
+/// has to give the same answers.
+async fn check_backend(storage: &dyn Storage) {
+    check_users(storage).await;
+    check_messages(storage).await;
+    check_mentions(storage).await;
+    check_direct_messages(storage).await;
+    check_attachments(storage).await;
+    check_audit(storage).await;
+    check_retention(storage).await;
+    check_purge(storage).await;
+    check_threads(storage).await;
+}
+
+async fn check_users(storage: &dyn Storage) {
+    storage.create_user("bob", "hash-b").await.unwrap();
+    storage.create_user("alice", "hash-a").await.unwrap();
+    assert!(storage.create_user("alice", "other").await.is_err());
+
+    assert!(storage.user_exists("alice").await.unwrap());
+    assert!(!storage.user_exists("carol").await.unwrap());
+    let credentials = storage.credentials("alice").await.unwrap().unwrap();
+    assert_eq!(credentials.password_hash, "hash-a");
+    assert!(!credentials.locked);
+    assert!(storage.credentials("carol").await.unwrap().is_none());
+
+    assert!(storage.set_password_hash("alice", "hash-a2").await.unwrap());
+    assert!(!storage.set_password_hash("carol", "x").await.unwrap());
+    let credentials = storage.credentials("alice").await.unwrap().unwrap();
+    assert_eq!(credentials.password_hash, "hash-a2");
+
+    assert!(storage.set_locked("bob", true).await.unwrap());
+    assert!(!storage.set_locked("carol", true).await.unwrap());
+    assert!(storage.credentials("bob").await.unwrap().unwrap().locked);
+    assert!(storage.set_locked("bob", false).await.unwrap());
+
+    storage.mark_seen("alice").await.unwrap();
+    let users = storage.users().await.unwrap();
+    let names: Vec<_> = users.iter().map(|u| u.username.as_str()).collect();
+    assert_eq!(names, ["alice", "bob"]);
+    assert!(users[0].last_seen.is_some());
+    assert!(users[1].last_seen.is_none());
+
+    assert_eq!(storage.public_key("alice").await.unwrap(), None);
+    storage.set_public_key("alice", &[1; 32]).await.unwrap();
+    storage.set_public_key("alice", &[2; 32]).await.unwrap();
+    assert_eq!(
+        storage.public_key("alice").await.unwrap(),
+        Some(vec![2; 32])
+    );
+}
+
+async fn check_messages(storage: &dyn Storage) {
+    let root = storage.save_message("alice", "root", None).await.unwrap();
+    let reply = storage
+        .save_message("bob", "reply", Some(root))
+        .await
+        .unwrap();
+    let other = storage.save_message("alice", "other", None).await.unwrap();
+    let nested = storage
+        .save_message("alice", "nested", Some(reply))
+        .await
+        .unwrap();
+
+    let stored = storage.message(reply).await.unwrap().unwrap();
+    assert_eq!(
+        (
+            stored.username.as_str(),
+            stored.content.as_str(),
+            stored.reply_to
+        ),
+        ("bob", "reply", Some(root))
+    );
+    assert_eq!(stored.timestamp.len(), "YYYY-MM-DD HH:MM:SS".len());
+    assert!(storage.message(nested + 100).await.unwrap().is_none());
+
+    let ids = |messages: Vec<hwork15::db::StoredMessage>| -> Vec<i64> {
+        messages.iter().map(|m| m.id).collect()
+    };
+    assert_eq!(
+        ids(storage.thread(nested).await.unwrap()),
+        [root, reply, nested]
+    );
+    assert_eq!(
+        ids(storage.thread(root).await.unwrap()),
+        [root, reply, nested]
+    );
+    assert_eq!(ids(storage.thread(other).await.unwrap()), [other]);
+    assert!(storage.thread(nested + 100).await.unwrap().is_empty());
+
+    assert_eq!(
+        ids(storage.recent_messages(2).await.unwrap()),
+        [other, nested]
+    );
+    let filter = TranscriptFilter {
+        user: Some("alice".into()),
+        ..Default::default()
+    };
+    assert_eq!(
+        ids(storage.messages(&filter).await.unwrap()),
+        [root, other, nested]
+    );
+    let future = TranscriptFilter::default()
+        .between(Some("2999-01-01"), None)
+        .unwrap();
+    assert!(storage.messages(&future).await.unwrap().is_empty());
+
+    let counted = storage.count_user_messages("alice").await.unwrap();
+    assert_eq!((counted.chat, counted.direct), (3, 0));
+    let users = storage.users().await.unwrap();
+    assert_eq!(users[0].messages, 3);
+    assert_eq!(users[1].messages, 1);
+}
+
+async fn check_mentions(storage: &dyn Storage) {
+    let first = storage
+        .save_message("alice", "@bob hi", None)
+        .await
+        .unwrap();
+    let second = storage
+        .save_message("alice", "@bob again", None)
+        .await
+        .unwrap();
+    let bob = vec!["bob".to_string()];
+    storage.record_mentions(first, &bob).await.unwrap();
+    storage.record_mentions(first, &bob).await.unwrap();
+    storage.record_mentions(second, &bob).await.unwrap();
+
+    let unread = storage.unread_mentions("bob").await.unwrap();
+    assert_eq!(
+        unread.iter().map(|m| m.id).collect::<Vec<_>>(),
+        [first, second]
+    );
+    assert!(storage.unread_mentions("alice").await.unwrap().is_empty());
+
+    assert_eq!(storage.mark_mentions_read("bob", first).await.unwrap(), 1);
+    assert_eq!(storage.mark_mentions_read("bob", first).await.unwrap(), 0);
+    let unread = storage.unread_mentions("bob").await.unwrap();
+    assert_eq!(unread.iter().map(|m| m.id).collect::<Vec<_>>(), [second]);
+    assert_eq!(storage.mark_mentions_read("bob", second).await.unwrap(), 1);
+}
+
+async fn check_direct_messages(storage: &dyn Storage) {
+    let first = storage
+        .queue_direct_message("alice", "bob", "first", None)
+        .await
+        .unwrap();
+    let sealed = storage
+        .queue_direct_message("alice", "bob", "", Some(&[7; 40]))
+        .await
+        .unwrap();
+    storage
+        .queue_direct_message("bob", "alice", "to alice", None)
+        .await
+        .unwrap();
+    assert_eq!(
+        storage.count_queued_direct_messages("bob").await.unwrap(),
+        2
+    );
+
+    let queued = storage.queued_direct_messages("bob").await.unwrap();
+    assert_eq!(
+        queued.iter().map(|m| m.id).collect::<Vec<_>>(),
+        [first, sealed]
+    );
+    assert_eq!(queued[0].content, "first");
+    assert_eq!(queued[1].sealed, Some(vec![7; 40]));
+
+    storage.mark_direct_message_delivered(first).await.unwrap();
+    // Nothing has waited an hour yet.
+    storage.expire_direct_messages("bob", 3600).await.unwrap();
+    let queued = storage.queued_direct_messages("bob").await.unwrap();
+    assert_eq!(queued.iter().map(|m| m.id).collect::<Vec<_>>(), [sealed]);
+
+    let counted = storage.count_user_messages("alice").await.unwrap();
+    assert_eq!(counted.direct, 2);
+}
+
+async fn check_attachments(storage: &dyn Storage) {
+    storage
+        .record_attachment("alice", "a.txt", "uploads/a.txt")
+        .await
+        .unwrap();
+    storage
+        .record_attachment("bob", "b.png", "images/b.png")
+        .await
+        .unwrap();
+    let all = storage
+        .attachments(&TranscriptFilter::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        all.iter().map(|a| a.path.as_str()).collect::<Vec<_>>(),
+        ["uploads/a.txt", "images/b.png"]
+    );
+    assert!(storage
+        .attachment_referenced("uploads/a.txt")
+        .await
+        .unwrap());
+    assert!(!storage
+        .attachment_referenced("uploads/c.txt")
+        .await
+        .unwrap());
+
+    // Neither an age nor a cutoff: nothing goes.
+    assert!(storage
+        .delete_expired_attachments(0, None, 10)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(storage
+        .delete_expired_attachments(30, Some("2000-01-01 00:00:00"), 10)
+        .await
+        .unwrap()
+        .is_empty());
+    let deleted = storage
+        .delete_expired_attachments(0, Some("2999-01-01 00:00:00"), 1)
+        .await
+        .unwrap();
+    assert_eq!(deleted, ["uploads/a.txt"]);
+    assert!(!storage
+        .attachment_referenced("uploads/a.txt")
+        .await
+        .unwrap());
+    assert!(storage.attachment_referenced("images/b.png").await.unwrap());
+}
+
+async fn check_audit(storage: &dyn Storage) {
+    let peer = "127.0.0.1:4000".parse().unwrap();
+    storage
+        .record_audit(&AuditEvent::new(
+            "alice",
+            peer,
+            AuditAction::Login,
+            AuditOutcome::Success,
+        ))
+        .await
+        .unwrap();
+    storage
+        .record_audit(&AuditEvent::new(
+            "bob",
+            peer,
+            AuditAction::Login,
+            AuditOutcome::Failure,
+        ))
+        .await
+        .unwrap();
+
+    let all = storage.audit_events(&AuditFilter::default()).await.unwrap();
+    assert_eq!(
+        all.iter().map(|e| e.actor.as_str()).collect::<Vec<_>>(),
+        ["alice", "bob"]
+    );
+    assert_eq!(all[0].peer, "127.0.0.1:4000");
+    let bob = AuditFilter::parse("user=bob").unwrap();
+    let events = storage.audit_events(&bob).await.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].actor, "bob");
+    let latest = AuditFilter::parse("1").unwrap();
+    let events = storage.audit_events(&latest).await.unwrap();
+    assert_eq!(events[0].actor, "bob");
+
+    assert_eq!(storage.prune_audit_events(1).await.unwrap(), 0);
+}
+
+async fn check_retention(storage: &dyn Storage) {
+    let before = storage.stats().await.unwrap().messages;
+    assert_eq!(before, 6);
+    assert_eq!(storage.delete_messages_older_than(1, 100).await.unwrap(), 0);
+
+    let deleted = storage.delete_messages_beyond(3, 2).await.unwrap();
+    assert_eq!(deleted.len(), 2);
+    assert!(deleted
+        .iter()
+        .all(|t| t.len() == "YYYY-MM-DD HH:MM:SS".len()));
+    let deleted = storage.delete_messages_beyond(3, 100).await.unwrap();
+    assert_eq!(deleted.len(), 1);
+    assert!(storage
+        .delete_messages_beyond(3, 100)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // The oldest went first.
+    let remaining = storage.recent_messages(100).await.unwrap();
+    assert_eq!(
+        remaining
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>(),
+        ["nested", "@bob hi", "@bob again"]
+    );
+    assert!(storage.unread_mentions("bob").await.unwrap().is_empty());
+
+    let stats = storage.stats().await.unwrap();
+    assert_eq!((stats.users, stats.locked_users), (2, 0));
+    assert_eq!(stats.messages, 3);
+    assert!(stats.oldest_message.is_some() && stats.newest_message.is_some());
+    assert_eq!(stats.attachments, 1);
+    assert_eq!(stats.audit_events, 2);
+    let mut direct = stats.direct_messages.clone();
+    direct.sort();
+    assert_eq!(
+        direct,
+        [("delivered".to_string(), 1), ("queued".to_string(), 2)]
+    );
+    storage.vacuum().await.unwrap();
+}
+
+async fn check_purge(storage: &dyn Storage) {
+    let purged = storage.purge_user_messages("alice").await.unwrap();
+    assert_eq!((purged.chat, purged.direct), (3, 2));
+    let counted = storage.count_user_messages("alice").await.unwrap();
+    assert_eq!((counted.chat, counted.direct), (0, 0));
+    assert_eq!(
+        storage.count_queued_direct_messages("alice").await.unwrap(),
+        1
+    );
+}
+
+async fn check_threads(storage: &dyn Storage) {
+    let ids = |messages: Vec<hwork15::db::StoredMessage>| -> Vec<i64> {
+        messages.iter().map(|m| m.id).collect()
+    };
+
+    // A long chain: every message answers the one before.
+    let mut chain = vec![storage.save_message("bob", "0", None).await.unwrap()];
+    for depth in 1..60 {
+        let parent = *chain.last().unwrap();
+        let id = storage
+            .save_message("bob", &depth.to_string(), Some(parent))
+            .await
+            .unwrap();
+        chain.push(id);
+    }
+    assert_eq!(ids(storage.thread(chain[59]).await.unwrap()), chain);
+    assert_eq!(ids(storage.thread(chain[30]).await.unwrap()), chain);
+
+    // Two threads written at the same time, branching, stay apart and in order.
+    let a1 = storage.save_message("bob", "a1", None).await.unwrap();
+    let b1 = storage.save_message("carol", "b1", None).await.unwrap();
+    let a2 = storage.save_message("carol", "a2", Some(a1)).await.unwrap();
+    let b2 = storage.save_message("bob", "b2", Some(b1)).await.unwrap();
+    let a3 = storage.save_message("bob", "a3", Some(a1)).await.unwrap();
+    let a4 = storage.save_message("carol", "a4", Some(a2)).await.unwrap();
+    let thread_a = [a1, a2, a3, a4];
+    for id in thread_a {
+        assert_eq!(ids(storage.thread(id).await.unwrap()), thread_a);
+    }
+    assert_eq!(ids(storage.thread(b2).await.unwrap()), [b1, b2]);
+
+    // Once retention removes the root, the oldest remaining ancestor leads,
+    // and branches hanging off the removed root are cut off.
+    // The chain and a1 are the oldest 61.
+    let keep = storage.recent_messages(1000).await.unwrap().len() as u64;
+    let deleted = storage
+        .delete_messages_beyond(keep - 61, 1000)
+        .await
+        .unwrap();
+    assert_eq!(deleted.len(), 61);
+    assert_eq!(ids(storage.thread(a4).await.unwrap()), [a2, a4]);
+    assert_eq!(ids(storage.thread(a3).await.unwrap()), [a3]);
+    assert_eq!(ids(storage.thread(b2).await.unwrap()), [b1, b2]);
+}
+
+fn sqlite_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("hwork15-{name}-{}.sqlite", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[tokio::test]
+async fn memory_backend() {
+    check_backend(&MemoryStorage::default()).await;
+}
+
+#[tokio::test]
+async fn sqlite_backend() {
+    let path = sqlite_path("parity");
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    check_backend(&SqliteStorage::connect(&url).await.unwrap()).await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn postgres_backend() {
+    let Ok(url) = std::env::var(POSTGRES_URL_VAR) else {
+        eprintln!("{POSTGRES_URL_VAR} is not set, skipping the Postgres backend");
+        return;
+    };
+    let admin = sqlx::PgPool::connect(&url).await.unwrap();
+    let name = format!("hwork15_parity_{}", std::process::id());
+    sqlx::query(&format!("DROP DATABASE IF EXISTS {name}"))
+        .execute(&admin)
+        .await
+        .unwrap();
+    sqlx::query(&format!("CREATE DATABASE {name}"))
+        .execute(&admin)
+        .await
+        .unwrap();
+
+    let (server, _) = url.split_once('?').unwrap_or((&url, ""));
+    let (server, _) = server.rsplit_once('/').unwrap();
+    let storage = storage::PostgresStorage::connect(&format!("{server}/{name}"))
+        .await
+        .unwrap();
+    check_backend(&storage).await;
+
+    drop(storage);
+    sqlx::query(&format!("DROP DATABASE {name} WITH (FORCE)"))
+        .execute(&admin)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn open_picks_the_backend_by_scheme() {
+    let memory = storage::open("memory:").await.unwrap();
+    memory.create_user("alice", "hash").await.unwrap();
+    assert!(memory.user_exists("alice").await.unwrap());
+
+    // Each memory: URL is a database of its own.
+    let other = storage::open("memory:").await.unwrap();
+    assert!(!other.user_exists("alice").await.unwrap());
+
+    let path = sqlite_path("open");
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    storage::open(&url)
+        .await
+        .unwrap()
+        .create_user("alice", "hash")
+        .await
+        .unwrap();
+    // The file keeps the user for the next open.
+    let reopened = storage::open(&url).await.unwrap();
+    assert!(reopened.user_exists("alice").await.unwrap());
+    drop(reopened);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn open_refuses_unknown_schemes() {
+    for url in ["mysql://localhost/chat", "./chat.db", "", "postgres:chat"] {
+        let error = storage::open(url).await.err().unwrap();
+        assert!(
+            error.to_string().starts_with("Unsupported database URL"),
+            "{url}: {error}"
+        );
+    }
+}